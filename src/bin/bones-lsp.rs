@@ -0,0 +1,9 @@
+// The wmach language server. Point an editor at this for .wm files; it talks LSP over stdio.
+fn main() -> anyhow::Result<()> {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+
+    bones::lsp::serve(stdin.lock(), stdout.lock())?;
+
+    Ok(())
+}
@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::diagnostic::Snippet;
+use crate::diagnostic::SourceMap;
+use crate::diagnostic::Span;
+use crate::lower;
+use crate::wmach::{Ast, Data, Insn, IoOp, Program, SeekOp, Stmt, WmachErr, WriteOp};
+
+// A compiled wmach program, so that big programs don't have to be parsed again every time they're
+// loaded. Everything is little endian and every number not given a size is an unsigned LEB128
+// varint:
+//
+//   magic         "WMCH"
+//   version       u16
+//   flags         u8, bit 0 set if there is a source map at the end
+//   instructions  count, then an opcode byte each followed by its operands:
+//                   0 -   1 +   2 < count   3 > count   4 ,   5 .   6 jmp t f   7 !   8 halt
+//   labels        count, then name and offset each, sorted by name
+//   data          head, number of bits, then the bits packed 8 to a byte, first bit lowest
+//   source map    number of files, then each file as an origin flag byte (plus the origin if it
+//                 is set) and its text, then a file, start and length for every instruction
+//
+// Strings are a length in bytes followed by that much UTF-8.
+
+pub const MAGIC: &[u8; 4] = b"WMCH";
+pub const VERSION: u16 = 1;
+
+const HAS_SOURCES: u8 = 1;
+
+// The tiles take a cell of the row for every cell a seek crosses or the data starts along, just as
+// if it had been written out that many times, so nothing past what we'll unroll can be run.
+const MAX_DISTANCE: usize = lower::MAX_UNROLLED;
+
+const OP_UNSET: u8 = 0;
+const OP_SET: u8 = 1;
+const OP_LEFT: u8 = 2;
+const OP_RIGHT: u8 = 3;
+const OP_IN: u8 = 4;
+const OP_OUT: u8 = 5;
+const OP_JMP: u8 = 6;
+const OP_DEBUG: u8 = 7;
+const OP_HALT: u8 = 8;
+
+#[derive(Debug, Error)]
+pub enum BinaryErr {
+    #[error("Not a compiled wmach program")]
+    BadMagic,
+
+    #[error("Compiled with format version {version}, but only version {} is supported", VERSION)]
+    UnsupportedVersion { version: u16 },
+
+    #[error("The program stops short at byte {offset}")]
+    Truncated { offset: usize },
+
+    #[error("Corrupt program at byte {offset}: {reason}")]
+    Corrupt { offset: usize, reason: String },
+
+    #[error("There are {spans} source spans for {instructions} instructions")]
+    SourceMismatch { spans: usize, instructions: usize },
+
+    #[error("The source span for instruction {instruction} isn't in any of the source files")]
+    StraySpan { instruction: usize },
+
+    #[error("Instruction {instruction} seeks {count} cells, which is too far to compile")]
+    TooFar { instruction: usize, count: usize },
+
+    #[error("The data starts {head} cells along, which is too far to compile")]
+    HeadTooFar { head: usize },
+
+    #[error("Invalid base64 character ``{found}''")]
+    BadBase64 { found: char },
+}
+
+// Where every instruction came from, one span per instruction.
+#[derive(Debug, Clone)]
+pub struct SourceInfo {
+    pub sources: SourceMap,
+    pub spans: Vec<Span>,
+}
+
+impl SourceInfo {
+    // Spans for the program as resolve_labels hands it out, run-length seeks and all.
+    pub fn new(ast: &Ast) -> Result<Self, WmachErr> {
+        let spans = lower::expand_repeats(&ast.sources, &ast.statements)?
+            .into_iter()
+            .filter(|stmt| !matches!(stmt.node, Stmt::Label(_) | Stmt::Data(_)))
+            .map(|stmt| stmt.span)
+            .collect();
+
+        Ok(SourceInfo {
+            sources: ast.sources.clone(),
+            spans,
+        })
+    }
+
+    pub fn snippet(&self, offset: usize) -> Option<Snippet> {
+        self.spans.get(offset).and_then(|span| self.sources.get_snippet(*span))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub program: Program,
+    pub sources: Option<SourceInfo>,
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, text: &str) {
+    put_varint(out, text.len());
+    out.extend_from_slice(text.as_bytes());
+}
+
+impl Image {
+    pub fn encode(&self) -> Result<Vec<u8>, BinaryErr> {
+        let code = &self.program.instructions;
+
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.push(if self.sources.is_some() { HAS_SOURCES } else { 0 });
+
+        put_varint(&mut out, code.len());
+        for (instruction, insn) in code.iter().enumerate() {
+            match insn {
+                Insn::Write(WriteOp::Unset) => out.push(OP_UNSET),
+                Insn::Write(WriteOp::Set) => out.push(OP_SET),
+                Insn::Seek(direction, count) => {
+                    if *count > MAX_DISTANCE {
+                        Err(BinaryErr::TooFar { instruction, count: *count })?;
+                    }
+                    out.push(match direction {
+                        SeekOp::Left => OP_LEFT,
+                        SeekOp::Right => OP_RIGHT,
+                    });
+                    put_varint(&mut out, *count);
+                }
+                Insn::Io(IoOp::In) => out.push(OP_IN),
+                Insn::Io(IoOp::Out) => out.push(OP_OUT),
+                Insn::Jmp(branch_t, branch_f) => {
+                    out.push(OP_JMP);
+                    put_varint(&mut out, *branch_t);
+                    put_varint(&mut out, *branch_f);
+                }
+                Insn::Debug => out.push(OP_DEBUG),
+                Insn::Halt => out.push(OP_HALT),
+            }
+        }
+
+        let mut labels: Vec<_> = self.program.labels.iter().collect();
+        labels.sort();
+        put_varint(&mut out, labels.len());
+        for (label, offset) in labels {
+            put_str(&mut out, label);
+            put_varint(&mut out, *offset);
+        }
+
+        let data = &self.program.data;
+        if data.head > MAX_DISTANCE {
+            Err(BinaryErr::HeadTooFar { head: data.head })?;
+        }
+        put_varint(&mut out, data.head);
+        put_varint(&mut out, data.bits.len());
+        for byte in data.bits.chunks(8) {
+            out.push(byte.iter().enumerate().map(|(i, bit)| (*bit as u8) << i).sum());
+        }
+
+        if let Some(info) = &self.sources {
+            if info.spans.len() != code.len() {
+                Err(BinaryErr::SourceMismatch {
+                    spans: info.spans.len(),
+                    instructions: code.len(),
+                })?;
+            }
+
+            let files = info.sources.files();
+            put_varint(&mut out, files.len());
+            for file in files.iter() {
+                match &file.origin {
+                    Some(origin) => {
+                        out.push(1);
+                        put_str(&mut out, origin);
+                    }
+                    None => out.push(0),
+                }
+                put_str(&mut out, &file.text);
+            }
+
+            for (instruction, span) in info.spans.iter().enumerate() {
+                let text = &files.get(span.file()).ok_or(BinaryErr::StraySpan { instruction })?.text;
+                let start = span.start(text).ok_or(BinaryErr::StraySpan { instruction })?;
+                let end = span.end(text).ok_or(BinaryErr::StraySpan { instruction })?;
+                put_varint(&mut out, span.file());
+                put_varint(&mut out, start);
+                put_varint(&mut out, end - start);
+            }
+        }
+
+        Ok(out)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Image, BinaryErr> {
+        let mut reader = Reader { bytes, offset: 0 };
+
+        if !is_binary(bytes) {
+            Err(BinaryErr::BadMagic)?;
+        }
+        reader.take(MAGIC.len())?;
+
+        let version = u16::from_le_bytes([reader.byte()?, reader.byte()?]);
+        if version == 0 || version > VERSION {
+            Err(BinaryErr::UnsupportedVersion { version })?;
+        }
+
+        let flags = reader.byte()?;
+        if flags & !HAS_SOURCES != 0 {
+            Err(reader.corrupt(format!("unknown flags {:#04x}", flags)))?;
+        }
+
+        let count = reader.varint()?;
+        let mut instructions = Vec::new();
+        for _ in 0..count {
+            let insn = match reader.byte()? {
+                OP_UNSET => Insn::Write(WriteOp::Unset),
+                OP_SET => Insn::Write(WriteOp::Set),
+                OP_LEFT => Insn::Seek(SeekOp::Left, reader.seek()?),
+                OP_RIGHT => Insn::Seek(SeekOp::Right, reader.seek()?),
+                OP_IN => Insn::Io(IoOp::In),
+                OP_OUT => Insn::Io(IoOp::Out),
+                OP_JMP => Insn::Jmp(reader.offset_into(count)?, reader.offset_into(count)?),
+                OP_DEBUG => Insn::Debug,
+                OP_HALT => Insn::Halt,
+                op => Err(reader.corrupt(format!("unknown opcode {}", op)))?,
+            };
+            instructions.push(insn);
+        }
+
+        let mut labels = HashMap::new();
+        for _ in 0..reader.varint()? {
+            let label = reader.string()?;
+            let offset = reader.offset_into(count)?;
+            if labels.insert(label, offset).is_some() {
+                Err(reader.corrupt("duplicate label".to_string()))?;
+            }
+        }
+
+        let head = reader.varint()?;
+        if head > MAX_DISTANCE {
+            Err(reader.corrupt(format!("data head {} is too far along the tape", head)))?;
+        }
+        let length = reader.varint()?;
+        let packed = reader.take(length.div_ceil(8))?;
+        let bits = (0..length).map(|i| packed[i / 8] & (1 << (i % 8)) != 0).collect();
+        let data = Data { bits, head };
+
+        let sources = if flags & HAS_SOURCES != 0 {
+            let mut sources = SourceMap::new();
+            let files = reader.varint()?;
+            for _ in 0..files {
+                let origin = match reader.byte()? {
+                    0 => None,
+                    1 => Some(reader.string()?),
+                    flag => Err(reader.corrupt(format!("unknown origin flag {}", flag)))?,
+                };
+                sources.add(origin, reader.string()?);
+            }
+
+            let mut spans = Vec::new();
+            for _ in 0..count {
+                let file = reader.varint()?;
+                let (start, length) = (reader.varint()?, reader.varint()?);
+
+                let text = match sources.files().get(file) {
+                    Some(file) => &file.text,
+                    None => Err(reader.corrupt(format!("no file {}", file)))?,
+                };
+                let end = start.checked_add(length).filter(|end| text.get(start..*end).is_some());
+                let end = end.ok_or_else(|| reader.corrupt("span outside of its file".to_string()))?;
+
+                spans.push(Span::between(&text[start..], &text[end..]).in_file(file));
+            }
+
+            Some(SourceInfo { sources, spans })
+        } else {
+            None
+        };
+
+        if reader.offset != bytes.len() {
+            Err(reader.corrupt("trailing bytes".to_string()))?;
+        }
+
+        Ok(Image {
+            program: Program {
+                instructions,
+                labels,
+                data,
+            },
+            sources,
+        })
+    }
+}
+
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn corrupt(&self, reason: String) -> BinaryErr {
+        BinaryErr::Corrupt {
+            offset: self.offset,
+            reason,
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], BinaryErr> {
+        let end = self.offset.checked_add(length).filter(|end| *end <= self.bytes.len());
+        let end = end.ok_or(BinaryErr::Truncated {
+            offset: self.bytes.len(),
+        })?;
+
+        let taken = &self.bytes[self.offset..end];
+        self.offset = end;
+
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, BinaryErr> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<usize, BinaryErr> {
+        let mut value: usize = 0;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.byte()?;
+            let bits = ((byte & 0x7f) as usize).checked_shl(shift).filter(|bits| bits >> shift == (byte & 0x7f) as usize);
+            value |= bits.ok_or_else(|| self.corrupt("number too big".to_string()))?;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(self.corrupt("number too big".to_string()))
+    }
+
+    // How far a seek goes. The parser won't take >0 so neither do we.
+    fn seek(&mut self) -> Result<usize, BinaryErr> {
+        let count = self.varint()?;
+        if count == 0 || count > MAX_DISTANCE {
+            Err(self.corrupt(format!("can't seek {} cells", count)))?;
+        }
+
+        Ok(count)
+    }
+
+    // An instruction offset, where one past the last instruction is fine since that halts.
+    fn offset_into(&mut self, count: usize) -> Result<usize, BinaryErr> {
+        let offset = self.varint()?;
+        if offset > count {
+            Err(self.corrupt(format!("offset {} is past the end of the program", offset)))?;
+        }
+
+        Ok(offset)
+    }
+
+    fn string(&mut self) -> Result<String, BinaryErr> {
+        let length = self.varint()?;
+        let bytes = self.take(length)?;
+
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Ok(text.to_string()),
+            Err(_) => Err(self.corrupt("invalid UTF-8".to_string())),
+        }
+    }
+}
+
+// URL-safe base64 without padding, which is how the web page takes a compiled program in ?bin=.
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+pub fn to_base64(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.chunks(3) {
+        let word = chunk.iter().enumerate().fold(0u32, |word, (i, byte)| word | (*byte as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            text.push(BASE64[(word >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+
+    text
+}
+
+pub fn from_base64(text: &str) -> Result<Vec<u8>, BinaryErr> {
+    let mut bytes = Vec::new();
+    let mut word: u32 = 0;
+    let mut bits = 0;
+
+    // Padding is pointless but harmless.
+    for c in text.trim_end_matches('=').chars() {
+        let value = match BASE64.iter().position(|b| *b as char == c) {
+            Some(value) => value as u32,
+            None => Err(BinaryErr::BadBase64 { found: c })?,
+        };
+
+        word = word << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((word >> bits) as u8);
+            word &= (1 << bits) - 1;
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn image(src: &str) -> Image {
+        let ast = Ast::from_str(src).expect("should parse fine");
+        let program = ast.resolve_labels().expect("should resolve fine");
+
+        Image {
+            program,
+            sources: Some(SourceInfo::new(&ast).expect("should unroll fine")),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let src = "
+            data \"hi\" at 3
+            top: + > , jmp top, end
+            repeat 2 { <3 ! . }
+            end: - halt
+        ";
+        let original = image(src);
+        let bytes = original.encode().expect("should encode fine");
+        assert!(bytes.starts_with(b"WMCH\x01\x00\x01"));
+
+        let decoded = Image::decode(&bytes).expect("should decode fine");
+        assert_eq!(decoded.program, original.program);
+
+        let sources = decoded.sources.expect("the source map should come back");
+        let spans: Vec<&str> = sources
+            .spans
+            .iter()
+            .filter_map(|span| span.text(&sources.sources.file(0).text))
+            .collect();
+        assert_eq!(spans[..5], ["+", ">", ",", "jmp top, end", "<3"]);
+        assert_eq!(sources.snippet(3).expect("jmp has a span").location().line, 3);
+
+        // Without the source map it is just the program.
+        let stripped = Image {
+            sources: None,
+            ..original
+        };
+        let bytes = stripped.encode().expect("should encode fine");
+        assert_eq!(bytes[6], 0);
+        let decoded = Image::decode(&bytes).expect("should decode fine");
+        assert_eq!(decoded.program, stripped.program);
+        assert!(decoded.sources.is_none());
+    }
+
+    #[test]
+    fn run_lengths_survive() {
+        let program = Ast::from_str(">>> top: <20 jmp top")
+            .and_then(|ast| ast.resolve_labels())
+            .expect("should parse fine");
+        let bytes = Image { program: program.clone(), sources: None }.encode().expect("should encode fine");
+
+        assert_eq!(Image::decode(&bytes).expect("should decode fine").program, program);
+    }
+
+    #[test]
+    fn rejects_junk() {
+        let bytes = image("top: + jmp top").encode().expect("should encode fine");
+
+        assert!(matches!(Image::decode(b"wmch"), Err(BinaryErr::BadMagic)));
+        assert!(matches!(Image::decode(b"WMCH\x02\x00\x00"), Err(BinaryErr::UnsupportedVersion { version: 2 })));
+
+        for length in 4..bytes.len() {
+            match Image::decode(&bytes[..length]) {
+                Err(BinaryErr::Truncated { .. }) => (),
+                x => panic!("Failed to notice the program stops after {} bytes: {:?}", length, x),
+            };
+        }
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(matches!(Image::decode(&longer), Err(BinaryErr::Corrupt { .. })));
+
+        // + jmp 0, 0 with the jump sent off into the distance.
+        let far = b"WMCH\x01\x00\x00\x02\x01\x06\x09\x00\x00\x00\x00";
+        match Image::decode(far) {
+            Err(BinaryErr::Corrupt { offset: 11, reason }) => assert!(reason.contains("offset 9")),
+            x => panic!("Failed to notice the bad jump: {:?}", x),
+        };
+
+        // > 0 and > 2^20 + 1, one past what the tiles can run, then a data head of 2^20 + 1.
+        for (bytes, reason) in [
+            (&b"WMCH\x01\x00\x00\x01\x03\x00\x00\x00\x00"[..], "seek 0 cells"),
+            (b"WMCH\x01\x00\x00\x01\x03\x81\x80\x40\x00\x00\x00", "seek 1048577 cells"),
+            (b"WMCH\x01\x00\x00\x00\x00\x81\x80\x40\x00", "too far along"),
+        ] {
+            match Image::decode(bytes) {
+                Err(BinaryErr::Corrupt { reason: found, .. }) => assert!(found.contains(reason), "{}", found),
+                x => panic!("Failed to notice the {}: {:?}", reason, x),
+            };
+        }
+
+        assert!(matches!(image("+ >1048577").encode(), Err(BinaryErr::TooFar { instruction: 1, count: 1048577 })));
+        let furthest = image("+ >1048576").encode().expect("should encode fine");
+        assert!(Image::decode(&furthest).is_ok());
+
+        let mismatched = Image {
+            sources: image("+ +").sources,
+            ..image("+")
+        };
+        assert!(matches!(mismatched.encode(), Err(BinaryErr::SourceMismatch { spans: 2, instructions: 1 })));
+    }
+
+    #[test]
+    fn base64() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"foob", b"\xff\x00\xfe\x01"] {
+            let text = to_base64(bytes);
+            assert_eq!(from_base64(&text).expect("should decode fine"), bytes);
+        }
+        assert_eq!(to_base64(b"foob"), "Zm9vYg");
+        assert_eq!(from_base64("Zm9vYg==").expect("padding is fine"), b"foob");
+        assert!(matches!(from_base64("Zm9v+g"), Err(BinaryErr::BadBase64 { found: '+' })));
+    }
+}
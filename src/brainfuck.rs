@@ -0,0 +1,265 @@
+use thiserror::Error;
+
+use crate::diagnostic::Snippet;
+use crate::diagnostic::SourceMap;
+use crate::diagnostic::Span;
+use crate::diagnostic::Spanned;
+use crate::wmach::{Ast, IoOp, LabelId, Program, SeekOp, Stmt, Target, WmachErr, WriteOp};
+
+// Brainfuck cells are bytes, stored least significant bit first (the same order IoBuffer uses)
+// in BITS consecutive tape cells. Between commands the head sits on bit 0 of the current cell.
+const BITS: usize = 8;
+
+#[derive(Debug, Error)]
+pub enum BrainfuckErr {
+    #[error("This [ is never closed\n{snippet}")]
+    UnclosedLoop { snippet: Snippet },
+
+    #[error("This ] doesn't close anything\n{snippet}")]
+    UnopenedLoop { snippet: Snippet },
+
+    #[error("{source}")]
+    Wmach {
+        #[from]
+        source: WmachErr,
+    },
+}
+
+// Builds up the wmach statements for one command at a time. Everything it emits points back at
+// the command it came from.
+struct Emitter {
+    statements: Vec<Spanned<Stmt>>,
+    span: Span,
+    fresh: usize,
+}
+
+impl Emitter {
+    fn emit(&mut self, stmt: Stmt) {
+        self.statements.push(Spanned::new(stmt, self.span));
+    }
+
+    // A prefix for labels nobody else has used.
+    fn fresh(&mut self, what: &str) -> LabelId {
+        self.fresh += 1;
+        format!("{}{}", what, self.fresh)
+    }
+
+    fn label(&mut self, label: &str) {
+        self.emit(Stmt::Label(label.to_string()));
+    }
+
+    fn target(&self, label: &str) -> Spanned<Target> {
+        Spanned::new(Target::Name(label.to_string()), self.span)
+    }
+
+    // jmp label, which goes on to the next statement when the bit is 0.
+    fn jmp_if_set(&mut self, label: &str) {
+        let fallthrough = Spanned::new(Target::NextAddress, self.span);
+        self.emit(Stmt::Jmp(self.target(label), fallthrough));
+    }
+
+    fn goto(&mut self, label: &str) {
+        self.emit(Stmt::Jmp(self.target(label), self.target(label)));
+    }
+
+    fn seek(&mut self, direction: SeekOp, count: usize) {
+        if count > 0 {
+            self.emit(Stmt::Seek(direction, count));
+        }
+    }
+
+    // Add one (or take one away) with a ripple carry, then come back to bit 0. Overflow wraps.
+    fn add(&mut self, carry: bool) {
+        let prefix = self.fresh(if carry { "inc" } else { "dec" });
+        let done = format!("{}'done", prefix);
+
+        // Incrementing stops at the first 0, decrementing at the first 1. Everything before it
+        // flips.
+        let (stop_at, flip_to) = if carry {
+            (WriteOp::Set, WriteOp::Unset)
+        } else {
+            (WriteOp::Unset, WriteOp::Set)
+        };
+
+        for bit in 0..BITS {
+            let flip = format!("{}'flip{}", prefix, bit);
+            let stop = format!("{}'stop{}", prefix, bit);
+            if carry {
+                self.emit(Stmt::Jmp(self.target(&flip), self.target(&stop)));
+            } else {
+                self.emit(Stmt::Jmp(self.target(&stop), self.target(&flip)));
+            }
+
+            self.label(&stop);
+            self.emit(Stmt::Write(stop_at));
+            self.seek(SeekOp::Left, bit);
+            self.goto(&done);
+
+            self.label(&flip);
+            self.emit(Stmt::Write(flip_to));
+            if bit + 1 < BITS {
+                self.seek(SeekOp::Right, 1);
+            } else {
+                self.seek(SeekOp::Left, BITS - 1);
+            }
+        }
+
+        self.label(&done);
+    }
+
+    fn io(&mut self, op: IoOp) {
+        for bit in 0..BITS {
+            if bit > 0 {
+                self.seek(SeekOp::Right, 1);
+            }
+            self.emit(Stmt::Io(op));
+        }
+        self.seek(SeekOp::Left, BITS - 1);
+    }
+
+    // Check every bit of the cell, leaving the head back on bit 0 either way.
+    fn open(&mut self, prefix: &str) {
+        let body = format!("{}'body", prefix);
+
+        self.label(prefix);
+        for bit in 0..BITS {
+            if bit > 0 {
+                self.seek(SeekOp::Right, 1);
+            }
+            self.jmp_if_set(&format!("{}'set{}", prefix, bit));
+        }
+        self.seek(SeekOp::Left, BITS - 1);
+        self.goto(&format!("{}'end", prefix));
+
+        for bit in 0..BITS {
+            self.label(&format!("{}'set{}", prefix, bit));
+            self.seek(SeekOp::Left, bit);
+            self.goto(&body);
+        }
+
+        self.label(&body);
+    }
+
+    fn close(&mut self, prefix: &str) {
+        self.goto(prefix);
+        self.label(&format!("{}'end", prefix));
+    }
+}
+
+// Translate Brainfuck into wmach. Anything that isn't one of the eight commands is a comment.
+// Reading past the end of the input is an error, the same as it is in wmach.
+pub fn translate(source: &str) -> Result<Ast, BrainfuckErr> {
+    let mut sources = SourceMap::new();
+    sources.add(None, source.to_string());
+
+    let mut emitter = Emitter {
+        statements: Vec::new(),
+        span: Span::between(source, source),
+        fresh: 0,
+    };
+    let mut loops: Vec<(LabelId, Span)> = Vec::new();
+
+    for (i, command) in source.char_indices() {
+        emitter.span = Span::between(&source[i..], &source[i + command.len_utf8()..]);
+
+        match command {
+            '+' => emitter.add(true),
+            '-' => emitter.add(false),
+            '>' => emitter.seek(SeekOp::Right, BITS),
+            '<' => emitter.seek(SeekOp::Left, BITS),
+            '.' => emitter.io(IoOp::Out),
+            ',' => emitter.io(IoOp::In),
+            '[' => {
+                let prefix = emitter.fresh("loop");
+                emitter.open(&prefix);
+                loops.push((prefix, emitter.span));
+            }
+            ']' => match loops.pop() {
+                Some((prefix, _)) => emitter.close(&prefix),
+                None => Err(BrainfuckErr::UnopenedLoop {
+                    snippet: sources.snippet(emitter.span),
+                })?,
+            },
+            _ => (),
+        }
+    }
+
+    if let Some((_, span)) = loops.pop() {
+        Err(BrainfuckErr::UnclosedLoop {
+            snippet: sources.snippet(span),
+        })?;
+    }
+
+    Ok(Ast {
+        sources,
+        statements: emitter.statements,
+    })
+}
+
+// Straight to the single-step form that the interpreter and the tile compiler both take.
+pub fn program(source: &str) -> Result<Program, BrainfuckErr> {
+    Ok(translate(source)?.resolve_labels()?.expand_seeks())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lockstep::Lockstep;
+    use crate::machine::{Machine, Status};
+
+    fn run(source: &str, input: &[u8]) -> Vec<u8> {
+        let program = program(source).expect("should translate fine");
+        let mut output = Vec::new();
+        {
+            let mut machine = Machine::with_io(&program, input, &mut output);
+            let status = machine.run(1_000_000).expect("should run fine");
+            assert_eq!(status, Status::Halted);
+        }
+
+        output
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(run("++++++++[>++++++++<-]>+.", &[]), b"A");
+        assert_eq!(run("+++--.", &[]), &[1]);
+    }
+
+    #[test]
+    fn cells_wrap() {
+        assert_eq!(run("-.+.", &[]), &[255, 0]);
+        assert_eq!(run("-[>+<-]>+.", &[]), &[0]);
+    }
+
+    #[test]
+    fn io_and_loops() {
+        // Echo until a zero byte, skipping a loop that never runs on the way.
+        assert_eq!(run("[.],[.,]", b"hi!\0"), b"hi!");
+        assert_eq!(run(">, then back to the first cell < and over again > .", b"\x7f"), &[0x7f]);
+    }
+
+    #[test]
+    fn tiles_agree() {
+        let program = program("+[>+.<-]").expect("should translate fine");
+        let mut lockstep = Lockstep::new(&program, &[]).expect("should compile fine");
+
+        let status = lockstep.run(10_000).expect("tiles should agree with the machine");
+        assert_eq!(status, Status::Halted);
+    }
+
+    #[test]
+    fn unbalanced_loops() {
+        match translate("+[[-]") {
+            Err(BrainfuckErr::UnclosedLoop { snippet }) => assert_eq!(snippet.location().column, 2),
+            x => panic!("Failed to notice the unclosed loop: {:?}", x),
+        };
+
+        match translate("+\n-]") {
+            Err(BrainfuckErr::UnopenedLoop { snippet }) => {
+                assert_eq!(snippet.to_string(), " --> 2:2\n  |\n2 | -]\n  |  ^");
+            }
+            x => panic!("Failed to notice the extra ]: {:?}", x),
+        };
+    }
+}
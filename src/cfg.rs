@@ -0,0 +1,325 @@
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+use crate::wmach::{Insn, InsnOffset, IoOp, LabelId, Program, SeekOp};
+
+pub type BlockId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    // The two ways out of a jmp.
+    True,
+    False,
+    // jmp x, x goes to x whatever the bit, and halt always stops.
+    Always,
+    // Running into the next block without jumping.
+    Fallthrough,
+}
+
+// Where an edge goes. A halt, or running off the end of the program, stops it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Node {
+    Block(BlockId),
+    Exit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub to: Node,
+}
+
+// A run of instructions that can only be entered at the top and only left at the bottom.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub start: InsnOffset,
+    // One past the last instruction.
+    pub end: InsnOffset,
+    pub labels: Vec<LabelId>,
+    pub edges: Vec<Edge>,
+
+    // What the block does on the way through.
+    pub writes: usize,
+    pub inputs: usize,
+    pub outputs: usize,
+    pub cells_moved: usize,
+    // Where the head ends up relative to where it came in, east is positive.
+    pub displacement: isize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    code: Vec<Insn>,
+}
+
+impl Cfg {
+    pub fn new(program: &Program) -> Self {
+        let code = &program.instructions;
+
+        // A block starts at the entry, at anything a jump goes to and right after every jump or halt.
+        let mut leaders = BTreeSet::new();
+        leaders.insert(0);
+        for (offset, insn) in code.iter().enumerate() {
+            if let Insn::Jmp(branch_t, branch_f) = insn {
+                leaders.insert(*branch_t);
+                leaders.insert(*branch_f);
+                leaders.insert(offset + 1);
+            }
+            if let Insn::Halt = insn {
+                leaders.insert(offset + 1);
+            }
+        }
+        let leaders: Vec<InsnOffset> = leaders.into_iter().filter(|leader| *leader < code.len()).collect();
+
+        let node = |offset: InsnOffset| match leaders.binary_search(&offset) {
+            Ok(block) => Node::Block(block),
+            Err(_) => Node::Exit,
+        };
+
+        let mut blocks = Vec::with_capacity(leaders.len());
+        for (i, start) in leaders.iter().enumerate() {
+            let end = leaders.get(i + 1).cloned().unwrap_or(code.len());
+
+            let mut labels: Vec<LabelId> = program
+                .labels
+                .iter()
+                .filter(|(_, offset)| *offset == start)
+                .map(|(label, _)| label.clone())
+                .collect();
+            labels.sort();
+
+            let mut block = Block {
+                start: *start,
+                end,
+                labels,
+                edges: Vec::new(),
+
+                writes: 0,
+                inputs: 0,
+                outputs: 0,
+                cells_moved: 0,
+                displacement: 0,
+            };
+
+            for insn in code[*start..end].iter() {
+                match insn {
+                    Insn::Write(_) => block.writes += 1,
+                    Insn::Io(IoOp::In) => block.inputs += 1,
+                    Insn::Io(IoOp::Out) => block.outputs += 1,
+                    Insn::Seek(direction, count) => {
+                        block.cells_moved += count;
+                        block.displacement += match direction {
+                            SeekOp::Left => -(*count as isize),
+                            SeekOp::Right => *count as isize,
+                        };
+                    }
+                    Insn::Jmp(_, _) | Insn::Debug | Insn::Halt => (),
+                }
+            }
+
+            block.edges = match code[end - 1] {
+                Insn::Jmp(branch_t, branch_f) if branch_t == branch_f => vec![Edge {
+                    kind: EdgeKind::Always,
+                    to: node(branch_t),
+                }],
+                Insn::Halt => vec![Edge {
+                    kind: EdgeKind::Always,
+                    to: Node::Exit,
+                }],
+                Insn::Jmp(branch_t, branch_f) => vec![
+                    Edge {
+                        kind: EdgeKind::True,
+                        to: node(branch_t),
+                    },
+                    Edge {
+                        kind: EdgeKind::False,
+                        to: node(branch_f),
+                    },
+                ],
+                _ => vec![Edge {
+                    kind: EdgeKind::Fallthrough,
+                    to: node(end),
+                }],
+            };
+
+            blocks.push(block);
+        }
+
+        Cfg {
+            blocks,
+            code: code.clone(),
+        }
+    }
+
+    // The block an instruction is in.
+    pub fn block_of(&self, offset: InsnOffset) -> Option<BlockId> {
+        self.blocks
+            .iter()
+            .position(|block| block.start <= offset && offset < block.end)
+    }
+
+    pub fn instructions(&self, block: BlockId) -> &[Insn] {
+        let block = &self.blocks[block];
+        &self.code[block.start..block.end]
+    }
+
+    // Graphviz source for the graph, e.g. for `dot -Tsvg`.
+    pub fn dot(&self) -> String {
+        let mut dot = String::new();
+
+        // Writing to a String can't fail.
+        writeln!(dot, "digraph wmach {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=monospace];").unwrap();
+        writeln!(dot, "    exit [shape=doublecircle, label=\"halt\"];").unwrap();
+
+        for (id, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for name in block.labels.iter() {
+                label.push_str(&format!("{}:\\l", escape(name)));
+            }
+            label.push_str(&format!("[{}..{})\\l", block.start, block.end));
+
+            let insns: Vec<String> = self.instructions(id).iter().map(|insn| insn.to_string()).collect();
+            label.push_str(&format!("{}\\l", insns.join(" ")));
+            label.push_str(&annotations(block));
+
+            writeln!(dot, "    b{} [label=\"{}\"];", id, label).unwrap();
+        }
+
+        for (id, block) in self.blocks.iter().enumerate() {
+            for edge in block.edges.iter() {
+                let to = match edge.to {
+                    Node::Block(to) => format!("b{}", to),
+                    Node::Exit => "exit".to_string(),
+                };
+                let attributes = match edge.kind {
+                    EdgeKind::True => " [label=\"1\", color=darkgreen]",
+                    EdgeKind::False => " [label=\"0\", color=red]",
+                    EdgeKind::Always => "",
+                    EdgeKind::Fallthrough => " [style=dashed]",
+                };
+
+                writeln!(dot, "    b{} -> {}{};", id, to, attributes).unwrap();
+            }
+        }
+
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+}
+
+// Only mention what a block actually does.
+fn annotations(block: &Block) -> String {
+    let mut notes = Vec::new();
+    if block.inputs > 0 {
+        notes.push(format!("in {}", block.inputs));
+    }
+    if block.outputs > 0 {
+        notes.push(format!("out {}", block.outputs));
+    }
+    if block.cells_moved > 0 {
+        notes.push(format!("head {:+} ({} moved)", block.displacement, block.cells_moved));
+    }
+
+    if notes.is_empty() {
+        String::new()
+    } else {
+        format!("{}\\l", notes.join(", "))
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn cfg(src: &str) -> Cfg {
+        Program::from_str(src).expect("should parse fine").cfg()
+    }
+
+    #[test]
+    fn splits_blocks() {
+        let graph = cfg("
+            + > > , .
+            scan: jmp right, done
+            right: > jmp scan, scan
+            done: <
+        ");
+
+        let ranges: Vec<(InsnOffset, InsnOffset)> =
+            graph.blocks.iter().map(|block| (block.start, block.end)).collect();
+        assert_eq!(ranges, vec![(0, 5), (5, 6), (6, 8), (8, 9)]);
+        assert_eq!(graph.blocks[1].labels, vec!["scan".to_string()]);
+        assert_eq!(graph.block_of(7), Some(2));
+        assert_eq!(graph.block_of(9), None);
+    }
+
+    #[test]
+    fn edges() {
+        let graph = cfg("
+            + > > , .
+            scan: jmp right, done
+            right: > jmp scan, scan
+            done: <
+        ");
+
+        let edge = |kind, to| Edge { kind, to };
+        assert_eq!(graph.blocks[0].edges, vec![edge(EdgeKind::Fallthrough, Node::Block(1))]);
+        assert_eq!(
+            graph.blocks[1].edges,
+            vec![edge(EdgeKind::True, Node::Block(2)), edge(EdgeKind::False, Node::Block(3))]
+        );
+        assert_eq!(graph.blocks[2].edges, vec![edge(EdgeKind::Always, Node::Block(1))]);
+        assert_eq!(graph.blocks[3].edges, vec![edge(EdgeKind::Fallthrough, Node::Exit)]);
+    }
+
+    #[test]
+    fn annotations() {
+        let graph = cfg("+ > > , . < , jmp end end:");
+        let block = &graph.blocks[0];
+
+        assert_eq!(block.writes, 1);
+        assert_eq!(block.inputs, 2);
+        assert_eq!(block.outputs, 1);
+        assert_eq!(block.cells_moved, 3);
+        assert_eq!(block.displacement, 1);
+    }
+
+    #[test]
+    fn empty_program() {
+        let graph = cfg("");
+
+        assert!(graph.blocks.is_empty());
+        assert!(graph.dot().contains("exit [shape=doublecircle"));
+    }
+
+    #[test]
+    fn dot_export() {
+        let dot = cfg("top: , > jmp top, end end: .").dot();
+
+        assert!(dot.starts_with("digraph wmach {\n"));
+        assert!(dot.contains("    b0 [label=\"top:\\l[0..3)\\l, > jmp 0, 3\\lin 1, head +1 (1 moved)\\l\"];\n"));
+        assert!(dot.contains("    b0 -> b0 [label=\"1\", color=darkgreen];\n"));
+        assert!(dot.contains("    b0 -> b1 [label=\"0\", color=red];\n"));
+        assert!(dot.contains("    b1 -> exit [style=dashed];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn halt_ends_blocks() {
+        let graph = cfg("+ halt > halt");
+
+        let ranges: Vec<(InsnOffset, InsnOffset)> =
+            graph.blocks.iter().map(|block| (block.start, block.end)).collect();
+        assert_eq!(ranges, vec![(0, 2), (2, 4)]);
+        for block in graph.blocks.iter() {
+            assert_eq!(block.edges, vec![Edge { kind: EdgeKind::Always, to: Node::Exit }]);
+        }
+    }
+}
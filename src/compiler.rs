@@ -1,6 +1,268 @@
-pub trait Backend<Target> {
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::binary::{self, BinaryErr, Image};
+use crate::crt;
+use crate::diagnostic::Snippet;
+use crate::tessera;
+use crate::tiling::SideEffects;
+use crate::wasm;
+use crate::wmach;
+
+// Turns a w-machine program into something else. Each backend is its own (usually empty) type so
+// a program can go to as many places as we like, e.g. tessera::Tiles.compile(&program).
+pub trait Backend {
     type Target;
     type Error;
 
-    fn compile(&self) -> Result<Self::Target, Self::Error>;
+    fn compile(&self, program: &wmach::Program) -> Result<Self::Target, Self::Error>;
+}
+
+// Backend errors that know which instruction they were on, so whoever has the source map can
+// point at the line that did it.
+pub trait Located {
+    fn position(&self) -> Option<wmach::InsnOffset> {
+        None
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum EmitError {
+    #[error("Unknown kind of output ``{kind}'', expected one of: {known}")]
+    UnknownKind { kind: String, known: String },
+
+    #[error("{message}{}", located(.snippet))]
+    Backend { message: String, snippet: Option<Snippet> },
+
+    #[error("Binary: {source}")]
+    Binary {
+        #[from]
+        source: BinaryErr,
+    },
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("JSON: {source}")]
+    Json {
+        #[from]
+        source: serde_json::Error,
+    },
+}
+
+fn located(snippet: &Option<Snippet>) -> String {
+    snippet.as_ref().map_or(String::new(), |snippet| format!("\n{}", snippet))
+}
+
+// Every backend error goes out through here so they all get pointed at the source the same way.
+fn failed<E: fmt::Display + Located>(image: &Image, error: E) -> EmitError {
+    let snippet = error
+        .position()
+        .and_then(|position| image.sources.as_ref().and_then(|sources| sources.snippet(position)));
+
+    EmitError::Backend {
+        message: error.to_string(),
+        snippet,
+    }
+}
+
+// One of the things --emit can make, by name. Everything comes out as bytes, ready to be written
+// wherever it's going.
+pub struct Emitter {
+    pub name: &'static str,
+    pub about: &'static str,
+    emit: fn(&Image) -> Result<Vec<u8>, EmitError>,
+}
+
+impl Emitter {
+    pub fn emit(&self, image: &Image) -> Result<Vec<u8>, EmitError> {
+        (self.emit)(image)
+    }
+}
+
+// Anything that runs the program rather than writing it out (threaded::Threaded, say) isn't here.
+pub const EMITTERS: &[Emitter] = &[
+    Emitter { name: "wmach", about: "the program as canonical source", emit: emit_wmach },
+    Emitter { name: "binary", about: "the compiled program, which -f loads as is", emit: emit_binary },
+    Emitter { name: "base64", about: "binary as base64, for the web page's ?bin=", emit: emit_base64 },
+    Emitter { name: "tiles", about: "the wang tiles and the row they start from", emit: emit_tiles },
+    Emitter { name: "c", about: "Program.h for the C runtime in src/crt", emit: emit_c },
+    Emitter { name: "wat", about: "a WebAssembly module as text", emit: emit_wat },
+    Emitter { name: "wasm", about: "a WebAssembly module", emit: emit_wasm },
+    Emitter { name: "dot", about: "the control-flow graph for Graphviz", emit: emit_dot },
+    #[cfg(not(target_arch = "wasm32"))]
+    Emitter { name: "json", about: "instructions, labels, data and where each came from", emit: emit_json },
+];
+
+pub fn emitter(kind: &str) -> Result<&'static Emitter, EmitError> {
+    EMITTERS.iter().find(|emitter| emitter.name == kind).ok_or_else(|| EmitError::UnknownKind {
+        kind: kind.to_string(),
+        known: EMITTERS.iter().map(|emitter| emitter.name).collect::<Vec<_>>().join(", "),
+    })
+}
+
+fn emit_wmach(image: &Image) -> Result<Vec<u8>, EmitError> {
+    Ok(image.program.to_string().into_bytes())
+}
+
+fn emit_binary(image: &Image) -> Result<Vec<u8>, EmitError> {
+    Ok(image.encode()?)
+}
+
+fn emit_base64(image: &Image) -> Result<Vec<u8>, EmitError> {
+    Ok(binary::to_base64(&image.encode()?).into_bytes())
+}
+
+// One tile per line with whatever it does on the side, then the border and the first row.
+fn emit_tiles(image: &Image) -> Result<Vec<u8>, EmitError> {
+    let tiles = tessera::Tiles.compile(&image.program).map_err(|e| failed(image, e))?;
+
+    let mut text = String::new();
+    for (tile_ref, tile) in tiles.pile().tiles() {
+        let effect = match tiles.pile().get_side_effects(&tile_ref) {
+            SideEffects::Pure(_) => String::new(),
+            SideEffects::In(_) => " in".to_string(),
+            SideEffects::Out(bit) => format!(" out {}", bit as u8),
+            SideEffects::Break => " break".to_string(),
+        };
+        text.push_str(&format!("{}{}\n", tile, effect));
+    }
+    text.push_str(&format!("border {}\n", tiles.border()));
+    let initial: Vec<String> = tiles.state().iter().map(|tile| tile.to_string()).collect();
+    text.push_str(&format!("initial {}\n", initial.join(" ")));
+
+    Ok(text.into_bytes())
+}
+
+fn emit_c(image: &Image) -> Result<Vec<u8>, EmitError> {
+    let program = crt::Crt.compile(&image.program).map_err(|e| failed(image, e))?;
+
+    Ok(program.source.into_bytes())
+}
+
+fn emit_wat(image: &Image) -> Result<Vec<u8>, EmitError> {
+    let module = wasm::Wasm.compile(&image.program).map_err(|e| failed(image, e))?;
+
+    Ok(module.to_string().into_bytes())
+}
+
+fn emit_wasm(image: &Image) -> Result<Vec<u8>, EmitError> {
+    let module = wasm::Wasm.compile(&image.program).map_err(|e| failed(image, e))?;
+
+    Ok(module.encode())
+}
+
+fn emit_dot(image: &Image) -> Result<Vec<u8>, EmitError> {
+    Ok(image.program.cfg().dot().into_bytes())
+}
+
+// Spans are only there when we still know where the program came from, i.e. not for programs
+// loaded from binary without sources or ones that went through -O.
+#[cfg(not(target_arch = "wasm32"))]
+fn emit_json(image: &Image) -> Result<Vec<u8>, EmitError> {
+    let program = &image.program;
+
+    let mut labels: Vec<_> = program.labels.iter().collect();
+    labels.sort();
+    let labels: serde_json::Map<String, serde_json::Value> =
+        labels.into_iter().map(|(name, offset)| (name.clone(), (*offset).into())).collect();
+
+    let instructions: Vec<serde_json::Value> = program
+        .instructions
+        .iter()
+        .enumerate()
+        .map(|(position, insn)| {
+            let snippet = image.sources.as_ref().and_then(|sources| sources.snippet(position));
+            serde_json::json!({
+                "insn": insn.to_string(),
+                "source": snippet.map(|snippet| serde_json::json!({
+                    "origin": snippet.origin(),
+                    "line": snippet.location().line,
+                    "column": snippet.location().column,
+                })),
+            })
+        })
+        .collect();
+
+    let bits: String = program.data.bits.iter().map(|bit| if *bit { '1' } else { '0' }).collect();
+    let json = serde_json::json!({
+        "instructions": instructions,
+        "labels": labels,
+        "data": { "bits": bits, "head": program.data.head },
+    });
+
+    let mut bytes = serde_json::to_vec_pretty(&json)?;
+    bytes.push(b'\n');
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn image(src: &str) -> Image {
+        let ast = wmach::Ast::from_str(src).expect("should parse fine");
+
+        Image {
+            program: ast.resolve_labels().expect("labels should resolve"),
+            sources: Some(binary::SourceInfo::new(&ast).expect("should unroll fine")),
+        }
+    }
+
+    #[test]
+    fn every_emitter_emits() {
+        let image = image("data bits 1 loop: . >3 jmp loop, done\ndone: , ! halt");
+
+        for emitter in EMITTERS.iter() {
+            let bytes = emitter.emit(&image).unwrap_or_else(|e| panic!("{} should emit: {}", emitter.name, e));
+            assert!(!bytes.is_empty(), "{} came out empty", emitter.name);
+        }
+
+        let binary = emitter("binary").expect("known").emit(&image).expect("emit");
+        assert_eq!(Image::decode(&binary).expect("should decode").program, image.program);
+    }
+
+    #[test]
+    fn unknown_kind() {
+        match emitter("cobol") {
+            Err(EmitError::UnknownKind { kind, known }) => {
+                assert_eq!(kind, "cobol");
+                assert!(known.contains("wat"));
+            }
+            _ => panic!("cobol isn't a thing"),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn json_knows_where_things_came_from() {
+        let image = image("+\n  loop: > jmp loop, loop");
+        let json: serde_json::Value =
+            serde_json::from_slice(&emitter("json").expect("known").emit(&image).expect("emit")).expect("should be json");
+
+        assert_eq!(json["labels"]["loop"], 1);
+        assert_eq!(json["instructions"][1]["insn"], ">");
+        assert_eq!(json["instructions"][1]["source"]["line"], 2);
+        assert_eq!(json["instructions"][1]["source"]["column"], 9);
+
+        let bare = Image { program: image.program, sources: None };
+        let json: serde_json::Value =
+            serde_json::from_slice(&emitter("json").expect("known").emit(&bare).expect("emit")).expect("should be json");
+        assert!(json["instructions"][1]["source"].is_null());
+    }
+
+    #[test]
+    fn errors_point_at_the_source() {
+        let image = image("+\n>3000000000");
+
+        match emitter("wasm").expect("known").emit(&image) {
+            Err(e @ EmitError::Backend { snippet: Some(_), .. }) => {
+                let message = e.to_string();
+                assert!(message.contains("doesn't fit in an i32"), "{}", message);
+                assert!(message.contains("2 | >3000000000"), "{}", message);
+            }
+            result => panic!("expected the seek to be too far: {:?}", result.map(|_| ())),
+        }
+    }
 }
@@ -1,5 +1,6 @@
 use thiserror::Error;
 
+use std::collections::HashMap;
 use std::collections::HashSet;
 
 use std::fmt;
@@ -21,7 +22,7 @@ pub enum TileCloudError {
     NoTilesLeft,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[cfg_attr(not(test), allow(dead_code))]
 pub enum TileCloudConf {
     Prefer(TileRef),
@@ -29,7 +30,7 @@ pub enum TileCloudConf {
     Whatever,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TileCloud<'process> {
     tiles: &'process DominoPile,
     cloud: HashSet<TileRef>,
@@ -117,6 +118,18 @@ impl<'process> TileCloud<'process> {
         }
     }
 
+    // Keep only the tiles with pip on the given side.
+    pub fn pin(&mut self, direction: &Direction, pip: Pip) -> Result<(), TileCloudError> {
+        let tiles = self.tiles;
+        self.cloud.retain(|r| tiles[*r].cardinal(direction) == pip);
+
+        if self.cloud.is_empty() {
+            Err(TileCloudError::NoTilesLeft)
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn select(&self) -> Result<TileRef, TileCloudError> {
         // The thinking behind these preferences is that we can use the border tile as a
         // tie-breaker. If the cloud is along the border then we prefer to keep a border as we
@@ -150,17 +163,31 @@ impl<'process> TileCloud<'process> {
     }
 }
 
+// The next row of tiles, worked out from the one above it. Each cell of the old row gets a cloud of
+// whatever can go underneath it, and then neighbours whittle each other's clouds down until the
+// row fits together.
+//
+// A cell that's just holding a bit could be passing on any stage of any seek's countdown (see
+// tessera's mk_seek), so its cloud starts out as big as all the seeks put together. Clouds are
+// built outwards from whichever cell has the least that can go under it (the head, usually) and
+// inwards from the border, each one with only what fits against the one before it, and only what
+// both ways agree on is kept. Neither way on its own knows where a countdown could have started,
+// so together they rule out nearly everything.
+//
+// A countdown can cross any number of tiles in a single row, so the row may have to grow by more
+// than a tile at either end. The border is taken to be just past the ends of the row at first.
+// When it isn't, something from the anchor runs off the end, and the void is added a cloud at a
+// time until one of them can meet the border.
 #[derive(Debug)]
-// XXX This only handles the very narrow case where a SINGLE tile changes between rows. To clarify,
-// if we have a single head that moves one square left or right, this case is covered. An
-// optimization where we can move the head n-squares is NOT. I still need to think more about how
-// to achieve this but for now it will not work.
-// Maybe use a list instead of a vector while we're handling TileClouds? Gotta think about how to
-// represent that "infinite" stuff though.
 pub struct Row<'process> {
     pile: &'process DominoPile,
-    row: Vec<TileCloud<'process>>,
+    board: Vec<TileRef>,
     border: TileRef,
+
+    // Every tile by its northern pip, and then by that along with the pip on its west or east.
+    by_north: HashMap<Pip, Vec<TileRef>>,
+    by_west: HashMap<(Pip, Pip), Vec<TileRef>>,
+    by_east: HashMap<(Pip, Pip), Vec<TileRef>>,
 }
 
 #[derive(Error, Debug)]
@@ -175,118 +202,189 @@ pub enum RowError {
     UnsatisfiableConstraints { context: String },
 }
 
-// XXX We need a more robust concept of fronts. We should keep adding border tiles on both the
-// east and western "fronts" until we get a border back. This way we'll be able to tile
-// configurations that expand by more than 1 tile per row. E.g., [west] [meat] [east] that can
-// all grow independantly. Once this completes all 3 components become the next row.
 impl<'process> Row<'process> {
     pub fn new(
         pile: &'process DominoPile,
         border: &TileRef,
         board: &Vec<TileRef>,
     ) -> Result<Self, RowError> {
-        let both_fronts = 2; // west + east
-        let mut row: Vec<TileCloud> = Vec::with_capacity(board.len() + both_fronts);
-
         // XXX We have no way of verifying whether or not border is a valid
         // reference. Is this ok?
 
-        // The main idea is that we may or may not use the border clouds. They are only added in
-        // case the machine expands. That leaves the loop where we generate the successor cloud
-        // based on the current row of tiles.
+        let mut by_north: HashMap<Pip, Vec<TileRef>> = HashMap::new();
+        let mut by_west: HashMap<(Pip, Pip), Vec<TileRef>> = HashMap::new();
+        let mut by_east: HashMap<(Pip, Pip), Vec<TileRef>> = HashMap::new();
+        for (r, tile) in pile.tiles() {
+            by_north.entry(tile.north).or_default().push(r);
+            by_west.entry((tile.north, tile.west)).or_default().push(r);
+            by_east.entry((tile.north, tile.east)).or_default().push(r);
+        }
+
+        Ok(Self {
+            pile,
+            board: board.clone(),
+            border: *border,
+
+            by_north,
+            by_west,
+            by_east,
+        })
+    }
 
-        // XXX depending on how costly this is, we should pre-compute the western and eastern
-        // clouds
-        let latitude: HashSet<TileRef> =
-            pile.matches(border, Direction::South).into_iter().collect();
+    pub fn to_vec(self) -> Result<Vec<TileRef>, RowError> {
+        let norths: Vec<Pip> = self.board.iter().map(|r| self.pile[*r].south).collect();
+        let under = |north: &Pip| self.by_north.get(north).cloned().unwrap_or_default();
 
-        // west
-        {
-            let longitude: HashSet<TileRef> =
-                pile.matches(border, Direction::East).into_iter().collect();
-            let cloud: Vec<TileRef> = longitude.intersection(&latitude).cloned().collect();
-            let cloud = TileCloud::new(pile, cloud, TileCloudConf::Prefer(*border));
-            row.push(cloud);
+        let anchor = match (0..norths.len()).min_by_key(|i| under(&norths[*i]).len()) {
+            Some(anchor) => anchor,
+            None => return Ok(Vec::new()),
+        };
+        let head = TileCloud::new(self.pile, under(&norths[anchor]), TileCloudConf::Avoid(self.border));
+        if head.cloud.is_empty() {
+            Err(RowError::UnsatisfiableConstraints {
+                context: format!("nothing goes under cloud {}", anchor),
+            })?;
         }
 
-        for r in board.iter() {
-            let cloud = pile.matches(r, Direction::South);
-            let cloud = TileCloud::new(pile, cloud, TileCloudConf::Avoid(*border));
-            row.push(cloud);
+        let west: Vec<Pip> = norths[..anchor].iter().rev().cloned().collect();
+        let west = self.side(&west, &head, Direction::West)?;
+        let east = self.side(&norths[anchor + 1..], &head, Direction::East)?;
+
+        let mut row: Vec<TileCloud<'process>> = west.into_iter().rev().collect();
+        row.push(head);
+        row.extend(east);
+
+        // Only neighbours constrain each other, so once every cloud has been squeezed against the
+        // one to the east of it, every tile left has something to go with it all the way along.
+        for i in (0..row.len() - 1).rev() {
+            let (earlier, later) = row[i..i + 2].split_at_mut(1);
+            let cloud = &mut earlier[0];
+            let succ = &later[0];
+
+            cloud.constrain(succ, &Orientation::East).map_err(|_| {
+                RowError::UnsatisfiableConstraints {
+                    context: format!("eastern: cloud {}: {}, other: {}", i, cloud, succ),
+                }
+            })?;
         }
 
-        // east
-        {
-            let longitude: HashSet<TileRef> =
-                pile.matches(border, Direction::West).into_iter().collect();
-            let cloud: Vec<TileRef> = longitude.intersection(&latitude).cloned().collect();
-            let cloud = TileCloud::new(pile, cloud, TileCloudConf::Prefer(*border));
-            row.push(cloud);
+        // Which means we can go along picking tiles as long as each one agrees with the last.
+        let mut next: Vec<TileRef> = Vec::with_capacity(row.len());
+        for cloud in row.iter_mut() {
+            if let Some(pred) = next.last() {
+                cloud.pin(&Direction::West, self.pile[*pred].east)?;
+            }
+
+            next.push(cloud.select()?);
         }
 
-        Ok(Self {
-            pile: pile,
-            row: row,
-            border: *border,
-        })
+        // Any void we didn't need is left off. This is to prevent us adding tiles every step.
+        let start = next.iter().position(|r| *r != self.border).unwrap_or(next.len());
+        let end = next.iter().rposition(|r| *r != self.border).map_or(start, |i| i + 1);
+
+        Ok(next[start..end].to_vec())
     }
 
-    pub fn to_vec(mut self) -> Result<Vec<TileRef>, RowError> {
-        let first: usize = 0;
-        let last: usize = self.row.len() - 1;
-        for i in 0..self.row.len() {
-            if i > first {
-                // westward
-                /*
-                let pred  = &    self.row[i-1];
-                let cloud = &mut self.row[i];
-                */
-                let (earlier, later) = self.row[i - 1..i + 1].split_at_mut(1);
-                let pred = &earlier[0];
-                let cloud = &mut later[0];
-
-                cloud.constrain(pred, &Orientation::West).map_err(|_| {
-                    RowError::UnsatisfiableConstraints {
-                        context: format!("western: cloud {}: {}, other: {}", i, cloud, pred),
-                    }
+    // The clouds on one side of the anchor and then the void past them, nearest the anchor first.
+    // outward is what's above each of them, in the same order.
+    fn side(&self, outward: &[Pip], anchor: &TileCloud<'process>, side: Direction) -> Result<Vec<TileCloud<'process>>, RowError> {
+        if let Some(clouds) = self.between(outward, anchor, side) {
+            return Ok(clouds);
+        }
+
+        // Whatever ran off the end is all there is between here and the anchor, so there's
+        // nothing to be gained by coming in from the border.
+        let mut clouds: Vec<TileCloud<'process>> = Vec::with_capacity(outward.len());
+        for (i, north) in outward.iter().enumerate() {
+            let cloud = self.beside(*north, clouds.last().unwrap_or(anchor), -side, TileCloudConf::Avoid(self.border));
+            if cloud.cloud.is_empty() {
+                Err(RowError::UnsatisfiableConstraints {
+                    context: format!("nothing fits {} cells {} of the anchor", i + 1, side),
                 })?;
             }
+            clouds.push(cloud);
+        }
 
-            if i < last {
-                // eastward
-                /*
-                let succ  = &    self.row[i+1];
-                let cloud = &mut self.row[i];
-                */
-                let (earlier, later) = self.row[i..i + 2].split_at_mut(1);
-                let cloud = &mut earlier[0];
-                let succ = &later[0];
-                cloud.constrain(succ, &Orientation::East).map_err(|_| {
-                    RowError::UnsatisfiableConstraints {
-                        context: format!("eastern: cloud {}: {}, other: {}", i, cloud, succ),
-                    }
+        let void = self.void(clouds.last().unwrap_or(anchor), side)?;
+        clouds.extend(void);
+
+        Ok(clouds)
+    }
+
+    // Like side, with a single cloud of void against the border, unless that doesn't fit.
+    fn between(&self, outward: &[Pip], anchor: &TileCloud<'process>, side: Direction) -> Option<Vec<TileCloud<'process>>> {
+        let border = self.pile[self.border];
+        let against = self.index(side).get(&(border.south, border.cardinal(&-side))).cloned().unwrap_or_default();
+
+        let mut inward = vec![TileCloud::new(self.pile, against, TileCloudConf::Prefer(self.border))];
+        for north in outward.iter().rev() {
+            let cloud = self.beside(*north, &inward[inward.len() - 1], side, TileCloudConf::Avoid(self.border));
+            inward.push(cloud);
+        }
+
+        let mut clouds: Vec<TileCloud<'process>> = Vec::with_capacity(inward.len());
+        for mut cloud in inward.into_iter().rev() {
+            cloud.constrain(clouds.last().unwrap_or(anchor), &-side).ok()?;
+            clouds.push(cloud);
+        }
+
+        Some(clouds)
+    }
+
+    // Whatever can go under north with neighbour on the given side of it.
+    fn beside(&self, north: Pip, neighbour: &TileCloud<'process>, side: Direction, conf: TileCloudConf) -> TileCloud<'process> {
+        let index = self.index(side);
+        let tiles = neighbour
+            .positional_pips(&-side)
+            .iter()
+            .filter_map(|pip| index.get(&(north, *pip)))
+            .flatten()
+            .cloned()
+            .collect();
+
+        TileCloud::new(self.pile, tiles, conf)
+    }
+
+    // Tiles by their northern pip and whichever pip is on the given side.
+    fn index(&self, side: Direction) -> &HashMap<(Pip, Pip), Vec<TileRef>> {
+        match side {
+            Direction::West => &self.by_west,
+            Direction::East => &self.by_east,
+            _ => unreachable!("rows only grow sideways"),
+        }
+    }
+
+    // The clouds of void that go on the given side of edge, nearest first. The last is the first
+    // that can have the border on its far side.
+    fn void(&self, edge: &TileCloud<'process>, side: Direction) -> Result<Vec<TileCloud<'process>>, RowError> {
+        let border = self.pile[self.border];
+        let meets = border.cardinal(&-side);
+
+        // Every extra cell the row grows by needs a tile from under the void that passes something
+        // on to the next one, so there's no point in going on for longer than there are such tiles.
+        let latitude = self.by_north.get(&border.south).map_or(0, Vec::len);
+        let mut void: Vec<TileCloud<'process>> = Vec::new();
+        while void.len() <= latitude {
+            let mut cloud = self.beside(border.south, void.last().unwrap_or(edge), -side, TileCloudConf::Prefer(self.border));
+            if cloud.cloud.is_empty() {
+                Err(RowError::UnsatisfiableConstraints {
+                    context: format!("nothing in the void fits {} of cloud {}", side, void.len()),
                 })?;
             }
-        }
 
-        // Check to see if we even have a valid set of tiles to work with.
-        let mut next = Vec::new();
-        for (i, cloud) in self.row.iter().enumerate() {
-            let tile_ref = cloud.select()?;
-
-            // Now that we have some valid tiles, let's see if we need to
-            // remove the ends. Remove the border pieces if they are the
-            // expected border pieces. This is to prevent us adding 2 tiles per
-            // step.
-            let in_border_position = i == 0 || i == (self.row.len() - 1);
-            let is_border = tile_ref == self.border;
-            let keep = !(in_border_position && is_border);
-
-            if keep {
-                next.push(tile_ref);
+            let meeting = cloud.cloud.iter().any(|r| self.pile[*r].cardinal(&side) == meets);
+            if meeting {
+                cloud.pin(&side, meets)?;
+            }
+            void.push(cloud);
+            if meeting {
+                return Ok(void);
             }
         }
-        Ok(next)
+
+        Err(RowError::UnsatisfiableConstraints {
+            context: format!("the void {} of the row never meets the border", side),
+        })
     }
 }
 
@@ -399,6 +497,34 @@ mod constraint_tests {
         assert_eq!(succ, verified_succ);
     }
 
+    #[test]
+    fn signals_cross_several_tiles() {
+        let border = Tile::new(0, 0, 0, 0);
+        let starter_tile = Tile::new(0, 0, 10, 0);
+        let send = Tile::new(10, 5, 1, 0);
+        let pass = Tile::new(0, 6, 2, 5);
+        let receive = Tile::new(0, 0, 10, 6);
+        // The signal goes two tiles past the end of the row, so it has to grow by two.
+        let pile = vec![border, starter_tile, send, pass, receive];
+
+        let pile = DominoPile::new(pile.clone().into_iter().map(Domino::pure).collect());
+
+        let init = vec![*pile.get(&starter_tile).expect("tile should be present")];
+        let row = Row::new(
+            &pile,
+            pile.get(&border).expect("tile should be present"),
+            &init,
+        )
+        .expect("valid row");
+        let succ = row.to_vec().expect("valid successor row");
+
+        let verified_succ: Vec<TileRef> = vec![send, pass, receive]
+            .iter()
+            .map(|tile| *pile.get(tile).expect("tile should be present"))
+            .collect();
+        assert_eq!(succ, verified_succ);
+    }
+
     #[test]
     fn impossible_constraints() {
         let border = Tile::new(0, 0, 0, 0);
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write;
+
+use thiserror::Error;
+
+use crate::compiler;
+use crate::wmach;
+
+// The C runtime in src/crt includes Program.h in the middle of `Program(Environment *Env)`, so what
+// we generate is a function body made of its macros, one statement per instruction. Every
+// instruction a jump can land on gets an L<offset> label to goto, with the source labels that name
+// it in a comment since those can't always be spelt in C.
+#[derive(Error, Debug)]
+pub enum CrtError {
+    #[error("Instruction {position} jumps to {target} but the program is only {len} instructions long.")]
+    BadTarget {
+        position: wmach::InsnOffset,
+        target: wmach::InsnOffset,
+        len: usize,
+    },
+
+    #[error("Format: {source}")]
+    Format {
+        #[from]
+        source: fmt::Error,
+    },
+}
+
+impl compiler::Located for CrtError {
+    fn position(&self) -> Option<wmach::InsnOffset> {
+        match self {
+            CrtError::BadTarget { position, .. } => Some(*position),
+            CrtError::Format { .. } => None,
+        }
+    }
+}
+
+// What ends up in src/crt/Program.h.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub source: String,
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn label(offset: wmach::InsnOffset) -> String {
+    format!("L{}", offset)
+}
+
+// The runtime's head starts on the very first bit of its memory, so nothing could go left of
+// where it started without falling off the tape. We move it to the middle first, and the data's
+// first bit goes there.
+fn data(src: &mut String, data: &wmach::Data) -> fmt::Result {
+    writeln!(src, "    SEEKR(MEMORY_SIZE * BITSIZE(Cell) / 2);")?;
+    if data.is_empty() {
+        writeln!(src)?;
+        return Ok(());
+    }
+
+    writeln!(src, "    // {}", data)?;
+    let mut head = 0;
+    for (cell, _) in data.bits.iter().enumerate().filter(|(_, bit)| **bit) {
+        writeln!(src, "    SEEKR({});", cell - head)?;
+        writeln!(src, "    SET();")?;
+        head = cell;
+    }
+    if head < data.head {
+        writeln!(src, "    SEEKR({});", data.head - head)?;
+    } else if head > data.head {
+        writeln!(src, "    SEEKL({});", head - data.head)?;
+    }
+    writeln!(src)?;
+
+    Ok(())
+}
+
+fn insn(insn: &wmach::Insn) -> String {
+    match insn {
+        wmach::Insn::Write(wmach::WriteOp::Set) => "SET();".to_string(),
+        wmach::Insn::Write(wmach::WriteOp::Unset) => "UNSET();".to_string(),
+        wmach::Insn::Seek(wmach::SeekOp::Left, n) => format!("SEEKL({});", n),
+        wmach::Insn::Seek(wmach::SeekOp::Right, n) => format!("SEEKR({});", n),
+        wmach::Insn::Io(wmach::IoOp::In) => "INPUT();".to_string(),
+        wmach::Insn::Io(wmach::IoOp::Out) => "OUTPUT();".to_string(),
+        wmach::Insn::Jmp(branch_t, branch_f) => format!("JMP({}, {});", label(*branch_t), label(*branch_f)),
+        wmach::Insn::Debug => "DEBUG();".to_string(),
+        // There's nothing to wait on so we may as well stop.
+        wmach::Insn::Halt => "goto Bail;".to_string(),
+    }
+}
+
+// Makes the Program.h that src/crt is built around.
+pub struct Crt;
+
+impl compiler::Backend for Crt {
+    type Target = Program;
+    type Error = CrtError;
+
+    fn compile(&self, program: &wmach::Program) -> Result<Self::Target, Self::Error> {
+        let len = program.instructions.len();
+
+        // Only the instructions somebody can get to need a label, otherwise the C compiler moans.
+        let mut names: BTreeMap<wmach::InsnOffset, Vec<&str>> = BTreeMap::new();
+        for (name, offset) in program.labels.iter() {
+            names.entry(*offset).or_default().push(name);
+        }
+        for (position, insn) in program.instructions.iter().enumerate() {
+            if let wmach::Insn::Jmp(branch_t, branch_f) = insn {
+                for target in [*branch_t, *branch_f] {
+                    if target > len {
+                        Err(CrtError::BadTarget { position, target, len })?;
+                    }
+                    names.entry(target).or_default();
+                }
+            }
+        }
+
+        let mut src = String::new();
+        writeln!(src, "// Generated by bones. Included by ProgramCtx.c as the body of Program().")?;
+        writeln!(src)?;
+        data(&mut src, &program.data)?;
+
+        for (position, insn) in program.instructions.iter().map(insn).chain(std::iter::once(";".to_string())).enumerate() {
+            if let Some(names) = names.get_mut(&position) {
+                names.sort();
+                if names.is_empty() {
+                    writeln!(src, "{}:", label(position))?;
+                } else {
+                    writeln!(src, "{}: // {}", label(position), names.join(", "))?;
+                }
+            }
+            writeln!(src, "    {}", insn)?;
+        }
+
+        Ok(Program { source: src })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+    use std::str::FromStr;
+
+    use crate::compiler::Backend;
+
+    fn compile(src: &str) -> Program {
+        let program = wmach::Ast::from_str(src)
+            .expect("should parse fine")
+            .resolve_labels()
+            .expect("labels should resolve");
+
+        Crt.compile(&program).expect("should compile fine")
+    }
+
+    #[test]
+    fn labels_and_seeks() {
+        let program = compile("start: + >>> jmp done, start\ndone: <2 !");
+        let lines: Vec<&str> = program.source.lines().filter(|line| !line.is_empty() && !line.starts_with("//")).collect();
+
+        assert_eq!(
+            lines,
+            vec![
+                "    SEEKR(MEMORY_SIZE * BITSIZE(Cell) / 2);",
+                "L0: // start",
+                "    SET();",
+                "    SEEKR(3);",
+                "    JMP(L3, L0);",
+                "L3: // done",
+                "    SEEKL(2);",
+                "    DEBUG();",
+                "    ;",
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_target() {
+        let program = wmach::Program {
+            instructions: vec![wmach::Insn::Jmp(0, 5)],
+            labels: wmach::LabelMap::new(),
+            data: wmach::Data::default(),
+        };
+
+        match Crt.compile(&program) {
+            Err(CrtError::BadTarget { position: 0, target: 5, len: 1 }) => (),
+            result => panic!("expected a bad target: {:?}", result),
+        }
+    }
+
+    // Builds the runtime with our output as Program.h and runs it, or None when there isn't a C
+    // compiler around.
+    fn run(program: &Program, name: &str) -> Option<std::process::Output> {
+        let runtime = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/crt");
+        let dir = std::env::temp_dir().join(format!("bones-crt-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).expect("mkdir");
+        let mut sources = Vec::new();
+        for entry in std::fs::read_dir(&runtime).expect("src/crt should be there") {
+            let path = entry.expect("readdir").path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("c") => sources.push(dir.join(path.file_name().expect("a file"))),
+                Some("h") => (),
+                _ => continue,
+            }
+            std::fs::copy(&path, dir.join(path.file_name().expect("a file"))).expect("copy");
+        }
+        std::fs::write(dir.join("Program.h"), program.to_string()).expect("write");
+
+        let cc = std::env::var("CC").unwrap_or("cc".to_string());
+        let exe = dir.join("wm");
+        let built = match Command::new(&cc).arg("-std=c11").arg("-o").arg(&exe).args(&sources).output() {
+            Ok(built) => built,
+            Err(e) => {
+                eprintln!("skipping, unable to run {}: {}", cc, e);
+                return None;
+            }
+        };
+        assert!(built.status.success(), "{}", String::from_utf8_lossy(&built.stderr));
+
+        let ran = Command::new(&exe)
+            .stdin(Stdio::null())
+            .output()
+            .expect("should run");
+
+        std::fs::remove_dir_all(&dir).expect("cleanup");
+        Some(ran)
+    }
+
+    #[test]
+    fn builds_against_the_runtime() {
+        // Prints the 'A' from the data twice, going round a loop with a flag further up the tape.
+        let program = compile(
+            "data \"A\"
+             again: . >. >. >. >. >. >. >.
+                 >9 jmp done, first
+             first: + <16 jmp again, again
+             done: halt",
+        );
+
+        if let Some(ran) = run(&program, "loop") {
+            assert!(ran.status.success(), "{}", String::from_utf8_lossy(&ran.stderr));
+            assert_eq!(ran.stdout, b"AA");
+        }
+    }
+
+    #[test]
+    fn goes_left_of_the_start() {
+        let program = compile("data \"A\" <8 + >8 . >. >. >. >. >. >. >.");
+
+        if let Some(ran) = run(&program, "left") {
+            assert!(ran.status.success(), "{}", String::from_utf8_lossy(&ran.stderr));
+            assert_eq!(ran.stdout, b"A");
+        }
+    }
+
+    // Nobody's debugging this, so the breakpoint shouldn't get in the way.
+    #[test]
+    fn breakpoints_carry_on() {
+        let program = compile("data \"B\" ! . >. >. >. >. >. >. >.");
+
+        if let Some(ran) = run(&program, "breakpoint") {
+            assert!(ran.status.success(), "{:?}: {}", ran.status, String::from_utf8_lossy(&ran.stderr));
+            assert_eq!(ran.stdout, b"B");
+        }
+    }
+}
@@ -1,6 +1,7 @@
 #include <stdio.h>
 #include <string.h>
 #include <stdlib.h>
+#include <signal.h>
 
 #include "IoBuffer.h"
 #include "Memory.h"
@@ -41,14 +42,14 @@ main(
 
     status = 0;
 
-    rawMemory = malloc(MEMORY_SIZE);
+    rawMemory = malloc(MEMORY_SIZE * sizeof(Cell));
     if (rawMemory == NULL)
     {
         status = 1;
         WmWarn("Unable to allocate memory");
         goto Bail;
     }
-    memset(rawMemory, 0, MEMORY_SIZE);
+    memset(rawMemory, 0, MEMORY_SIZE * sizeof(Cell));
 
     io.GetByte = (GetByteFn) getc;
     io.GetContext = stdin;
@@ -56,6 +57,13 @@ main(
     io.PutContext = stdout;
     DisableBuffering();
 
+    //
+    // Breakpoint raises SIGTRAP, which would kill us without a debugger
+    // around. Debuggers still see it when it's ignored.
+    //
+
+    signal(SIGTRAP, SIG_IGN);
+
     CHECK(status = EnvironmentInit(&env, &io, rawMemory, MEMORY_SIZE));
 
     Program(&env);
@@ -4,6 +4,7 @@
 
 #include <err.h>
 #include <errno.h>
+#include <signal.h>
 #include <unistd.h>
 
 #include "Util.h"
@@ -143,6 +144,7 @@ Breakpoint(
     void
     )
 {
-    __builtin_debugtrap();
-    //__asm__("int $3");
+    // Unlike __builtin_trap, this carries on afterwards with either compiler. Main ignores
+    // SIGTRAP so that it only stops anything when there's a debugger attached.
+    raise(SIGTRAP);
 }
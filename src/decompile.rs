@@ -0,0 +1,406 @@
+use thiserror::Error;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::tessera::BASE_OFFSET;
+use crate::tiling::pip_from_components;
+use crate::tiling::DominoPile;
+use crate::tiling::Pip;
+use crate::tiling::SideEffects;
+use crate::tiling::Tile;
+use crate::tiling::EMPTY_PIP;
+use crate::tiling::ONE_PIP;
+use crate::tiling::UNALLOCATED_PIP;
+use crate::tiling::ZERO_PIP;
+use crate::wmach;
+
+#[derive(Error, Debug)]
+pub enum DecompileError {
+    #[error("These tiles don't look like anything the compiler makes: {}", list(.tiles))]
+    UnknownTiles { tiles: Vec<Tile> },
+
+    #[error("There are no tiles for instruction {offset}")]
+    MissingInstruction { offset: wmach::InsnOffset },
+
+    #[error("The tiles for instruction {offset} don't add up to an instruction: {}", list(.tiles))]
+    IncompleteInstruction {
+        offset: wmach::InsnOffset,
+        tiles: Vec<Tile>,
+    },
+}
+
+fn list(tiles: &[Tile]) -> String {
+    let tiles: Vec<String> = tiles.iter().map(|tile| tile.to_string()).collect();
+    tiles.join(", ")
+}
+
+// See pip_from_components. Plain bits and the void aren't the head.
+fn head(pip: Pip) -> Option<(usize, usize)> {
+    if pip <= ONE_PIP || pip == UNALLOCATED_PIP {
+        return None;
+    }
+
+    Some((pip >> 1, pip & 1))
+}
+
+// Everything tessera puts in a tile set that isn't part of an instruction.
+fn scaffolding() -> Vec<Tile> {
+    let magic = usize::MAX;
+    let start = pip_from_components(BASE_OFFSET, 0);
+
+    vec![
+        // Alcoves
+        Tile::new(UNALLOCATED_PIP, EMPTY_PIP, UNALLOCATED_PIP, UNALLOCATED_PIP),
+        Tile::new(UNALLOCATED_PIP, UNALLOCATED_PIP, UNALLOCATED_PIP, EMPTY_PIP),
+        // Persistence
+        Tile::new(ZERO_PIP, EMPTY_PIP, ZERO_PIP, EMPTY_PIP),
+        Tile::new(ONE_PIP, EMPTY_PIP, ONE_PIP, EMPTY_PIP),
+        // The void
+        Tile::new(UNALLOCATED_PIP, UNALLOCATED_PIP, UNALLOCATED_PIP, UNALLOCATED_PIP),
+        // The initial row
+        Tile::new(UNALLOCATED_PIP, magic, start, magic),
+        Tile::new(UNALLOCATED_PIP, magic, EMPTY_PIP, UNALLOCATED_PIP),
+        Tile::new(UNALLOCATED_PIP, UNALLOCATED_PIP, EMPTY_PIP, magic),
+        // and the data in it
+        Tile::new(UNALLOCATED_PIP, magic, ZERO_PIP, magic),
+        Tile::new(UNALLOCATED_PIP, magic, ONE_PIP, magic),
+        Tile::new(UNALLOCATED_PIP, magic, start | 1, magic),
+    ]
+}
+
+// An entry tile and what it does when placed.
+type Half = (Tile, SideEffects<()>);
+
+// The two tiles an instruction starts with, one for each bit the head can be sitting on.
+struct Entry {
+    tiles: [Tile; 2],
+    side_effects: [SideEffects<()>; 2],
+}
+
+// Turn a tile set made by tessera's compiler back into the program it came from. This is the
+// inverse of mk_write, mk_seek, mk_io, mk_jmp, mk_debug and mk_halt. Seeks come back as far as
+// they went, so a program with run-length seeks comes back with them.
+//
+// A jmp to itself and a halt make exactly the same tiles, so both come back as halt. The halt
+// the compiler puts after the last instruction is dropped again. Every jump target gets a
+// synthetic label. Only the first row knows what was on the tape to begin with, so the program
+// always comes back with an empty one.
+pub fn decompile(pile: &DominoPile) -> Result<wmach::Program, DecompileError> {
+    let mut leftover: HashSet<Tile> = pile.tiles().map(|(_, tile)| tile).collect();
+    for tile in scaffolding() {
+        leftover.remove(&tile);
+    }
+
+    // Sort the head tiles out by the instruction they belong to. Input alternates come along
+    // with their domino so they don't need looking at.
+    let mut entries: BTreeMap<usize, [Option<Half>; 2]> = BTreeMap::new();
+    for (r, tile) in pile.tiles() {
+        if !leftover.contains(&tile) {
+            continue;
+        }
+
+        if let Some((position, bit)) = head(tile.north) {
+            let side_effect = match pile.get_side_effects(&r) {
+                SideEffects::Pure(bias) => SideEffects::Pure(bias),
+                SideEffects::In(_) => SideEffects::In([(), ()]),
+                SideEffects::Out(value) => SideEffects::Out(value),
+                SideEffects::Break => SideEffects::Break,
+            };
+            entries.entry(position).or_default()[bit] = Some((tile, side_effect));
+        }
+    }
+
+    let mut code = Vec::new();
+    for (expected, (position, entry)) in (BASE_OFFSET..).zip(entries) {
+        let offset = position - BASE_OFFSET;
+        if position != expected {
+            Err(DecompileError::MissingInstruction {
+                offset: expected - BASE_OFFSET,
+            })?;
+        }
+
+        let entry = match entry {
+            [Some((tile_0, effect_0)), Some((tile_1, effect_1))] => Entry {
+                tiles: [tile_0, tile_1],
+                side_effects: [effect_0, effect_1],
+            },
+            [tile_0, tile_1] => Err(DecompileError::IncompleteInstruction {
+                offset,
+                tiles: tile_0.into_iter().chain(tile_1).map(|(tile, _)| tile).collect(),
+            })?,
+        };
+
+        let (insn, used) = recognise(position, &entry, &leftover).ok_or_else(|| {
+            DecompileError::IncompleteInstruction {
+                offset,
+                tiles: entry.tiles.to_vec(),
+            }
+        })?;
+        for tile in used {
+            leftover.remove(&tile);
+        }
+
+        code.push(insn);
+    }
+
+    if !leftover.is_empty() {
+        let mut tiles: Vec<Tile> = leftover.into_iter().collect();
+        tiles.sort_by_key(|tile| (tile.north, tile.east, tile.south, tile.west));
+        Err(DecompileError::UnknownTiles { tiles })?;
+    }
+
+    // The compiler always finishes with a halt so that falling off the end has somewhere to go.
+    if let Some(wmach::Insn::Halt) = code.last() {
+        code.pop();
+    }
+
+    let mut labels = HashMap::new();
+    for insn in code.iter() {
+        if let wmach::Insn::Jmp(branch_t, branch_f) = insn {
+            for target in [*branch_t, *branch_f] {
+                labels.insert(format!("_{}", target), target);
+            }
+        }
+    }
+
+    Ok(wmach::Program {
+        instructions: code,
+        labels,
+        data: wmach::Data::default(),
+    })
+}
+
+// Work out which instruction the entry tiles at position are for, along with every tile that
+// instruction is made of.
+fn recognise(position: usize, entry: &Entry, leftover: &HashSet<Tile>) -> Option<(wmach::Insn, Vec<Tile>)> {
+    let [tile_0, tile_1] = entry.tiles;
+    let used = entry.tiles.to_vec();
+    let next = |bit| pip_from_components(position + 1, bit);
+
+    match entry.side_effects {
+        [SideEffects::Out(false), SideEffects::Out(true)] => {
+            if tile_0.south == next(0) && tile_1.south == next(1) {
+                return Some((wmach::Insn::Io(wmach::IoOp::Out), used));
+            }
+            return None;
+        }
+        // The alternates already checked out when the pile was made.
+        [SideEffects::In(_), SideEffects::In(_)] => {
+            return Some((wmach::Insn::Io(wmach::IoOp::In), used));
+        }
+        [SideEffects::Break, SideEffects::Break] => {
+            if tile_0.south == next(0) && tile_1.south == next(1) {
+                return Some((wmach::Insn::Debug, used));
+            }
+            return None;
+        }
+        [SideEffects::Pure(_), SideEffects::Pure(_)] => (),
+        _ => return None,
+    };
+
+    // Seeks hand the head along the row through the east or west pip, counting down to whichever
+    // tile picks it up. The last step of the count is always the instruction's own position.
+    let bind = position;
+    let (direction, mut signal) = match (tile_0.east, tile_0.west) {
+        (EMPTY_PIP, EMPTY_PIP) => (None, EMPTY_PIP),
+        (EMPTY_PIP, west) => (Some(wmach::SeekOp::Left), west),
+        (east, EMPTY_PIP) => (Some(wmach::SeekOp::Right), east),
+        _ => return None,
+    };
+
+    if let Some(direction) = direction {
+        if (tile_1.east, tile_1.west) != (tile_0.east, tile_0.west) {
+            return None;
+        }
+        if tile_0.south != ZERO_PIP || tile_1.south != ONE_PIP {
+            return None;
+        }
+
+        // The (east, west) pips of a tile the signal arrives at on one side and leaves by the
+        // other, as in mk_seek.
+        let passing = |arriving: Pip, leaving: Pip| match direction {
+            wmach::SeekOp::Left => (arriving, leaving),
+            wmach::SeekOp::Right => (leaving, arriving),
+        };
+        // Every tile along the way has to be there for every bit, and for the void.
+        let cells = |(east, west), south: &dyn Fn(usize) -> Pip| {
+            vec![
+                Tile::new(ZERO_PIP, east, south(0), west),
+                Tile::new(ONE_PIP, east, south(1), west),
+                Tile::new(UNALLOCATED_PIP, east, south(0), west),
+            ]
+        };
+        let bit = |bit| if bit == 1 { ONE_PIP } else { ZERO_PIP };
+
+        let mut used = used;
+        let mut count = 1;
+        while signal != bind {
+            // Whatever the signal turns into next is on the far side of a plain 0 it crosses.
+            let leaving = leftover.iter().find_map(|tile| {
+                let (arriving, leaving) = match direction {
+                    wmach::SeekOp::Left => (tile.east, tile.west),
+                    wmach::SeekOp::Right => (tile.west, tile.east),
+                };
+                let plain = tile.north == ZERO_PIP && tile.south == ZERO_PIP;
+
+                if plain && arriving == signal && leaving != EMPTY_PIP {
+                    Some(leaving)
+                } else {
+                    None
+                }
+            })?;
+
+            let along = cells(passing(signal, leaving), &bit);
+            if !along.iter().all(|tile| leftover.contains(tile)) || count > leftover.len() {
+                return None;
+            }
+
+            used.extend(along);
+            signal = leaving;
+            count += 1;
+        }
+
+        let bound = cells(passing(bind, EMPTY_PIP), &next);
+        if !bound.iter().all(|tile| leftover.contains(tile)) {
+            return None;
+        }
+
+        let used = used.into_iter().chain(bound).collect();
+        return Some((wmach::Insn::Seek(direction, count), used));
+    }
+
+    if tile_1.east != EMPTY_PIP || tile_1.west != EMPTY_PIP {
+        return None;
+    }
+
+    let (position_0, bit_0) = head(tile_0.south)?;
+    let (position_1, bit_1) = head(tile_1.south)?;
+
+    let insn = if position_0 == position + 1 && position_1 == position + 1 && bit_0 == bit_1 {
+        let value = if bit_0 == 1 {
+            wmach::WriteOp::Set
+        } else {
+            wmach::WriteOp::Unset
+        };
+        wmach::Insn::Write(value)
+    } else if bit_0 == 0 && bit_1 == 1 {
+        if position_0 == position && position_1 == position {
+            wmach::Insn::Halt
+        } else {
+            wmach::Insn::Jmp(position_1 - BASE_OFFSET, position_0 - BASE_OFFSET)
+        }
+    } else {
+        return None;
+    };
+
+    Some((insn, used))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use crate::compiler::Backend;
+    use crate::tessera;
+    use crate::tiling::Domino;
+
+    fn round_trip(src: &str) -> wmach::Program {
+        let program = wmach::Program::from_str(src).expect("should parse fine");
+        let tiles = tessera::Tiles.compile(&program).expect("should compile fine");
+
+        decompile(tiles.pile()).expect("should decompile fine")
+    }
+
+    #[test]
+    fn straight_line() {
+        let program = round_trip("+ > - < , . !");
+
+        assert_eq!(program.to_string(), "    +\n    >\n    -\n    <\n    ,\n    .\n    !\n");
+    }
+
+    #[test]
+    fn jumps_get_labels() {
+        let src = "
+            + > + <
+            scan: jmp right, done
+            right: > jmp scan, scan
+            done: halt
+        ";
+        let original = wmach::Program::from_str(src).expect("should parse fine");
+        let program = round_trip(src);
+
+        assert_eq!(program.instructions, original.instructions);
+        assert_eq!(program.labels.get("_4"), Some(&4));
+        assert_eq!(program.labels.get("_7"), Some(&7));
+        assert_eq!(program.labels.len(), 3);
+
+        // Compiling it again makes the same tiles.
+        let tiles = |program: &wmach::Program| -> HashSet<Tile> {
+            let compiled = tessera::Tiles.compile(program).expect("should compile fine");
+            compiled.pile().tiles().map(|(_, tile)| tile).collect()
+        };
+        assert_eq!(tiles(&program), tiles(&original));
+    }
+
+    #[test]
+    fn run_length_seeks() {
+        let src = "start: >3 + <12 jmp start, end end: <";
+        let original = wmach::Ast::from_str(src)
+            .and_then(|ast| ast.resolve_labels())
+            .expect("should parse fine");
+        let tiles = tessera::Tiles.compile(&original).expect("should compile fine");
+        let program = decompile(tiles.pile()).expect("should decompile fine");
+
+        assert_eq!(program.instructions, original.instructions);
+    }
+
+    #[test]
+    fn self_jumps_are_halts() {
+        let program = round_trip("+ stop: jmp stop, stop");
+
+        assert_eq!(program.instructions, vec![wmach::Insn::Write(wmach::WriteOp::Set), wmach::Insn::Halt]);
+    }
+
+    #[test]
+    fn unknown_tiles() {
+        let mut dominoes: Vec<Domino> = scaffolding().into_iter().map(Domino::pure).collect();
+        let stray = Tile::new(ONE_PIP, 7, ZERO_PIP, 7);
+        dominoes.push(Domino::pure(stray));
+
+        match decompile(&DominoPile::new(dominoes)) {
+            Err(DecompileError::UnknownTiles { tiles }) => assert_eq!(tiles, vec![stray]),
+            x => panic!("Failed to notice the stray tile: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn half_an_instruction() {
+        let mut dominoes: Vec<Domino> = scaffolding().into_iter().map(Domino::pure).collect();
+        let write = Tile::new(pip_from_components(1, 0), EMPTY_PIP, pip_from_components(2, 1), EMPTY_PIP);
+        dominoes.push(Domino::pure(write));
+
+        match decompile(&DominoPile::new(dominoes)) {
+            Err(DecompileError::IncompleteInstruction { offset: 0, tiles }) => assert_eq!(tiles, vec![write]),
+            x => panic!("Failed to notice the missing tile: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn missing_instruction() {
+        let mut dominoes: Vec<Domino> = scaffolding().into_iter().map(Domino::pure).collect();
+        for bit in 0..2 {
+            let north = pip_from_components(2, bit);
+            let south = pip_from_components(3, bit);
+            dominoes.push(Domino::pure(Tile::new(north, EMPTY_PIP, south, EMPTY_PIP)));
+        }
+
+        match decompile(&DominoPile::new(dominoes)) {
+            Err(DecompileError::MissingInstruction { offset: 0 }) => (),
+            x => panic!("Failed to notice the gap: {:?}", x),
+        };
+    }
+}
@@ -0,0 +1,298 @@
+use std::fmt;
+
+// A stretch of source code. nom only ever hands a parser whatever is left of its input, so a span
+// records where it starts as the number of bytes remaining in the source at that point. That is
+// enough to find it again once we have the whole source to look at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    rest: usize,
+    len: usize,
+
+    // Which file of a SourceMap the span is in. Parsers don't know, so this starts out as 0 and
+    // whoever loaded the file fixes it up with in_file().
+    file: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    // Both are 1-based. Columns count characters, not bytes.
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl Span {
+    // Everything in `before` that is not in `after`, minus any trailing whitespace. Both must be
+    // tails of the same source with `after` being the shorter one.
+    pub fn between(before: &str, after: &str) -> Self {
+        assert!(after.len() <= before.len());
+
+        let consumed = &before[..before.len() - after.len()];
+        Span {
+            rest: before.len(),
+            len: consumed.trim_end().len(),
+            file: 0,
+        }
+    }
+
+    pub fn in_file(self, file: usize) -> Self {
+        Span { file, ..self }
+    }
+
+    pub fn file(&self) -> usize {
+        self.file
+    }
+
+    // Where the span starts in `source`. None if it can't be in there, i.e. it came from some
+    // other text.
+    pub fn start(&self, source: &str) -> Option<usize> {
+        let start = source.len().checked_sub(self.rest)?;
+
+        if start + self.len <= source.len() && source.is_char_boundary(start) {
+            Some(start)
+        } else {
+            None
+        }
+    }
+
+    pub fn end(&self, source: &str) -> Option<usize> {
+        self.start(source).map(|start| start + self.len)
+    }
+
+    pub fn text<'a>(&self, source: &'a str) -> Option<&'a str> {
+        source.get(self.start(source)?..self.end(source)?)
+    }
+
+    pub fn locate(&self, source: &str) -> Option<Location> {
+        let start = self.start(source)?;
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+
+        Some(Location {
+            line: source[..start].matches('\n').count() + 1,
+            column: source[line_start..start].chars().count() + 1,
+        })
+    }
+}
+
+// Every file that went into a program, so that spans from any of them can be shown.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    pub origin: Option<String>,
+    pub text: String,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    // Returns the number to give to Span::in_file for spans into this file.
+    pub fn add(&mut self, origin: Option<String>, text: String) -> usize {
+        self.files.push(SourceFile { origin, text });
+        self.files.len() - 1
+    }
+
+    pub fn file(&self, file: usize) -> &SourceFile {
+        &self.files[file]
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    // For spans made from the files in here, which is all of them unless somebody mixed up their
+    // source maps.
+    pub fn snippet(&self, span: Span) -> Snippet {
+        self.get_snippet(span).expect("span should be from a file in the source map")
+    }
+
+    // The same for spans that came from somewhere we don't trust, like a binary off the disk.
+    pub fn get_snippet(&self, span: Span) -> Option<Snippet> {
+        let file = self.files.get(span.file)?;
+        let snippet = Snippet::new(&file.text, span)?;
+
+        match &file.origin {
+            Some(origin) => Some(snippet.with_origin(origin)),
+            None => Some(snippet),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Spanned { node, span }
+    }
+}
+
+// A rustc-style excerpt of the source with carets under the offending text:
+//
+//  --> main.wm:3:11
+//   |
+// 3 | jmp loop, dnoe
+//   |           ^^^^
+//
+#[derive(Debug, Clone)]
+pub struct Snippet {
+    origin: Option<String>,
+    location: Location,
+
+    line: String,
+    width: usize,
+}
+
+impl Snippet {
+    pub fn new(source: &str, span: Span) -> Option<Self> {
+        let start = span.start(source)?;
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+
+        let end = std::cmp::min(span.end(source)?, line_end);
+        let width = std::cmp::max(source[start..end].chars().count(), 1);
+
+        Some(Snippet {
+            origin: None,
+            location: span.locate(source)?,
+
+            line: source[line_start..line_end].trim_end().to_string(),
+            width,
+        })
+    }
+
+    pub fn with_origin(mut self, origin: &str) -> Self {
+        self.set_origin(origin);
+        self
+    }
+
+    pub fn set_origin(&mut self, origin: &str) {
+        self.origin = Some(origin.to_string());
+    }
+
+    pub fn location(&self) -> Location {
+        self.location
+    }
+
+    pub fn origin(&self) -> Option<&str> {
+        self.origin.as_deref()
+    }
+
+    // How many characters the carets cover. Never runs past the end of the line.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+}
+
+impl fmt::Display for Snippet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let number = self.location.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        match &self.origin {
+            Some(origin) => writeln!(f, "{}--> {}:{}", gutter, origin, self.location)?,
+            None => writeln!(f, "{}--> {}", gutter, self.location)?,
+        };
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", number, self.line)?;
+
+        // Tabs are kept so the carets still line up under them.
+        let indent: String = self
+            .line
+            .chars()
+            .take(self.location.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "{} | {}{}", gutter, indent, "^".repeat(self.width))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locate_span() {
+        let source = "+ >\n  jmp   loop\n";
+        let start = source.find("loop").expect("loop is in there");
+        let span = Span::between(&source[start..], &source[start + 4..]);
+
+        assert_eq!(span.text(source), Some("loop"));
+        assert_eq!(span.locate(source), Some(Location { line: 2, column: 9 }));
+    }
+
+    #[test]
+    fn trailing_whitespace() {
+        let source = "label:   \n+";
+        let span = Span::between(source, &source[source.len() - 1..]);
+
+        assert_eq!(span.text(source), Some("label:"));
+    }
+
+    #[test]
+    fn render_snippet() {
+        let source = "+\n\tjmp loop, dnoe\n>";
+        let start = source.find("dnoe").expect("dnoe is in there");
+        let span = Span::between(&source[start..], &source[start + 4..]);
+        let snippet = Snippet::new(source, span).expect("span is in there").with_origin("main.wm");
+
+        let expected = [
+            " --> main.wm:2:12",
+            "  |",
+            "2 | \tjmp loop, dnoe",
+            "  | \t          ^^^^",
+        ]
+        .join("\n");
+        assert_eq!(snippet.to_string(), expected);
+    }
+
+    #[test]
+    fn snippet_from_source_map() {
+        let mut sources = SourceMap::new();
+        sources.add(None, "+ > +".to_string());
+        let lib = "\n  jmp nowhere";
+        let file = sources.add(Some("lib.wm".to_string()), lib.to_string());
+
+        let start = lib.find("nowhere").expect("nowhere is in there");
+        let span = Span::between(&lib[start..], "").in_file(file);
+        let snippet = sources.snippet(span);
+
+        assert!(snippet.to_string().starts_with(" --> lib.wm:2:7\n"));
+        assert!(snippet.to_string().ends_with("2 |   jmp nowhere\n  |       ^^^^^^^"));
+    }
+
+    #[test]
+    fn snippet_at_end_of_input() {
+        let source = "+ >";
+        let span = Span::between(&source[3..], &source[3..]);
+        let snippet = Snippet::new(source, span).expect("span is in there");
+
+        assert_eq!(snippet.location(), Location { line: 1, column: 4 });
+        assert!(snippet.to_string().ends_with("1 | + >\n  |    ^"));
+    }
+
+    #[test]
+    fn span_from_other_text() {
+        let source = "+ > jmp loop, loop";
+        let span = Span::between(&source[4..], &source[7..]);
+
+        assert_eq!(span.text(source), Some("jmp"));
+        assert_eq!(span.start("+ >"), None);
+        assert_eq!(span.text("jmp"), None);
+        assert!(Snippet::new("+", span).is_none());
+        assert!(SourceMap::new().get_snippet(span).is_none());
+    }
+}
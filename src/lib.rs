@@ -5,6 +5,7 @@ use wasm_bindgen::JsCast;
 use std::ops::AddAssign;
 use std::ops::SubAssign;
 use std::borrow::Cow;
+use std::str::FromStr;
 
 // This is recommended for debug builds.
 extern crate console_error_panic_hook;
@@ -14,12 +15,31 @@ mod renderer;
 mod dispatch;
 mod mosaic;
 
-mod compiler;
+pub mod binary;
+pub mod brainfuck;
+pub mod cfg;
+pub mod compiler;
 mod constraint;
+pub mod crt;
+pub mod decompile;
+pub mod diagnostic;
 mod io_buffer;
-mod tessera;
+pub mod lint;
+mod loader;
+pub mod lockstep;
+mod lower;
+// For bones-lsp, which the web page has no use for.
+#[cfg(not(target_arch = "wasm32"))]
+pub mod lsp;
+mod macros;
+pub mod machine;
+pub mod optimize;
+pub mod structured;
+pub mod tessera;
+pub mod threaded;
 mod tiling;
-mod wmach;
+pub mod wasm;
+pub mod wmach;
 
 
 const SCREEN_SAVER_MODE: bool = false;
@@ -132,12 +152,30 @@ pub fn js_main() -> Result<(), JsValue> {
 
 fn main(params: dispatch::Parameters) -> anyhow::Result<()> {
 
+    // ?bin= is a program compiled ahead of time (base64 of what binary.rs writes), which saves
+    // parsing big programs on every load. It wins over ?src=.
+    if let Some((_, bin)) = params.url.query_pairs().find(|(key, _)| key == "bin") {
+        let image = binary::Image::decode(&binary::from_base64(&bin)?)?;
+
+        let mosaic = mosaic::Mosaic::from_program(&image.program)?;
+        let _dispatch = dispatch::Dispatch::new(mosaic, params);
+
+        return Ok(());
+    }
+
     let src = params.url.query_pairs()
         .find(|(key, _)| key == "src")
         .map_or(
             Cow::from(String::from_utf8_lossy(std::include_bytes!("wasm.wm"))),
             |(_, value)| value);
 
+    // ?lint puts warnings about the program on the console.
+    if params.url.query_pairs().any(|(key, _)| key == "lint") {
+        for warning in lint::lint(&wmach::Ast::from_str(&src)?)? {
+            web_sys::console::warn_1(&format!("warning: {}", warning).into());
+        }
+    }
+
     let mosaic = mosaic::Mosaic::new(&src)?;
     let _dispatch = dispatch::Dispatch::new(mosaic, params);
 
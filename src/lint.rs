@@ -0,0 +1,347 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::cfg::{EdgeKind, Node};
+use crate::diagnostic::Snippet;
+use crate::diagnostic::Span;
+use crate::lower;
+use crate::wmach::{Ast, Insn, InsnOffset, LabelId, Stmt, Target, WmachErr};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lint {
+    UnusedLabel,
+    AliasedLabel,
+    IdenticalTargets,
+    AfterSelfLoop,
+    TightLoop,
+}
+
+// Something that is legal but probably not what was meant. Shown the same way as parse errors.
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub lint: Lint,
+    pub message: String,
+    pub span: Span,
+    pub snippet: Snippet,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n{}", self.message, self.snippet)
+    }
+}
+
+// Labels whose last part starts with an underscore are allowed to go unused, e.g. entry points
+// in a library.
+fn quiet(label: &str) -> bool {
+    label.rsplit('.').next().is_some_and(|name| name.starts_with('_'))
+}
+
+pub fn lint(ast: &Ast) -> Result<Vec<Warning>, WmachErr> {
+    // Offsets are into the run-length form so that every instruction is exactly one statement.
+    let program = ast.resolve_labels()?;
+
+    let statements = lower::expand_repeats(&ast.sources, &ast.statements)?;
+
+    let mut spans = Vec::new();
+    let mut labels = Vec::new();
+    let mut jumps = Vec::new();
+    for stmt in statements.iter() {
+        match &stmt.node {
+            Stmt::Label(label) => labels.push((label, stmt.span)),
+            Stmt::Data(_) => (),
+            Stmt::Jmp(branch_t, branch_f) => {
+                jumps.push((spans.len(), branch_t, branch_f));
+                spans.push(stmt.span);
+            }
+            _ => spans.push(stmt.span),
+        }
+    }
+
+    let mut warnings = Vec::new();
+    let mut warn = |lint, message: String, span: Span| {
+        warnings.push(Warning {
+            lint,
+            message,
+            span,
+            snippet: ast.sources.snippet(span),
+        })
+    };
+
+    let used: HashSet<&LabelId> = jumps
+        .iter()
+        .flat_map(|(_, branch_t, branch_f)| vec![&branch_t.node, &branch_f.node])
+        .filter_map(|target| match target {
+            Target::Name(label) => Some(label),
+            Target::NextAddress => None,
+        })
+        .collect();
+
+    let mut first_at: HashMap<InsnOffset, &LabelId> = HashMap::new();
+    for (label, span) in labels.iter() {
+        if !used.contains(label) {
+            if !quiet(label) {
+                warn(Lint::UnusedLabel, format!("Label ``{}'' is never jumped to", label), *span);
+            }
+            continue;
+        }
+
+        let offset = program.labels[*label];
+        match first_at.get(&offset) {
+            Some(first) => warn(
+                Lint::AliasedLabel,
+                format!("Label ``{}'' is the same place as ``{}''", label, first),
+                *span,
+            ),
+            None => {
+                first_at.insert(offset, label);
+            }
+        };
+    }
+
+    for (offset, branch_t, branch_f) in jumps.iter() {
+        // jmp x, x is how you write an unconditional jump. It's different names for the same
+        // place that give the game away.
+        let written_differently = match (&branch_t.node, &branch_f.node) {
+            (Target::Name(t), Target::Name(f)) => t != f,
+            _ => true,
+        };
+
+        if let Insn::Jmp(t, f) = program.instructions[*offset] {
+            if t == f && written_differently {
+                warn(
+                    Lint::IdenticalTargets,
+                    "Both branches of this jmp go to the same instruction".to_string(),
+                    spans[*offset],
+                );
+            }
+        }
+    }
+
+    let targets: HashSet<InsnOffset> = program
+        .instructions
+        .iter()
+        .flat_map(|insn| match insn {
+            Insn::Jmp(t, f) => vec![*t, *f],
+            _ => vec![],
+        })
+        .collect();
+    for (offset, insn) in program.instructions.iter().enumerate() {
+        let next = offset + 1;
+        if next >= spans.len() || targets.contains(&next) {
+            continue;
+        }
+
+        match insn {
+            Insn::Jmp(t, f) if *t == offset && *f == offset => warn(
+                Lint::AfterSelfLoop,
+                "Nothing can get here past the loop before it".to_string(),
+                spans[next],
+            ),
+            Insn::Halt => warn(
+                Lint::AfterSelfLoop,
+                "Nothing can get here past the halt before it".to_string(),
+                spans[next],
+            ),
+            _ => (),
+        }
+    }
+
+    // Blocks that don't change anything always go the same way for a given bit, so once there's a
+    // cycle of them nothing can ever get out of it.
+    let cfg = program.cfg();
+    let pure: Vec<bool> = cfg
+        .blocks
+        .iter()
+        .map(|block| block.writes + block.inputs + block.outputs + block.cells_moved == 0)
+        .collect();
+    let mut reported = HashSet::new();
+    for bit in [false, true] {
+        let next = |block: usize| {
+            cfg.blocks[block]
+                .edges
+                .iter()
+                .find(|edge| match edge.kind {
+                    EdgeKind::True => bit,
+                    EdgeKind::False => !bit,
+                    EdgeKind::Always | EdgeKind::Fallthrough => true,
+                })
+                .and_then(|edge| match edge.to {
+                    Node::Block(to) if pure[to] => Some(to),
+                    _ => None,
+                })
+        };
+
+        for start in (0..cfg.blocks.len()).filter(|block| pure[*block]) {
+            // Walk until we leave the pure blocks or come back to one we've seen.
+            let mut path = vec![start];
+            let mut current = start;
+            while let Some(to) = next(current) {
+                if let Some(position) = path.iter().position(|block| *block == to) {
+                    let cycle = &path[position..];
+                    let head = *cycle.iter().min().expect("cycles aren't empty");
+
+                    // A lone jmp x, x is the usual way to stop, so leave that alone.
+                    let block = &cfg.blocks[head];
+                    let halt = cycle.len() == 1
+                        && block.end - block.start == 1
+                        && matches!(block.edges[..], [ref edge] if edge.kind == EdgeKind::Always);
+
+                    let conditional = cycle.iter().any(|block| {
+                        cfg.blocks[*block]
+                            .edges
+                            .iter()
+                            .any(|edge| edge.kind == EdgeKind::True)
+                    });
+
+                    if !halt && reported.insert(head) {
+                        let when = if conditional {
+                            format!(" once the bit under the head is {}", bit as u8)
+                        } else {
+                            String::new()
+                        };
+                        warn(
+                            Lint::TightLoop,
+                            format!("This loops forever without moving, writing or doing IO{}", when),
+                            spans[block.start],
+                        );
+                    }
+                    break;
+                }
+
+                path.push(to);
+                current = to;
+            }
+        }
+    }
+
+    // Macros and repeats put the same statements in more than one place.
+    let mut seen = HashSet::new();
+    warnings.retain(|warning| seen.insert((warning.lint as u8, warning.span)));
+
+    warnings.sort_by_key(|warning| {
+        let location = warning.snippet.location();
+        (warning.span.file(), location.line, location.column)
+    });
+
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn lints(src: &str) -> Vec<(Lint, String)> {
+        let ast = Ast::from_str(src).expect("should parse fine");
+        lint(&ast)
+            .expect("should lint fine")
+            .into_iter()
+            .map(|warning| (warning.lint, warning.span.text(src).expect("span should be in the source").to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn clean_program() {
+        let src = "
+            + > + <
+            scan: jmp right, done
+            right: > jmp scan, scan
+            done: jmp done, done
+        ";
+        assert_eq!(lints(src), vec![]);
+    }
+
+    #[test]
+    fn unused_and_aliased_labels() {
+        let src = "
+            top: first: second: _spare: +
+            jmp first, end
+            jmp second, end
+            jmp top, end
+            end:
+        ";
+        assert_eq!(
+            lints(src),
+            vec![
+                (Lint::AliasedLabel, "first:".to_string()),
+                (Lint::AliasedLabel, "second:".to_string()),
+            ]
+        );
+
+        assert_eq!(lints("a: + b: -"), vec![
+            (Lint::UnusedLabel, "a:".to_string()),
+            (Lint::UnusedLabel, "b:".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn identical_targets() {
+        let src = "
+            + jmp next
+            next: jmp a, b
+            a: b: -
+        ";
+        let found = lints(src);
+
+        assert!(found.contains(&(Lint::IdenticalTargets, "jmp next".to_string())));
+        assert!(found.contains(&(Lint::IdenticalTargets, "jmp a, b".to_string())));
+    }
+
+    #[test]
+    fn code_after_self_loop() {
+        assert_eq!(
+            lints("+ stop: jmp stop, stop\n  > <"),
+            vec![(Lint::AfterSelfLoop, ">".to_string())]
+        );
+        assert_eq!(
+            lints("+ halt -\n  top: - jmp top, top"),
+            vec![(Lint::AfterSelfLoop, "-".to_string())]
+        );
+    }
+
+    #[test]
+    fn tight_loops() {
+        let src = "
+            , spin: jmp spin, out
+            out: + ping: jmp pong, pong
+            pong: jmp ping, ping
+        ";
+        let found = lints(src);
+
+        assert_eq!(
+            found,
+            vec![
+                (Lint::TightLoop, "jmp spin, out".to_string()),
+                (Lint::TightLoop, "jmp pong, pong".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeats_warn_once() {
+        let src = "data bits 1 repeat 3 { unused: + jmp next next: }";
+        let found = lints(src);
+
+        assert_eq!(
+            found,
+            vec![
+                (Lint::UnusedLabel, "unused:".to_string()),
+                (Lint::IdenticalTargets, "jmp next".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rendered_like_errors() {
+        let ast = Ast::from_str("+\nunused: -").expect("should parse fine");
+        let warnings = lint(&ast).expect("should lint fine");
+
+        assert_eq!(
+            warnings[0].to_string(),
+            "Label ``unused'' is never jumped to\n --> 2:1\n  |\n2 | unused: -\n  | ^^^^^^^"
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::diagnostic::SourceMap;
+use crate::diagnostic::Spanned;
+use crate::macros;
+use crate::wmach::{Argument, LabelId, Program, Stmt, Target, WmachErr};
+
+type Block = Vec<Spanned<Stmt>>;
+
+// Reads a program along with everything it includes or imports. Paths in directives are relative
+// to the file the directive is in.
+struct Loader {
+    sources: SourceMap,
+
+    // Files currently being loaded, outermost first.
+    stack: Vec<PathBuf>,
+
+    // The namespaces the file being loaded is going to end up in, outermost first.
+    namespace: Vec<LabelId>,
+
+    // Every file that has been included so far, along with the namespace it went into.
+    included: HashSet<(Option<LabelId>, PathBuf)>,
+}
+
+pub fn load_str(text: &str) -> Result<(SourceMap, Block), WmachErr> {
+    let mut loader = Loader {
+        sources: SourceMap::new(),
+        stack: Vec::new(),
+        namespace: Vec::new(),
+        included: HashSet::new(),
+    };
+
+    // There is no file to be relative to, so go by wherever we were started from.
+    let statements = loader.load(None, text.to_string())?;
+
+    Ok((loader.sources, statements))
+}
+
+pub fn load_file(path: &Path) -> Result<(SourceMap, Block), WmachErr> {
+    let text = fs::read_to_string(path)?;
+    load_buffer(path, &text)
+}
+
+// Like load_file with text standing in for what is on disk, where there might not be anything yet.
+pub fn load_buffer(path: &Path, text: &str) -> Result<(SourceMap, Block), WmachErr> {
+    let mut loader = Loader {
+        sources: SourceMap::new(),
+        stack: vec![path.canonicalize().unwrap_or_else(|_| path.to_path_buf())],
+        namespace: Vec::new(),
+        included: HashSet::new(),
+    };
+
+    let statements = loader.load(Some(path), text.to_string())?;
+
+    Ok((loader.sources, statements))
+}
+
+impl Loader {
+    fn load(&mut self, path: Option<&Path>, text: String) -> Result<Block, WmachErr> {
+        let origin = path.map(|path| path.to_string_lossy().to_string());
+        let mut statements = Program::parse_statements(&text).map_err(|e| match &origin {
+            Some(origin) => e.with_origin(origin),
+            None => e,
+        })?;
+
+        let file = self.sources.add(origin, text);
+        relocate(&mut statements, file);
+
+        let dir = path.and_then(Path::parent).unwrap_or_else(|| Path::new(""));
+        self.resolve(statements, dir)
+    }
+
+    fn resolve(&mut self, statements: Block, dir: &Path) -> Result<Block, WmachErr> {
+        let mut resolved = Vec::new();
+
+        for mut stmt in statements {
+            match stmt.node {
+                Stmt::Include(path) => resolved.extend(self.read(dir, &path, true)?),
+                Stmt::Import(path, namespace) => {
+                    self.namespace.push(namespace.node.clone());
+                    let library = self.read(dir, &path, false);
+                    self.namespace.pop();
+
+                    let mut library = library?;
+                    qualify(&mut library, &namespace.node);
+                    resolved.extend(library);
+                }
+                Stmt::Macro(ref mut definition) => {
+                    let body = std::mem::take(&mut definition.body);
+                    definition.body = self.resolve(body, dir)?;
+                    resolved.push(stmt);
+                }
+                Stmt::Repeat(_, ref mut body) => {
+                    *body = self.resolve(std::mem::take(body), dir)?;
+                    resolved.push(stmt);
+                }
+                Stmt::Invoke(_, ref mut args) => {
+                    for arg in args.iter_mut() {
+                        if let Argument::Block(block) = &mut arg.node {
+                            *block = self.resolve(std::mem::take(block), dir)?;
+                        }
+                    }
+                    resolved.push(stmt);
+                }
+                _ => resolved.push(stmt),
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    fn read(&mut self, dir: &Path, path: &Spanned<String>, once: bool) -> Result<Block, WmachErr> {
+        let full = dir.join(&path.node);
+        let unreadable = |err| WmachErr::UnreadableFile {
+            path: path.node.clone(),
+            err,
+            snippet: self.sources.snippet(path.span),
+        };
+
+        let canonical = full.canonicalize().map_err(unreadable)?;
+        let text = fs::read_to_string(&full).map_err(unreadable)?;
+
+        if self.stack.contains(&canonical) {
+            Err(WmachErr::IncludeCycle {
+                path: path.node.clone(),
+                snippet: self.sources.snippet(path.span),
+            })?;
+        }
+
+        // A file that two others both include only goes in the first time, otherwise everything
+        // in it is defined twice. Imports are namespaced so each of those gets its own copy, and
+        // so does each namespace that something imported includes it into.
+        let namespace = (!self.namespace.is_empty()).then(|| self.namespace.join("."));
+        if once && !self.included.insert((namespace, canonical.clone())) {
+            return Ok(Vec::new());
+        }
+
+        self.stack.push(canonical);
+        let statements = self.load(Some(&full), text);
+        self.stack.pop();
+
+        statements
+    }
+}
+
+// The parser thinks everything is in file 0.
+fn relocate(statements: &mut [Spanned<Stmt>], file: usize) {
+    for stmt in statements.iter_mut() {
+        stmt.span = stmt.span.in_file(file);
+
+        match &mut stmt.node {
+            Stmt::Jmp(branch_t, branch_f) => {
+                branch_t.span = branch_t.span.in_file(file);
+                branch_f.span = branch_f.span.in_file(file);
+            }
+            Stmt::Macro(definition) => {
+                definition.name.span = definition.name.span.in_file(file);
+                for param in definition.params.iter_mut() {
+                    param.span = param.span.in_file(file);
+                }
+                relocate(&mut definition.body, file);
+            }
+            Stmt::Invoke(name, args) => {
+                name.span = name.span.in_file(file);
+                for arg in args.iter_mut() {
+                    arg.span = arg.span.in_file(file);
+                    if let Argument::Block(block) = &mut arg.node {
+                        relocate(block, file);
+                    }
+                }
+            }
+            Stmt::Include(path) => path.span = path.span.in_file(file),
+            Stmt::Import(path, namespace) => {
+                path.span = path.span.in_file(file);
+                namespace.span = namespace.span.in_file(file);
+            }
+            Stmt::Repeat(_, body) => relocate(body, file),
+            _ => (),
+        }
+    }
+}
+
+// Put every label and macro that an imported library defines into the namespace it was imported
+// as. Names the library uses but doesn't define are left alone.
+struct Namespace<'a> {
+    prefix: &'a str,
+    labels: HashSet<LabelId>,
+    macros: HashSet<LabelId>,
+}
+
+fn qualify(library: &mut Block, prefix: &str) {
+    let mut labels = HashSet::new();
+    macros::collect_labels(library, &mut labels);
+    let macros = library
+        .iter()
+        .filter_map(|stmt| match &stmt.node {
+            Stmt::Macro(definition) => Some(definition.name.node.clone()),
+            _ => None,
+        })
+        .collect();
+
+    let namespace = Namespace {
+        prefix,
+        labels,
+        macros,
+    };
+    namespace.apply(library, &HashSet::new());
+}
+
+impl<'a> Namespace<'a> {
+    // Macro parameters shadow whatever the library defines.
+    fn qualify(&self, defined: &HashSet<LabelId>, params: &HashSet<LabelId>, name: &mut LabelId) {
+        if defined.contains(name) && !params.contains(name) {
+            *name = format!("{}.{}", self.prefix, name);
+        }
+    }
+
+    fn apply(&self, statements: &mut [Spanned<Stmt>], params: &HashSet<LabelId>) {
+        for stmt in statements.iter_mut() {
+            match &mut stmt.node {
+                Stmt::Label(label) => self.qualify(&self.labels, params, label),
+                Stmt::Jmp(branch_t, branch_f) => {
+                    for branch in [branch_t, branch_f] {
+                        if let Target::Name(label) = &mut branch.node {
+                            self.qualify(&self.labels, params, label);
+                        }
+                    }
+                }
+                Stmt::Macro(definition) => {
+                    self.qualify(&self.macros, params, &mut definition.name.node);
+                    let params = definition.params.iter().map(|param| param.node.clone()).collect();
+                    self.apply(&mut definition.body, &params);
+                }
+                Stmt::Invoke(name, args) => {
+                    self.qualify(&self.macros, params, &mut name.node);
+                    for arg in args.iter_mut() {
+                        match &mut arg.node {
+                            Argument::Label(label) => self.qualify(&self.labels, params, label),
+                            Argument::Block(block) => self.apply(block, params),
+                        }
+                    }
+                }
+                Stmt::Repeat(_, body) => self.apply(body, params),
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::machine::{Machine, Status};
+    use crate::wmach::Insn;
+
+    // A scratch directory with the given files in it, which goes away when the test is done.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn join(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn scratch(test: &str, files: &[(&str, &str)]) -> Scratch {
+        let dir = std::env::temp_dir().join(format!("bones-{}-{}", test, std::process::id()));
+        for (name, text) in files.iter() {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().expect("files are in a directory")).expect("mkdir");
+            fs::write(path, text).expect("write");
+        }
+
+        Scratch(dir)
+    }
+
+    #[test]
+    fn include_is_relative_to_the_includer() {
+        let dir = scratch(
+            "include",
+            &[
+                ("main.wm", "include \"lib/ones.wm\"\n> +"),
+                ("lib/ones.wm", "include \"more.wm\" +"),
+                ("lib/more.wm", "start: + >"),
+            ],
+        );
+        let program = Program::from_file(&dir.join("main.wm")).expect("should load fine");
+
+        assert_eq!(program.instructions.len(), 5);
+        assert_eq!(program.labels.get("start"), Some(&0));
+    }
+
+    #[test]
+    fn diamonds_are_included_once() {
+        let dir = scratch(
+            "diamond",
+            &[
+                ("main.wm", "include \"left.wm\"\ninclude \"right.wm\"\njmp ones, ones"),
+                ("left.wm", "include \"ones.wm\" >"),
+                ("right.wm", "include \"ones.wm\" <"),
+                ("ones.wm", "ones: +"),
+            ],
+        );
+        let program = Program::from_file(&dir.join("main.wm")).expect("should load fine");
+
+        assert_eq!(program.instructions.len(), 4);
+        assert_eq!(program.labels.get("ones"), Some(&0));
+    }
+
+    #[test]
+    fn includes_go_once_into_each_namespace() {
+        let lib = ("lib.wm", "include \"common.wm\"\nend: jmp x, x");
+        let common = ("common.wm", "x: +");
+
+        let main = ("main.wm", "import lib as b\ninclude \"common.wm\"\njmp x, x");
+        let dir = scratch("namespace-after", &[lib, common, main]);
+        let program = Program::from_file(&dir.join("main.wm")).expect("should load fine");
+        assert_eq!(program.labels.get("b.x"), Some(&0));
+        assert_eq!(program.labels.get("x"), Some(&2));
+        assert_eq!(program.instructions[1], Insn::Jmp(0, 0));
+        assert_eq!(program.instructions[3], Insn::Jmp(2, 2));
+
+        let main = ("main.wm", "include \"common.wm\"\nimport lib as b\njmp x, x");
+        let dir = scratch("namespace-before", &[lib, common, main]);
+        let program = Program::from_file(&dir.join("main.wm")).expect("should load fine");
+        assert_eq!(program.labels.get("x"), Some(&0));
+        assert_eq!(program.labels.get("b.x"), Some(&1));
+        assert_eq!(program.instructions[2], Insn::Jmp(1, 1));
+        assert_eq!(program.instructions[3], Insn::Jmp(0, 0));
+    }
+
+    #[test]
+    fn imports_are_namespaced() {
+        let dir = scratch(
+            "import",
+            &[
+                (
+                    "main.wm",
+                    "
+                    + > + <
+                    b.skip_ones(done)
+                    done: jmp b.end, b.end
+                    import bits as b
+                    ",
+                ),
+                (
+                    "bits.wm",
+                    "
+                    macro skip_ones(done) {
+                        loop: jmp next, done
+                        next: > jmp loop, loop
+                    }
+                    end:
+                    ",
+                ),
+            ],
+        );
+        let program = Program::from_file(&dir.join("main.wm")).expect("should load fine");
+
+        assert_eq!(program.labels.get("b.end"), Some(&8));
+        assert!(!program.labels.contains_key("end"));
+
+        let mut machine = Machine::with_io(&program, &[][..], std::io::sink());
+        let status = machine.run(100).expect("should run fine");
+        assert_eq!(status, Status::Halted);
+        assert_eq!(machine.tape().head(), 2);
+    }
+
+    #[test]
+    fn cycles_are_caught() {
+        let dir = scratch(
+            "cycle",
+            &[
+                ("main.wm", "include \"a.wm\""),
+                ("a.wm", "+\ninclude \"b.wm\""),
+                ("b.wm", "import a as a"),
+            ],
+        );
+
+        match Program::from_file(&dir.join("main.wm")) {
+            Err(WmachErr::IncludeCycle { path, snippet }) => {
+                assert_eq!(path, "a.wm");
+                assert!(snippet.to_string().contains("b.wm:1:8"));
+            }
+            x => panic!("Failed to catch the cycle: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn errors_point_into_the_right_file() {
+        let dir = scratch(
+            "errors",
+            &[
+                ("main.wm", "include \"lib.wm\"\njmp main_loop"),
+                ("lib.wm", "+\n\njmp nowhere"),
+                ("broken.wm", "include \"missing.wm\""),
+            ],
+        );
+
+        match Program::from_file(&dir.join("main.wm")) {
+            Err(WmachErr::UnknownTarget { snippet, .. }) => {
+                assert!(snippet.to_string().contains("lib.wm:3:5"));
+            }
+            x => panic!("Failed to reject the unknown target: {:?}", x),
+        };
+
+        match Program::from_file(&dir.join("broken.wm")) {
+            Err(WmachErr::UnreadableFile { path, snippet, .. }) => {
+                assert_eq!(path, "missing.wm");
+                assert!(snippet.to_string().contains("broken.wm:1:9"));
+            }
+            x => panic!("Failed to reject the missing file: {:?}", x),
+        };
+    }
+}
@@ -0,0 +1,461 @@
+use thiserror::Error;
+
+use std::fmt;
+
+use crate::compiler::Backend;
+use crate::machine::Machine;
+use crate::machine::MachineError;
+use crate::machine::Status;
+use crate::tessera;
+use crate::tessera::MosaicError;
+use crate::tiling::Tile;
+use crate::tiling::ONE_PIP;
+use crate::tiling::UNALLOCATED_PIP;
+use crate::wmach;
+
+// Everything we can say about the w-machine at a given point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub pc: wmach::InsnOffset,
+    pub head: isize,
+
+    // The tape cells, starting from position `west`. Cells outside of this window are 0.
+    pub west: isize,
+    pub tape: Vec<bool>,
+}
+
+impl Snapshot {
+    pub fn get(&self, position: isize) -> bool {
+        let index = position - self.west;
+        if index < 0 {
+            return false;
+        }
+
+        self.tape.get(index as usize).cloned().unwrap_or(false)
+    }
+
+    fn of_machine<I: std::io::Read, O: std::io::Write>(machine: &Machine<I, O>) -> Self {
+        Snapshot {
+            pc: machine.pc(),
+            head: machine.tape().head(),
+
+            west: machine.tape().start(),
+            tape: machine.tape().bits(),
+        }
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pc {}, head {}, tape ", self.pc, self.head)?;
+        for (i, bit) in self.tape.iter().enumerate() {
+            let position = self.west + i as isize;
+            let bit = if *bit { '1' } else { '0' };
+            if position == self.head {
+                write!(f, "[{}]", bit)?;
+            } else {
+                write!(f, "{}", bit)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Mismatch {
+    #[error("the tiles could not be evolved: {source}")]
+    Tiles { source: Box<MosaicError> },
+
+    #[error("the tile row is not a machine state: {reason}")]
+    Undecodable { reason: String },
+
+    #[error("program counter should be {expected} but the tiles say {found}")]
+    Pc {
+        expected: wmach::InsnOffset,
+        found: wmach::InsnOffset,
+    },
+
+    #[error("head should be at {expected} but the tiles say {found}")]
+    Head { expected: isize, found: isize },
+
+    #[error("bit at {position} should be {expected} but the tiles say {}", !expected)]
+    Bit { position: isize, expected: bool },
+
+    #[error("the machine has halted but the tiles keep going")]
+    NotHalted,
+
+    #[error("the tiles have halted but the machine keeps going")]
+    Halted,
+}
+
+#[derive(Error, Debug)]
+pub enum LockstepError {
+    #[error("Unable to compile program: {source}")]
+    Compile { source: Box<MosaicError> },
+
+    #[error("Machine: {source}")]
+    Machine {
+        #[from]
+        source: MachineError,
+    },
+
+    #[error("Diverged at step {step} executing instruction {offset} ({insn:?}): {mismatch}")]
+    Diverged {
+        step: usize,
+        offset: wmach::InsnOffset,
+        insn: Option<wmach::Insn>,
+        mismatch: Mismatch,
+    },
+}
+
+// A decoded tile row. Cells are the non-void southern pips of the row, in order.
+struct Row {
+    cells: Vec<bool>,
+    head: usize,
+    pc: wmach::InsnOffset,
+
+    // How many cells appeared west of everything the previous row knew about.
+    grown: usize,
+}
+
+fn decode(row: &[Tile]) -> Result<Row, String> {
+    let mut cells = Vec::new();
+    let mut head = None;
+    let mut grown = None;
+    let mut ended = false;
+
+    for tile in row.iter() {
+        // Every cell of the previous row sits directly above one of ours so the first tile that
+        // is not fresh out of the void marks where the old tape begins.
+        if tile.north != UNALLOCATED_PIP && grown.is_none() {
+            grown = Some(cells.len());
+        }
+
+        if tile.south == UNALLOCATED_PIP {
+            if tile.north != UNALLOCATED_PIP {
+                return Err(format!("{} drops a tape cell into the void", tile));
+            }
+            if !cells.is_empty() {
+                ended = true;
+            }
+            continue;
+        }
+
+        if ended {
+            return Err(format!("{} follows a hole in the tape", tile));
+        }
+
+        // See pip_from_components: anything that isn't a plain bit is the head.
+        if tile.south > ONE_PIP {
+            if head.is_some() {
+                return Err(format!("{} is a second head", tile));
+            }
+
+            let position = tile.south >> 1;
+            if position < tessera::BASE_OFFSET {
+                return Err(format!("{} has no program counter", tile));
+            }
+            head = Some((cells.len(), position - tessera::BASE_OFFSET));
+        }
+
+        cells.push(tile.south & 1 == 1);
+    }
+
+    let (head, pc) = head.ok_or_else(|| "there is no head".to_string())?;
+
+    Ok(Row {
+        cells,
+        head,
+        pc,
+        grown: grown.unwrap_or(0),
+    })
+}
+
+fn compare(expected: &Snapshot, found: &Snapshot) -> Option<Mismatch> {
+    if expected.pc != found.pc {
+        return Some(Mismatch::Pc {
+            expected: expected.pc,
+            found: found.pc,
+        });
+    }
+
+    if expected.head != found.head {
+        return Some(Mismatch::Head {
+            expected: expected.head,
+            found: found.head,
+        });
+    }
+
+    let west = std::cmp::min(expected.west, found.west);
+    let east = std::cmp::max(
+        expected.west + expected.tape.len() as isize,
+        found.west + found.tape.len() as isize,
+    );
+    for position in west..east {
+        if expected.get(position) != found.get(position) {
+            return Some(Mismatch::Bit {
+                position,
+                expected: expected.get(position),
+            });
+        }
+    }
+
+    None
+}
+
+// Runs a w-machine program on the reference interpreter and, one row per instruction, on the
+// tiles it compiles into. After every step the tile row is decoded back into a machine state and
+// checked against the interpreter.
+pub struct Lockstep<'a> {
+    code: wmach::Code,
+
+    machine: Machine<&'a [u8], std::io::Sink>,
+    tiles: tessera::Program<&'a [u8], std::io::Sink>,
+
+    // Tape position of the westernmost cell in the current tile row.
+    west: isize,
+}
+
+impl<'a> Lockstep<'a> {
+    pub fn new(program: &wmach::Program, input: &'a [u8]) -> Result<Self, LockstepError> {
+        let tiles = tessera::Tiles.compile(program)
+            .map_err(|e| LockstepError::Compile { source: Box::new(e) })?;
+
+        Lockstep::with_tiles(program, tiles, input)
+    }
+
+    // Check program against an arbitrary tile program instead of the one it compiles into.
+    pub fn with_tiles(
+        program: &wmach::Program,
+        tiles: tessera::Program,
+        input: &'a [u8],
+    ) -> Result<Self, LockstepError> {
+        let machine = Machine::with_io(program, input, std::io::sink());
+        let tiles = tiles.with_io(input, std::io::sink());
+
+        let mut lockstep = Lockstep {
+            code: program.instructions.clone(),
+
+            machine,
+            tiles,
+
+            west: 0,
+        };
+
+        // The initial row has nothing above it so we line it up with where the machine starts.
+        let row = decode(&lockstep.tiles.state()).map_err(|reason| lockstep.diverged(0, 0, reason))?;
+        lockstep.west = -(row.head as isize);
+        let found = lockstep.snapshot(row);
+        let expected = Snapshot::of_machine(&lockstep.machine);
+        if let Some(mismatch) = compare(&expected, &found) {
+            Err(lockstep.mismatched(0, 0, mismatch))?;
+        }
+
+        Ok(lockstep)
+    }
+
+    pub fn steps(&self) -> usize {
+        self.machine.steps()
+    }
+
+    pub fn step(&mut self) -> Result<Status, LockstepError> {
+        // Once the program runs off the end there are no tiles left to compare against.
+        if self.machine.status() == Status::Halted {
+            return Ok(Status::Halted);
+        }
+
+        let offset = self.machine.pc();
+        let step = self.machine.steps() + 1;
+
+        let status = self.machine.step()?;
+        match self.tiles.step() {
+            Ok(tessera::Status::Halted { .. }) if status != Status::Halted => {
+                Err(self.mismatched(step, offset, Mismatch::Halted))?
+            }
+            Ok(_) => (),
+            Err(e) => Err(self.mismatched(step, offset, Mismatch::Tiles { source: Box::new(e) }))?,
+        };
+
+        let row = decode(&self.tiles.state()).map_err(|reason| self.diverged(step, offset, reason))?;
+        self.west -= row.grown as isize;
+        let found = self.snapshot(row);
+        let expected = Snapshot::of_machine(&self.machine);
+        if let Some(mismatch) = compare(&expected, &found) {
+            Err(self.mismatched(step, offset, mismatch))?;
+        }
+
+        // Once the machine stops, the tiles should settle into repeating the last row.
+        if status == Status::Halted {
+            let offset = self.machine.pc();
+            match self.tiles.step() {
+                Ok(tessera::Status::Halted { .. }) => (),
+                Ok(tessera::Status::Running | tessera::Status::Breakpoint { .. }) => Err(self.mismatched(step, offset, Mismatch::NotHalted))?,
+                Err(e) => Err(self.mismatched(step, offset, Mismatch::Tiles { source: Box::new(e) }))?,
+            };
+        }
+
+        Ok(status)
+    }
+
+    // Step until the program halts or max_steps instructions have executed.
+    pub fn run(&mut self, max_steps: usize) -> Result<Status, LockstepError> {
+        for _ in 0..max_steps {
+            if self.step()? == Status::Halted {
+                break;
+            }
+        }
+
+        Ok(self.machine.status())
+    }
+
+    fn snapshot(&self, row: Row) -> Snapshot {
+        Snapshot {
+            pc: row.pc,
+            head: self.west + row.head as isize,
+
+            west: self.west,
+            tape: row.cells,
+        }
+    }
+
+    fn diverged(&self, step: usize, offset: wmach::InsnOffset, reason: String) -> LockstepError {
+        self.mismatched(step, offset, Mismatch::Undecodable { reason })
+    }
+
+    fn mismatched(&self, step: usize, offset: wmach::InsnOffset, mismatch: Mismatch) -> LockstepError {
+        LockstepError::Diverged {
+            step,
+            offset,
+            insn: self.code.get(offset).cloned(),
+            mismatch,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn agree(src: &str, input: &[u8], max_steps: usize) -> Status {
+        let program = wmach::Program::from_str(src).expect("should parse fine");
+        let mut lockstep = Lockstep::new(&program, input).expect("should compile fine");
+
+        lockstep.run(max_steps).expect("tiles should agree with the machine")
+    }
+
+    #[test]
+    fn write_and_seek() {
+        let status = agree("+ > + > - < < < < + > - > > > > > +", &[], 100);
+        assert_eq!(status, Status::Halted);
+    }
+
+    #[test]
+    fn scan_for_zero() {
+        let src = "
+            + > + > + < <
+            scan: jmp right, done
+            right: > jmp scan, scan
+            done:
+        ";
+        let status = agree(src, &[], 100);
+        assert_eq!(status, Status::Halted);
+    }
+
+    #[test]
+    fn echo_byte() {
+        let status = agree(",.>,.>,.>,.>,.>,.>,.>,.", b"Z", 100);
+        assert_eq!(status, Status::Halted);
+    }
+
+    #[test]
+    fn starts_from_data() {
+        // Scan east over the data to the first 0, echoing as we go.
+        let src = "
+            data bytes 0xff, 0x0f at 2
+            scan: . > jmp scan
+        ";
+        let program = wmach::Program::from_str(src).expect("should parse fine");
+        let mut lockstep = Lockstep::new(&program, &[]).expect("should compile fine");
+
+        let status = lockstep.run(100).expect("tiles should agree with the machine");
+        assert_eq!(status, Status::Halted);
+        assert_eq!(lockstep.steps(), 30);
+    }
+
+    #[test]
+    fn run_length_seeks() {
+        // Out past both ends of the tape, a row per seek however far it goes.
+        let src = "+ >5 + <9 + >*3 loop: - <2 jmp loop, end end:";
+        let program = wmach::Ast::from_str(src)
+            .and_then(|ast| ast.resolve_labels())
+            .expect("should parse fine");
+        let mut lockstep = Lockstep::new(&program, &[]).expect("should compile fine");
+
+        let status = lockstep.run(100).expect("tiles should agree with the machine");
+        assert_eq!(status, Status::Halted);
+        assert_eq!(lockstep.steps(), 9);
+    }
+
+    #[test]
+    fn halts_in_place() {
+        let status = agree("top: + > halt + jmp top", &[], 100);
+        assert_eq!(status, Status::Halted);
+
+        let program = wmach::Program::from_str("> halt").expect("should parse fine");
+        let mut tiles = tessera::Tiles.compile(&program).expect("should compile fine");
+        tiles.step().expect("should step fine");
+        let state = tiles.state();
+
+        for _ in 0..3 {
+            let status = tiles.step().expect("should step fine");
+            assert_eq!(status.to_string(), "halted at step 1");
+        }
+        assert_eq!(tiles.state(), state);
+    }
+
+    #[test]
+    fn breakpoints_are_transparent() {
+        let status = agree("+ ! > ! - !", &[], 100);
+        assert_eq!(status, Status::Halted);
+    }
+
+    #[test]
+    fn bounded_loop() {
+        let status = agree("spin: > + jmp spin", &[], 30);
+        assert_eq!(status, Status::Running);
+    }
+
+    // Nothing changes from one row to the next, but that's a busy wait rather than a halt.
+    #[test]
+    fn spinning_on_the_spot() {
+        let status = agree("+ spin: jmp spin, spin", &[], 30);
+        assert_eq!(status, Status::Running);
+
+        let status = agree("+ spin: jmp spin, out\nout: halt", &[], 30);
+        assert_eq!(status, Status::Running);
+
+        let status = agree("spin: jmp spin, out\nout: halt", &[], 30);
+        assert_eq!(status, Status::Halted);
+    }
+
+    #[test]
+    fn catches_divergence() {
+        let program = wmach::Program::from_str("> + <").expect("should parse fine");
+        let impostor = wmach::Program::from_str("> - <").expect("should parse fine");
+        let tiles = tessera::Tiles.compile(&impostor).expect("should compile fine");
+        let mut lockstep = Lockstep::with_tiles(&program, tiles, &[]).expect("should start fine");
+
+        match lockstep.run(10) {
+            Err(LockstepError::Diverged {
+                step: 2,
+                offset: 1,
+                insn: Some(wmach::Insn::Write(wmach::WriteOp::Set)),
+                mismatch: Mismatch::Bit { position: 1, expected: true },
+            }) => (),
+            x => panic!("Failed to catch the bad write: {:?}", x),
+        };
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::diagnostic::SourceMap;
+use crate::diagnostic::Spanned;
+use crate::macros;
+use crate::wmach::{Data, Insn, InsnOffset, LabelId, LabelMap, Program, Stmt, Target, WmachErr};
+
+// The passes that take the front end's statements (see wmach::Ast) down to instructions. Each one
+// only does one thing so that consumers can stop at whichever form suits them:
+//
+//   Ast --expand_repeats--> Ast without repeats
+//       --resolve_labels--> Program with run-length seeks
+//       --expand_seeks--> single-step Program
+//
+// resolve_labels does the unrolling itself when it's handed repeats, since instructions have no
+// way of saying them.
+
+// The most statements unrolling is allowed to make. Repeats nest, so this goes for all of them
+// together rather than for any one count.
+pub const MAX_UNROLLED: usize = 1 << 20;
+
+// Unroll every repeat block, innermost first. The first time round keeps the labels as they were
+// written, so jumping to one from outside lands in the first pass. Every other pass gets fresh
+// names for them, e.g. repeat'2'loop for loop in the second extra pass overall.
+pub fn expand_repeats(sources: &SourceMap, statements: &[Spanned<Stmt>]) -> Result<Vec<Spanned<Stmt>>, WmachErr> {
+    let mut used = HashSet::new();
+    macros::collect_labels(statements, &mut used);
+
+    let mut unroller = Unroller { sources, used, passes: 0 };
+    unroller.unroll(statements)
+}
+
+struct Unroller<'a> {
+    sources: &'a SourceMap,
+
+    // Every label name in the source plus every name handed out so far.
+    used: HashSet<LabelId>,
+    passes: usize,
+}
+
+impl<'a> Unroller<'a> {
+    fn unroll(&mut self, statements: &[Spanned<Stmt>]) -> Result<Vec<Spanned<Stmt>>, WmachErr> {
+        let mut unrolled = Vec::new();
+
+        for stmt in statements.iter() {
+            let (count, body) = match &stmt.node {
+                Stmt::Repeat(count, body) => (*count, self.unroll(body)?),
+                _ => {
+                    unrolled.push(stmt.clone());
+                    continue;
+                }
+            };
+
+            // Empty bodies count as one statement, going round that many times takes just as long.
+            let size = count.checked_mul(std::cmp::max(body.len(), 1));
+            if size.is_none_or(|size| size > MAX_UNROLLED.saturating_sub(unrolled.len())) {
+                Err(WmachErr::TooManyRepeats {
+                    count,
+                    snippet: self.sources.snippet(stmt.span),
+                })?;
+            }
+
+            let mut locals = HashSet::new();
+            macros::collect_labels(&body, &mut locals);
+
+            for pass in 0..count {
+                if pass == 0 {
+                    unrolled.extend(body.iter().cloned());
+                    continue;
+                }
+
+                self.passes += 1;
+                let renames: HashMap<LabelId, LabelId> = locals
+                    .iter()
+                    .map(|label| (label.clone(), self.fresh(label)))
+                    .collect();
+                unrolled.extend(body.iter().map(|stmt| rename(stmt, &renames)));
+            }
+        }
+
+        Ok(unrolled)
+    }
+
+    fn fresh(&mut self, label: &str) -> LabelId {
+        let mut suffix = String::new();
+        loop {
+            let candidate = format!("repeat'{}{}'{}", self.passes, suffix, label);
+            if self.used.insert(candidate.clone()) {
+                return candidate;
+            }
+            suffix.push('\'');
+        }
+    }
+}
+
+// Macros have been expanded already, so labels and jumps are all there is to rename, along with
+// whatever is in repeats that haven't been unrolled yet.
+pub fn rename(stmt: &Spanned<Stmt>, renames: &HashMap<LabelId, LabelId>) -> Spanned<Stmt> {
+    let renamed = |label: &LabelId| renames.get(label).unwrap_or(label).clone();
+    let target = |target: &Spanned<Target>| match &target.node {
+        Target::Name(label) => Spanned::new(Target::Name(renamed(label)), target.span),
+        Target::NextAddress => target.clone(),
+    };
+
+    let node = match &stmt.node {
+        Stmt::Label(label) => Stmt::Label(renamed(label)),
+        Stmt::Jmp(branch_t, branch_f) => Stmt::Jmp(target(branch_t), target(branch_f)),
+        Stmt::Repeat(count, body) => Stmt::Repeat(*count, body.iter().map(|stmt| rename(stmt, renames)).collect()),
+        node => node.clone(),
+    };
+
+    Spanned::new(node, stmt.span)
+}
+
+// Give every label an instruction offset and point the jumps at them. Seeks keep whatever run
+// length they were written with. A data statement, if there is one, sets up the tape.
+pub fn resolve_labels(sources: &SourceMap, statements: &[Spanned<Stmt>]) -> Result<Program, WmachErr> {
+    let unrolled;
+    let statements = if statements.iter().any(|stmt| matches!(stmt.node, Stmt::Repeat(..))) {
+        unrolled = expand_repeats(sources, statements)?;
+        &unrolled[..]
+    } else {
+        statements
+    };
+
+    // make jmp table
+    let mut jmp_table: LabelMap = HashMap::new();
+    let mut data: Option<Data> = None;
+    let mut offset: InsnOffset = 0;
+    for stmt in statements.iter() {
+        if let Stmt::Label(label_id) = &stmt.node {
+            if jmp_table.contains_key(label_id) {
+                Err(WmachErr::DuplicateLabel {
+                    label: label_id.to_owned(),
+                    snippet: sources.snippet(stmt.span),
+                })?
+            }
+
+            jmp_table.insert(label_id.to_owned(), offset);
+        } else if let Stmt::Data(tape) = &stmt.node {
+            if data.is_some() {
+                Err(WmachErr::DuplicateData {
+                    snippet: sources.snippet(stmt.span),
+                })?
+            }
+
+            data = Some(tape.clone());
+        } else {
+            offset += 1;
+        }
+    }
+
+    // XXX Might want to bind the previous offset and the subsequent offset more tightly.
+    // Maybe we can use the type-system somehow.
+
+    // make instructions
+    let mut insns: Vec<Insn> = Vec::new();
+    for (offset, stmt) in statements
+        .iter()
+        .filter(|stmt| !matches!(stmt.node, Stmt::Label(_) | Stmt::Data(_)))
+        .enumerate()
+    {
+        let insn = match &stmt.node {
+            Stmt::Write(value) => Insn::Write(*value),
+            Stmt::Seek(direction, count) => Insn::Seek(*direction, *count),
+            Stmt::Io(rw) => Insn::Io(*rw),
+            Stmt::Jmp(branch_t, branch_f) => {
+                let target_address = |branch: &Spanned<Target>| {
+                    let address = match &branch.node {
+                        Target::NextAddress => Some(offset + 1),
+                        Target::Name(label_id) => jmp_table.get(label_id).cloned(),
+                    };
+
+                    // missing label error
+                    address.ok_or_else(|| WmachErr::UnknownTarget {
+                        offset,
+                        target: branch.node.to_owned(),
+                        snippet: sources.snippet(branch.span),
+                    })
+                };
+
+                let t = target_address(branch_t)?;
+                let f = target_address(branch_f)?;
+
+                Insn::Jmp(t, f)
+            }
+            Stmt::Debug => Insn::Debug,
+            Stmt::Halt => Insn::Halt,
+
+            _ => {
+                panic!("Shouldn't reach this");
+            }
+        };
+
+        insns.push(insn);
+    }
+
+    Ok(Program {
+        instructions: insns,
+        labels: jmp_table,
+        data: data.unwrap_or_default(),
+    })
+}
+
+// Split every seek into seeks of a single cell. Jumps and labels follow the instructions they
+// point at.
+pub fn expand_seeks(program: &Program) -> Program {
+    // Where each instruction ends up, plus where the end of the program ends up.
+    let mut addresses = Vec::with_capacity(program.instructions.len() + 1);
+    let mut address = 0;
+    for insn in program.instructions.iter() {
+        addresses.push(address);
+        address += match insn {
+            Insn::Seek(_, count) => *count,
+            _ => 1,
+        };
+    }
+    addresses.push(address);
+
+    let mut instructions = Vec::with_capacity(address);
+    for insn in program.instructions.iter() {
+        match insn {
+            Insn::Seek(direction, count) => {
+                instructions.extend((0..*count).map(|_| Insn::Seek(*direction, 1)))
+            }
+            Insn::Jmp(branch_t, branch_f) => {
+                instructions.push(Insn::Jmp(addresses[*branch_t], addresses[*branch_f]))
+            }
+            _ => instructions.push(insn.clone()),
+        }
+    }
+
+    let labels = program
+        .labels
+        .iter()
+        .map(|(label, offset)| (label.clone(), addresses[*offset]))
+        .collect();
+
+    Program {
+        instructions,
+        labels,
+        data: program.data.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use crate::machine::Machine;
+    use crate::wmach::{Ast, SeekOp};
+
+    #[test]
+    fn labels_keep_run_lengths() {
+        let ast = Ast::from_str(">>> top: <<- jmp top, end end:").expect("should parse fine");
+        let program = ast.resolve_labels().expect("should resolve fine");
+
+        assert_eq!(
+            format!("{:?}", program.instructions),
+            "[Seek(Right, 3), Seek(Left, 2), Write(Unset), Jmp(1, 4)]"
+        );
+        assert_eq!(program.labels.get("top"), Some(&1));
+        assert_eq!(program.labels.get("end"), Some(&4));
+    }
+
+    #[test]
+    fn expansion_moves_targets() {
+        let ast = Ast::from_str(">>> top: <<- jmp top, end end:").expect("should parse fine");
+        let program = ast.resolve_labels().expect("should resolve fine").expand_seeks();
+
+        assert_eq!(program.instructions.len(), 7);
+        assert!(program.instructions[..5]
+            .iter()
+            .all(|insn| matches!(insn, Insn::Seek(_, 1))));
+        match program.instructions[6] {
+            Insn::Jmp(3, 7) => (),
+            ref insn => panic!("jmp went to the wrong place: {:?}", insn),
+        };
+        assert_eq!(program.labels.get("top"), Some(&3));
+        assert_eq!(program.labels.get("end"), Some(&7));
+    }
+
+    #[test]
+    fn repeats_unroll() {
+        let ast = Ast::from_str("repeat 2 { repeat 3 { > } + } <").expect("should parse fine");
+        let program = ast.resolve_labels().expect("should resolve fine").expand_seeks();
+        let mut machine = Machine::with_io(&program, &[][..], std::io::sink());
+        machine.run(100).expect("should run fine");
+
+        assert_eq!(machine.tape().head(), 5);
+        assert_eq!(machine.tape().bits(), vec![false, false, false, true, false, false, true]);
+
+        let ast = Ast::from_str("repeat 0 { + } -").expect("should parse fine");
+        assert_eq!(expand_repeats(&ast.sources, &ast.statements).expect("should unroll fine").len(), 1);
+    }
+
+    #[test]
+    fn repeats_are_bounded() {
+        for src in ["+\nrepeat 2000000 { > }", "+\nrepeat 2000 { repeat 1000 { > } }", "+\nrepeat 18446744073709551615 { }"] {
+            match Ast::from_str(src).expect("should parse fine").resolve_labels() {
+                Err(WmachErr::TooManyRepeats { snippet, .. }) => assert_eq!(snippet.location().line, 2),
+                result => panic!("{} should be too many: {:?}", src, result.map(|_| ())),
+            }
+        }
+
+        let ast = Ast::from_str("repeat 1024 { repeat 1024 { > } }").expect("should parse fine");
+        assert_eq!(ast.resolve_labels().expect("should resolve fine").instructions.len(), MAX_UNROLLED);
+    }
+
+    #[test]
+    fn repeats_rename_labels() {
+        // Skip over two runs of ones, one pass each.
+        let src = "
+            data bits 11011
+            repeat 2 {
+                scan: jmp right, done
+                right: > jmp scan, scan
+                done: >
+            }
+            jmp scan, scan'
+            scan': +
+        ";
+        let program = Program::from_str(src).expect("should parse fine");
+        assert_eq!(program.labels.get("scan"), Some(&0));
+        assert_eq!(program.labels.get("repeat'1'scan"), Some(&4));
+
+        let mut machine = Machine::with_io(&program, &[][..], std::io::sink());
+        machine.run(100).expect("should run fine");
+        assert_eq!(machine.tape().head(), 6);
+        assert!(machine.tape().get(6));
+    }
+
+    #[test]
+    fn expansion_is_idempotent() {
+        let program = Program::from_str("< top: > > jmp top").expect("should parse fine");
+        let again = program.expand_seeks();
+
+        assert_eq!(format!("{:?}", again.instructions), format!("{:?}", program.instructions));
+        assert_eq!(again.labels, program.labels);
+        assert!(matches!(program.instructions[0], Insn::Seek(SeekOp::Left, 1)));
+    }
+}
@@ -0,0 +1,896 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde_json::{json, Value};
+use thiserror::Error;
+
+use crate::binary::SourceInfo;
+use crate::diagnostic::{Snippet, Span, Spanned};
+use crate::lint;
+use crate::tessera;
+use crate::wmach::{Argument, Ast, Program, Stmt, Target, WmachErr};
+
+// A language server for wmach files, spoken over stdin and stdout by bones-lsp. Every open document
+// is loaded again whenever it changes, and everything else is answered from what that turned up.
+//
+// Messages are JSON-RPC with a Content-Length header in front, and positions are lines from 0 and
+// UTF-16 code units along the line, as the protocol insists.
+
+#[derive(Debug, Error)]
+pub enum LspErr {
+    #[error("IO: {source}")]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+
+    #[error("Malformed message: {source}")]
+    Json {
+        #[from]
+        source: serde_json::Error,
+    },
+
+    #[error("Malformed header ``{header}''")]
+    BadHeader { header: String },
+
+    #[error("Message without a Content-Length header")]
+    MissingLength,
+
+    #[error("Message of {length} bytes is more than we'll read")]
+    TooLong { length: usize },
+}
+
+// Nothing an editor sends for a wmach file comes close, so anything bigger is somebody else's
+// stream.
+const MAX_LENGTH: usize = 1 << 26;
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+const SEVERITY_ERROR: u64 = 1;
+const SEVERITY_WARNING: u64 = 2;
+
+const SYMBOL_FUNCTION: u64 = 12;
+const SYMBOL_CONSTANT: u64 = 14;
+
+// Answer whatever comes in on input until the client says exit or hangs up.
+pub fn serve<R: BufRead, W: Write>(mut input: R, output: W) -> Result<(), LspErr> {
+    let mut server = Server {
+        output,
+        documents: HashMap::new(),
+    };
+
+    loop {
+        let message = match read_message(&mut input) {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            // The whole body has been read, so we're still in step with the client and can tell
+            // it what went wrong.
+            Err(LspErr::Json { source }) => {
+                let error = json!({"code": PARSE_ERROR, "message": format!("Malformed message: {}", source)});
+                write_message(&mut server.output, &json!({"jsonrpc": "2.0", "id": null, "error": error}))?;
+                continue;
+            }
+            Err(e) => Err(e)?,
+        };
+
+        if message["method"] == "exit" {
+            break;
+        }
+
+        server.handle(&message)?;
+    }
+
+    Ok(())
+}
+
+fn read_message<R: BufRead>(input: &mut R) -> Result<Option<Value>, LspErr> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+
+        let bad_header = || LspErr::BadHeader {
+            header: line.to_string(),
+        };
+        let (name, value) = line.split_once(':').ok_or_else(bad_header)?;
+        if name.eq_ignore_ascii_case("Content-Length") {
+            length = Some(value.trim().parse::<usize>().map_err(|_| bad_header())?);
+        }
+    }
+
+    let length = length.ok_or(LspErr::MissingLength)?;
+    if length > MAX_LENGTH {
+        Err(LspErr::TooLong { length })?;
+    }
+
+    let mut body = vec![0; length];
+    input.read_exact(&mut body)?;
+
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Value) -> Result<(), LspErr> {
+    let body = serde_json::to_string(message)?;
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()?;
+
+    Ok(())
+}
+
+struct Server<W: Write> {
+    output: W,
+    documents: HashMap<String, Document>,
+}
+
+impl<W: Write> Server<W> {
+    fn handle(&mut self, message: &Value) -> Result<(), LspErr> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+
+        // Anything without an id is a notification, which never gets a reply. Replies to requests
+        // of ours would land here too, but we never make any.
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notification(method, params),
+        };
+
+        let result = match method {
+            "initialize" => Some(capabilities()),
+            "shutdown" => Some(Value::Null),
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/documentSymbol" => self.document_symbols(params),
+            _ => {
+                let error = json!({
+                    "code": METHOD_NOT_FOUND,
+                    "message": format!("Unknown method {}", method),
+                });
+                return write_message(&mut self.output, &json!({"jsonrpc": "2.0", "id": id, "error": error}));
+            }
+        };
+
+        let response = match result {
+            Some(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            None => {
+                let error = json!({"code": INVALID_PARAMS, "message": "Missing or malformed parameters"});
+                json!({"jsonrpc": "2.0", "id": id, "error": error})
+            }
+        };
+
+        write_message(&mut self.output, &response)
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> Result<(), LspErr> {
+        let uri = match params["textDocument"]["uri"].as_str() {
+            Some(uri) => uri.to_string(),
+            None => return Ok(()),
+        };
+
+        // We only ask for full text on every change, so the last change has all of it.
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change["text"].as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return self.publish(&uri, Vec::new());
+            }
+            _ => None,
+        };
+
+        match text {
+            Some(text) => {
+                let document = Document::new(&uri, text.to_string());
+                let diagnostics = document.diagnostics.clone();
+                self.documents.insert(uri.clone(), document);
+
+                self.publish(&uri, diagnostics)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Value>) -> Result<(), LspErr> {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {"uri": uri, "diagnostics": diagnostics},
+        });
+
+        write_message(&mut self.output, &notification)
+    }
+
+    // The document a request is about and the byte offset of the position it gives. A document we
+    // were never told about is not the client's mistake, so that gets an empty answer instead.
+    fn at<'a>(&'a self, params: &'a Value) -> Option<Option<(&'a str, &'a Document, usize)>> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let position = &params["position"];
+        position["line"].as_u64()?;
+        position["character"].as_u64()?;
+
+        Some(
+            self.documents
+                .get(uri)
+                .map(|document| (uri, document, offset(&document.text, position))),
+        )
+    }
+
+    fn definition(&self, params: &Value) -> Option<Value> {
+        let (uri, document, offset) = match self.at(params)? {
+            Some(found) => found,
+            None => return Some(Value::Null),
+        };
+        let symbol = match document.symbol_at(offset) {
+            Some(symbol) => symbol,
+            None => return Some(Value::Null),
+        };
+
+        let locations: Vec<Value> = document
+            .symbols
+            .iter()
+            .filter(|other| other.definition && other.refers_to(symbol))
+            .map(|other| location(uri, &document.text, other.start, other.end))
+            .collect();
+        if !locations.is_empty() || symbol.kind != Kind::Label {
+            return Some(Value::Array(locations));
+        }
+
+        // Not in this file, so maybe it came from an include or an import.
+        Some(Value::Array(document.elsewhere(&symbol.name)))
+    }
+
+    fn references(&self, params: &Value) -> Option<Value> {
+        let (uri, document, offset) = match self.at(params)? {
+            Some(found) => found,
+            None => return Some(Value::Null),
+        };
+        let symbol = match document.symbol_at(offset) {
+            Some(symbol) => symbol,
+            None => return Some(Value::Null),
+        };
+        let declarations = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+
+        let locations: Vec<Value> = document
+            .symbols
+            .iter()
+            .filter(|other| other.refers_to(symbol) && (declarations || !other.definition))
+            .map(|other| location(uri, &document.text, other.start, other.end))
+            .collect();
+
+        Some(Value::Array(locations))
+    }
+
+    fn hover(&self, params: &Value) -> Option<Value> {
+        let (_, document, offset) = match self.at(params)? {
+            Some(found) => found,
+            None => return Some(Value::Null),
+        };
+        let loaded = match &document.loaded {
+            Some(loaded) => loaded,
+            None => return Some(Value::Null),
+        };
+
+        let (contents, start, end) = match document.symbol_at(offset) {
+            Some(symbol) if symbol.kind == Kind::Label => {
+                let address = match loaded.program.labels.get(&symbol.name) {
+                    Some(address) => *address,
+                    None => return Some(Value::Null),
+                };
+
+                let contents = match loaded.program.instructions.get(address) {
+                    Some(insn) => format!(
+                        "`{}` is instruction {}: `{}`, {} tiles",
+                        symbol.name,
+                        address,
+                        insn,
+                        tessera::tile_count(insn)
+                    ),
+                    None => format!("`{}` is instruction {}, the end of the program", symbol.name, address),
+                };
+                (contents, symbol.start, symbol.end)
+            }
+            Some(_) => return Some(Value::Null),
+            None => {
+                // Everything the statement under the cursor became, which can be several
+                // instructions for repeats and macros.
+                let insns: Vec<usize> = (0..loaded.program.instructions.len())
+                    .filter(|&i| {
+                        let span = loaded.info.spans[i];
+                        let text = &document.text;
+                        span.file() == 0
+                            && span.start(text).is_some_and(|start| start <= offset)
+                            && span.end(text).is_some_and(|end| offset < end)
+                    })
+                    .collect();
+                let first = match insns.first() {
+                    Some(first) => *first,
+                    None => return Some(Value::Null),
+                };
+
+                let tiles: usize = insns
+                    .iter()
+                    .map(|&i| tessera::tile_count(&loaded.program.instructions[i]))
+                    .sum();
+                let contents = if insns.len() == 1 {
+                    format!("instruction {}: {} tiles", first, tiles)
+                } else {
+                    format!("{} instructions starting at {}: {} tiles", insns.len(), first, tiles)
+                };
+
+                let span = loaded.info.spans[first];
+                (contents, span.start(&document.text)?, span.end(&document.text)?)
+            }
+        };
+
+        Some(json!({
+            "contents": {"kind": "markdown", "value": contents},
+            "range": range(&document.text, start, end),
+        }))
+    }
+
+    fn document_symbols(&self, params: &Value) -> Option<Value> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let document = match self.documents.get(uri) {
+            Some(document) => document,
+            None => return Some(Value::Null),
+        };
+
+        // Labels inside a macro go under it, since they only mean anything in there.
+        let symbol = |symbol: &Symbol, children: Vec<Value>| {
+            let range = range(&document.text, symbol.start, symbol.end);
+            let (kind, detail) = match symbol.kind {
+                Kind::Macro => (SYMBOL_FUNCTION, "macro".to_string()),
+                Kind::Label => {
+                    let address = document
+                        .loaded
+                        .as_ref()
+                        .filter(|_| symbol.container.is_none())
+                        .and_then(|loaded| loaded.program.labels.get(&symbol.name));
+                    match address {
+                        Some(address) => (SYMBOL_CONSTANT, format!("instruction {}", address)),
+                        None => (SYMBOL_CONSTANT, "label".to_string()),
+                    }
+                }
+            };
+
+            json!({
+                "name": symbol.name,
+                "detail": detail,
+                "kind": kind,
+                "range": range,
+                "selectionRange": range,
+                "children": children,
+            })
+        };
+
+        let definitions: Vec<&Symbol> = document.symbols.iter().filter(|symbol| symbol.definition).collect();
+        let symbols: Vec<Value> = definitions
+            .iter()
+            .filter(|outer| outer.container.is_none())
+            .map(|outer| {
+                let children = definitions
+                    .iter()
+                    .filter(|inner| outer.kind == Kind::Macro && inner.container.as_ref() == Some(&outer.name))
+                    .map(|inner| symbol(inner, Vec::new()))
+                    .collect();
+                symbol(outer, children)
+            })
+            .collect();
+
+        Some(Value::Array(symbols))
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            "textDocumentSync": 1,
+            "definitionProvider": true,
+            "referencesProvider": true,
+            "hoverProvider": true,
+            "documentSymbolProvider": true,
+        },
+        "serverInfo": {"name": "bones-lsp"},
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Label,
+    Macro,
+}
+
+// A name in the document as written, either where it is defined or somewhere it is used.
+#[derive(Debug)]
+struct Symbol {
+    kind: Kind,
+    name: String,
+    definition: bool,
+    // The macro that defines it, for labels a macro keeps to itself.
+    container: Option<String>,
+
+    // Byte offsets of the name itself.
+    start: usize,
+    end: usize,
+}
+
+impl Symbol {
+    fn refers_to(&self, other: &Symbol) -> bool {
+        self.kind == other.kind && self.name == other.name && self.container == other.container
+    }
+}
+
+struct Document {
+    text: String,
+    // None when the document isn't a file, which leaves includes relative to wherever we were
+    // started from.
+    path: Option<PathBuf>,
+
+    // Only needs the document to parse, so definitions and references carry on working while the
+    // program as a whole has errors.
+    symbols: Vec<Symbol>,
+    // Only when the whole program loads.
+    loaded: Option<Loaded>,
+    diagnostics: Vec<Value>,
+}
+
+struct Loaded {
+    ast: Ast,
+    // Run-length seeks and all, since that's what ends up as tiles.
+    program: Program,
+    info: SourceInfo,
+}
+
+impl Document {
+    fn new(uri: &str, text: String) -> Self {
+        let path = url::Url::parse(uri).ok().and_then(|url| url.to_file_path().ok());
+
+        let mut symbols = Vec::new();
+        if let Ok(statements) = Program::parse_statements(&text) {
+            collect_symbols(&statements, &text, None, &mut symbols);
+            scope_symbols(&mut symbols);
+        }
+
+        let mut document = Document {
+            text,
+            path,
+            symbols,
+            loaded: None,
+            diagnostics: Vec::new(),
+        };
+
+        match document.load() {
+            Ok(loaded) => {
+                if let Ok(warnings) = lint::lint(&loaded.ast) {
+                    for warning in warnings {
+                        let diagnostic = document.diagnostic(&warning.message, Some(&warning.snippet), SEVERITY_WARNING);
+                        document.diagnostics.push(diagnostic);
+                    }
+                }
+                document.loaded = Some(loaded);
+            }
+            Err(err) => {
+                let diagnostic = document.diagnostic(&err.to_string(), err.snippet(), SEVERITY_ERROR);
+                document.diagnostics.push(diagnostic);
+            }
+        }
+
+        document
+    }
+
+    fn load(&self) -> Result<Loaded, WmachErr> {
+        let ast = match &self.path {
+            Some(path) => Ast::from_buffer(path, &self.text)?,
+            None => Ast::from_str(&self.text)?,
+        };
+        let program = ast.resolve_labels()?;
+        let info = SourceInfo::new(&ast)?;
+
+        Ok(Loaded { ast, program, info })
+    }
+
+    fn origin(&self) -> Option<String> {
+        self.path.as_ref().map(|path| path.to_string_lossy().to_string())
+    }
+
+    // Messages carry their own snippet, which the editor has no use for when it can underline the
+    // text instead. Anything from another file goes at the top, snippet and all.
+    fn diagnostic(&self, message: &str, snippet: Option<&Snippet>, severity: u64) -> Value {
+        let (message, start, end) = match snippet {
+            Some(snippet) if snippet.origin().map(str::to_string) == self.origin() => {
+                let (start, end) = snippet_range(&self.text, snippet);
+                (message.lines().next().unwrap_or(""), start, end)
+            }
+            Some(_) | None => (message, 0, 0),
+        };
+
+        json!({
+            "range": range(&self.text, start, end),
+            "severity": severity,
+            "source": "bones",
+            "message": message,
+        })
+    }
+
+    fn symbol_at(&self, offset: usize) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.start <= offset && offset <= symbol.end)
+    }
+
+    // Where the label was defined if it was in another file.
+    fn elsewhere(&self, name: &str) -> Vec<Value> {
+        let loaded = match &self.loaded {
+            Some(loaded) => loaded,
+            None => return Vec::new(),
+        };
+
+        let mut spans = Vec::new();
+        find_labels(&loaded.ast.statements, name, &mut spans);
+
+        spans
+            .into_iter()
+            .filter(|span| span.file() != 0)
+            .filter_map(|span| {
+                let file = loaded.ast.sources.file(span.file());
+                let uri = url::Url::from_file_path(file.origin.as_ref()?).ok()?;
+                let start = span.start(&file.text)?;
+
+                Some(location(uri.as_str(), &file.text, start, start + name.len()))
+            })
+            .collect()
+    }
+}
+
+fn collect_symbols(statements: &[Spanned<Stmt>], text: &str, container: Option<&str>, symbols: &mut Vec<Symbol>) {
+    // Spans always come from `text` here, but don't fall over if they didn't.
+    let mut push = |kind, name: &str, definition, start: Option<usize>| {
+        if let Some(start) = start {
+            symbols.push(Symbol {
+                kind,
+                name: name.to_string(),
+                definition,
+                container: container.map(str::to_string),
+                start,
+                end: start + name.len(),
+            })
+        }
+    };
+
+    let mut nested = Vec::new();
+    for stmt in statements.iter() {
+        match &stmt.node {
+            Stmt::Label(label) => push(Kind::Label, label, true, stmt.span.start(text)),
+            Stmt::Jmp(branch_t, branch_f) => {
+                for branch in [branch_t, branch_f] {
+                    if let Target::Name(label) = &branch.node {
+                        push(Kind::Label, label, false, branch.span.start(text));
+                    }
+                }
+            }
+            Stmt::Repeat(_, body) => nested.push((body, container)),
+            Stmt::Macro(definition) => {
+                push(Kind::Macro, &definition.name.node, true, definition.name.span.start(text));
+                nested.push((&definition.body, Some(definition.name.node.as_str())));
+            }
+            Stmt::Invoke(name, args) => {
+                push(Kind::Macro, &name.node, false, name.span.start(text));
+                for arg in args.iter() {
+                    match &arg.node {
+                        Argument::Label(label) => push(Kind::Label, label, false, arg.span.start(text)),
+                        Argument::Block(block) => nested.push((block, container)),
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    for (block, container) in nested {
+        collect_symbols(block, text, container, symbols);
+    }
+}
+
+// A macro only keeps the labels it defines to itself. Any other name in its body means the same
+// as it does outside, and so does every macro name.
+fn scope_symbols(symbols: &mut [Symbol]) {
+    let local: HashSet<(String, String)> = symbols
+        .iter()
+        .filter(|symbol| symbol.definition && symbol.kind == Kind::Label)
+        .filter_map(|symbol| Some((symbol.container.clone()?, symbol.name.clone())))
+        .collect();
+
+    for symbol in symbols.iter_mut() {
+        let keep = match &symbol.container {
+            Some(container) => {
+                symbol.kind == Kind::Label && local.contains(&(container.clone(), symbol.name.clone()))
+            }
+            None => false,
+        };
+
+        if !keep {
+            symbol.container = None;
+        }
+    }
+}
+
+fn find_labels(statements: &[Spanned<Stmt>], name: &str, spans: &mut Vec<Span>) {
+    for stmt in statements.iter() {
+        match &stmt.node {
+            Stmt::Label(label) if label == name => spans.push(stmt.span),
+            Stmt::Repeat(_, body) => find_labels(body, name, spans),
+            _ => (),
+        }
+    }
+}
+
+fn position(text: &str, offset: usize) -> Value {
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+
+    json!({
+        "line": text[..offset].matches('\n').count(),
+        "character": text[line_start..offset].encode_utf16().count(),
+    })
+}
+
+// Positions past the end of a line or the document are taken to mean the end of it.
+fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let character = position["character"].as_u64().unwrap_or(0) as usize;
+
+    let line_start = match line {
+        0 => 0,
+        _ => match text.match_indices('\n').nth(line - 1) {
+            Some((i, _)) => i + 1,
+            None => return text.len(),
+        },
+    };
+
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+
+    text.len()
+}
+
+fn range(text: &str, start: usize, end: usize) -> Value {
+    json!({"start": position(text, start), "end": position(text, end)})
+}
+
+fn location(uri: &str, text: &str, start: usize, end: usize) -> Value {
+    json!({"uri": uri, "range": range(text, start, end)})
+}
+
+// Snippets only know lines and columns counted in characters.
+fn snippet_range(text: &str, snippet: &Snippet) -> (usize, usize) {
+    let location = snippet.location();
+    let line_start: usize = text.split_inclusive('\n').take(location.line - 1).map(str::len).sum();
+    let line = text[line_start..].split('\n').next().unwrap_or("");
+
+    let start = line_start
+        + line
+            .char_indices()
+            .nth(location.column - 1)
+            .map_or(line.len(), |(i, _)| i);
+    let end = start + text[start..].chars().take(snippet.width()).map(char::len_utf8).sum::<usize>();
+
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    // Play the part of an editor: send every message in turn and collect whatever comes back.
+    fn session(messages: &[Value]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for message in messages.iter() {
+            write_message(&mut input, message).expect("writing to memory");
+        }
+
+        let mut output = Vec::new();
+        serve(Cursor::new(input), &mut output).expect("should serve fine");
+
+        let mut output = Cursor::new(output);
+        let mut replies = Vec::new();
+        while let Some(reply) = read_message(&mut output).expect("replies should be well formed") {
+            replies.push(reply);
+        }
+        replies
+    }
+
+    const URI: &str = "untitled:main.wm";
+
+    fn open(text: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": URI, "languageId": "wmach", "version": 1, "text": text}},
+        })
+    }
+
+    fn request(id: u64, method: &str, line: u64, character: u64) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": {
+                "textDocument": {"uri": URI},
+                "position": {"line": line, "character": character},
+                "context": {"includeDeclaration": true},
+            },
+        })
+    }
+
+    fn reply(replies: &[Value], id: u64) -> &Value {
+        let reply = replies.iter().find(|reply| reply["id"] == id).expect("every request gets a reply");
+        &reply["result"]
+    }
+
+    #[test]
+    fn errors_are_published() {
+        let change = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": {"uri": URI, "version": 2},
+                "contentChanges": [{"text": "+\njmp nowhere, end end:"}],
+            },
+        });
+        let fix = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": {"uri": URI, "version": 3},
+                "contentChanges": [{"text": "+\nnowhere: jmp nowhere, end end:"}],
+            },
+        });
+        let replies = session(&[open("+ > ~"), change, fix]);
+        assert_eq!(replies.len(), 3);
+
+        let syntax = &replies[0]["params"]["diagnostics"];
+        assert_eq!(syntax[0]["severity"], SEVERITY_ERROR);
+        assert_eq!(syntax[0]["range"]["start"], json!({"line": 0, "character": 4}));
+        assert_eq!(syntax[0]["message"], "Unable to parse ``~''");
+
+        let unknown = &replies[1]["params"]["diagnostics"];
+        assert_eq!(unknown.as_array().map(Vec::len), Some(1));
+        assert_eq!(
+            unknown[0]["range"],
+            json!({"start": {"line": 1, "character": 4}, "end": {"line": 1, "character": 11}})
+        );
+
+        // Fixed, but now it gets linted.
+        let lint = &replies[2]["params"]["diagnostics"];
+        assert_eq!(lint.as_array().map(Vec::len), Some(1));
+        assert_eq!(lint[0]["severity"], SEVERITY_WARNING);
+        assert_eq!(lint[0]["range"]["start"], json!({"line": 1, "character": 9}));
+    }
+
+    #[test]
+    fn definitions_and_references() {
+        let text = "top: +\n> jmp top, end\nend:\nmacro m(x) { top: jmp top }\nm(top)";
+        let replies = session(&[
+            open(text),
+            request(1, "textDocument/definition", 1, 7),
+            request(2, "textDocument/references", 0, 1),
+            request(3, "textDocument/definition", 3, 23),
+        ]);
+
+        assert_eq!(
+            reply(&replies, 1),
+            &json!([{"uri": URI, "range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 3}}}])
+        );
+
+        // The label inside the macro is a different one.
+        let references = reply(&replies, 2).as_array().expect("should find references");
+        let lines: Vec<&Value> = references.iter().map(|location| &location["range"]["start"]["line"]).collect();
+        assert_eq!(lines, vec![0, 1, 4]);
+
+        assert_eq!(reply(&replies, 3)[0]["range"]["start"], json!({"line": 3, "character": 13}));
+    }
+
+    #[test]
+    fn hover_shows_offsets_and_tiles() {
+        let text = "top: >>> jmp top, end\nend: + repeat 2 { - }";
+        let replies = session(&[
+            open(text),
+            request(1, "textDocument/hover", 0, 19),
+            request(2, "textDocument/hover", 0, 6),
+            request(3, "textDocument/hover", 1, 5),
+            request(4, "textDocument/hover", 0, 4),
+            request(5, "textDocument/hover", 1, 18),
+        ]);
+
+        assert_eq!(reply(&replies, 1)["contents"]["value"], "`end` is instruction 2: `+`, 2 tiles");
+        assert_eq!(reply(&replies, 2)["contents"]["value"], "instruction 0: 11 tiles");
+        assert_eq!(reply(&replies, 3)["contents"]["value"], "instruction 2: 2 tiles");
+        assert_eq!(reply(&replies, 4), &Value::Null);
+        assert_eq!(reply(&replies, 5)["contents"]["value"], "2 instructions starting at 3: 4 tiles");
+    }
+
+    #[test]
+    fn document_symbols() {
+        let text = "start: macro m() { inner: + } m() done:";
+        let replies = session(&[open(text), request(1, "textDocument/documentSymbol", 0, 0)]);
+
+        let symbols = reply(&replies, 1);
+        let names: Vec<&Value> = symbols.as_array().expect("should be a list").iter().map(|s| &s["name"]).collect();
+        assert_eq!(names, vec!["start", "m", "done"]);
+        assert_eq!(symbols[0]["detail"], "instruction 0");
+        assert_eq!(symbols[1]["kind"], SYMBOL_FUNCTION);
+        assert_eq!(symbols[1]["children"][0]["name"], "inner");
+        assert_eq!(symbols[2]["detail"], "instruction 1");
+    }
+
+    #[test]
+    fn protocol() {
+        let initialize = json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}});
+        let unknown = json!({"jsonrpc": "2.0", "id": 2, "method": "workspace/frobnicate", "params": {}});
+        let shutdown = json!({"jsonrpc": "2.0", "id": 3, "method": "shutdown"});
+        let exit = json!({"jsonrpc": "2.0", "method": "exit"});
+        let late = json!({"jsonrpc": "2.0", "id": 4, "method": "shutdown"});
+
+        let replies = session(&[initialize, unknown, shutdown, exit, late]);
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+        assert_eq!(replies[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(replies[2]["result"], Value::Null);
+
+        let mut input = Cursor::new(b"Content-Type: nonsense\r\n\r\n{}".to_vec());
+        assert!(matches!(read_message(&mut input), Err(LspErr::MissingLength)));
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let shutdown = serde_json::to_string(&json!({"jsonrpc": "2.0", "id": 1, "method": "shutdown"})).expect("json");
+        let input = format!("Content-Length: 5\r\n\r\n{{oopsContent-Length: {}\r\n\r\n{}", shutdown.len(), shutdown);
+
+        let mut output = Vec::new();
+        serve(Cursor::new(input), &mut output).expect("should carry on after the junk");
+
+        let mut output = Cursor::new(output);
+        let junk = read_message(&mut output).expect("well formed").expect("a reply to the junk");
+        assert_eq!(junk["id"], Value::Null);
+        assert_eq!(junk["error"]["code"], PARSE_ERROR);
+        let shutdown = read_message(&mut output).expect("well formed").expect("a reply to shutdown");
+        assert_eq!(shutdown["id"], 1);
+        assert_eq!(shutdown["result"], Value::Null);
+
+        let mut input = Cursor::new(b"Content-Length: 99999999999\r\n\r\n{}".to_vec());
+        assert!(matches!(read_message(&mut input), Err(LspErr::TooLong { length: 99999999999 })));
+    }
+
+    #[test]
+    fn utf16_positions() {
+        let text = "é𝄞: +\n+";
+        let offset_of = |line, character| offset(text, &json!({"line": line, "character": character}));
+
+        assert_eq!(offset_of(0, 1), 2);
+        assert_eq!(offset_of(0, 3), 6);
+        assert_eq!(position(text, 6), json!({"line": 0, "character": 3}));
+        assert_eq!(offset_of(1, 0), text.len() - 1);
+        assert_eq!(offset_of(7, 0), text.len());
+    }
+}
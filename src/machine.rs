@@ -0,0 +1,318 @@
+use thiserror::Error;
+
+use std::collections::VecDeque;
+
+use crate::io_buffer::IoBuffer;
+use crate::wmach;
+
+#[derive(Error, Debug)]
+pub enum MachineError {
+    #[error("IO: {source}")]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Running,
+    Halted,
+}
+
+// An unbounded, bidirectional tape of bits. Cells are only materialized once the head visits
+// them; everything else reads as 0. Positions are relative to the cell the head started on.
+#[derive(Debug, Clone)]
+pub struct Tape {
+    cells: VecDeque<bool>,
+    // Index into cells of position 0.
+    origin: usize,
+    // Index into cells of the head.
+    head: usize,
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tape {
+    pub fn new() -> Self {
+        Tape {
+            cells: VecDeque::from(vec![false]),
+            origin: 0,
+            head: 0,
+        }
+    }
+
+    // Starts out holding data, with position 0 wherever its head is.
+    pub fn with_data(data: &wmach::Data) -> Self {
+        let mut cells: VecDeque<bool> = data.bits.iter().cloned().collect();
+        if cells.len() <= data.head {
+            cells.resize(data.head + 1, false);
+        }
+
+        Tape {
+            cells,
+            origin: data.head,
+            head: data.head,
+        }
+    }
+
+    pub fn read(&self) -> bool {
+        self.cells[self.head]
+    }
+
+    pub fn write(&mut self, bit: bool) {
+        self.cells[self.head] = bit;
+    }
+
+    pub fn seek(&mut self, direction: &wmach::SeekOp) {
+        match direction {
+            wmach::SeekOp::Left => {
+                if self.head == 0 {
+                    self.cells.push_front(false);
+                    self.origin += 1;
+                } else {
+                    self.head -= 1;
+                }
+            }
+            wmach::SeekOp::Right => {
+                self.head += 1;
+                if self.head == self.cells.len() {
+                    self.cells.push_back(false);
+                }
+            }
+        }
+    }
+
+    pub fn head(&self) -> isize {
+        self.head as isize - self.origin as isize
+    }
+
+    // The westernmost position the head has visited.
+    pub fn start(&self) -> isize {
+        -(self.origin as isize)
+    }
+
+    pub fn get(&self, position: isize) -> bool {
+        let index = position + self.origin as isize;
+        if index < 0 {
+            return false;
+        }
+
+        self.cells.get(index as usize).cloned().unwrap_or(false)
+    }
+
+    // Every visited cell, starting from Tape::start().
+    pub fn bits(&self) -> Vec<bool> {
+        self.cells.iter().cloned().collect()
+    }
+}
+
+// A plain interpreter for the w-machine. This is the reference semantics that the tile compiler
+// (and anything else) is expected to agree with.
+#[derive(Debug)]
+pub struct Machine<I: std::io::Read, O: std::io::Write> {
+    code: wmach::Code,
+    pc: wmach::InsnOffset,
+    tape: Tape,
+    steps: usize,
+
+    io: IoBuffer<I, O>,
+}
+
+impl Machine<std::io::Stdin, std::io::Stdout> {
+    pub fn new(program: &wmach::Program) -> Self {
+        Machine::with_io(program, std::io::stdin(), std::io::stdout())
+    }
+}
+
+impl<I: std::io::Read, O: std::io::Write> Machine<I, O> {
+    pub fn with_io(program: &wmach::Program, input: I, output: O) -> Self {
+        Machine {
+            code: program.instructions.clone(),
+            pc: 0,
+            tape: Tape::with_data(&program.data),
+            steps: 0,
+
+            io: IoBuffer::with_io(input, output),
+        }
+    }
+
+    pub fn pc(&self) -> wmach::InsnOffset {
+        self.pc
+    }
+
+    pub fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    // A halt stops the machine where it is. So does running off the end of the instructions.
+    pub fn status(&self) -> Status {
+        match self.code.get(self.pc) {
+            Some(wmach::Insn::Halt) | None => Status::Halted,
+            Some(_) => Status::Running,
+        }
+    }
+
+    pub fn step(&mut self) -> Result<Status, MachineError> {
+        let insn = match self.code.get(self.pc) {
+            Some(wmach::Insn::Halt) | None => return Ok(Status::Halted),
+            Some(insn) => insn,
+        };
+
+        let mut next = self.pc + 1;
+        match insn {
+            wmach::Insn::Write(value) => self.tape.write(*value == wmach::WriteOp::Set),
+            wmach::Insn::Seek(direction, count) => {
+                for _ in 0..*count {
+                    self.tape.seek(direction);
+                }
+            }
+            wmach::Insn::Io(wmach::IoOp::In) => {
+                let bit = self.io.get()?;
+                self.tape.write(bit);
+            }
+            wmach::Insn::Io(wmach::IoOp::Out) => self.io.put(self.tape.read())?,
+            wmach::Insn::Jmp(branch_t, branch_f) => {
+                next = if self.tape.read() { *branch_t } else { *branch_f };
+            }
+            wmach::Insn::Debug | wmach::Insn::Halt => (),
+        };
+
+        self.pc = next;
+        self.steps += 1;
+
+        Ok(self.status())
+    }
+
+    // Step until the machine halts or max_steps instructions have executed, whichever comes
+    // first.
+    pub fn run(&mut self, max_steps: usize) -> Result<Status, MachineError> {
+        for _ in 0..max_steps {
+            if self.step()? == Status::Halted {
+                break;
+            }
+        }
+
+        Ok(self.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn machine<'a>(src: &str, input: &'a [u8], output: &'a mut Vec<u8>) -> Machine<&'a [u8], &'a mut Vec<u8>> {
+        let program = wmach::Program::from_str(src).expect("should parse fine");
+        Machine::with_io(&program, input, output)
+    }
+
+    #[test]
+    fn tape_grows_both_ways() {
+        let mut tape = Tape::new();
+
+        tape.seek(&wmach::SeekOp::Left);
+        tape.seek(&wmach::SeekOp::Left);
+        tape.write(true);
+        assert_eq!(tape.head(), -2);
+        assert_eq!(tape.start(), -2);
+
+        for _ in 0..4 {
+            tape.seek(&wmach::SeekOp::Right);
+        }
+        tape.write(true);
+        assert_eq!(tape.head(), 2);
+
+        assert_eq!(tape.bits(), vec![true, false, false, false, true]);
+        assert!(tape.get(-2));
+        assert!(!tape.get(0));
+        assert!(!tape.get(-100));
+        assert!(!tape.get(100));
+    }
+
+    #[test]
+    fn scan_for_zero() {
+        let src = "
+            + > + > + < <
+            scan: jmp right, done
+            right: > jmp scan, scan
+            done:
+        ";
+        let mut output = Vec::new();
+        let mut m = machine(src, &[], &mut output);
+
+        let status = m.run(1000).expect("should run fine");
+        assert_eq!(status, Status::Halted);
+        assert_eq!(m.pc(), 10);
+        assert_eq!(m.tape().head(), 3);
+        assert_eq!(m.tape().bits(), vec![true, true, true, false]);
+    }
+
+    #[test]
+    fn halt_stays_put() {
+        let mut output = Vec::new();
+        let mut m = machine("+ > halt +", &[], &mut output);
+
+        let status = m.run(100).expect("should run fine");
+        assert_eq!(status, Status::Halted);
+        assert_eq!(m.steps(), 2);
+        assert_eq!(m.pc(), 2);
+        assert_eq!(m.tape().bits(), vec![true, false]);
+    }
+
+    #[test]
+    fn starts_on_data() {
+        let mut output = Vec::new();
+        let mut m = machine("data bits 1101 at 2 < - > > +", &[], &mut output);
+
+        assert_eq!(m.tape().head(), 0);
+        assert_eq!(m.tape().start(), -2);
+        assert!(!m.tape().read());
+
+        m.run(100).expect("should run fine");
+        assert_eq!(m.tape().bits(), vec![true, false, false, true]);
+    }
+
+    #[test]
+    fn run_is_bounded() {
+        let mut output = Vec::new();
+        let mut m = machine("spin: + jmp spin", &[], &mut output);
+
+        let status = m.run(5).expect("should run fine");
+        assert_eq!(status, Status::Running);
+        assert_eq!(m.steps(), 5);
+        assert_eq!(m.pc(), 1);
+    }
+
+    #[test]
+    fn echo_byte() {
+        let mut output = Vec::new();
+        {
+            let mut m = machine(",.,.,.,.,.,.,.,.", b"A", &mut output);
+            let status = m.run(100).expect("should run fine");
+            assert_eq!(status, Status::Halted);
+        }
+
+        assert_eq!(output, b"A");
+    }
+
+    #[test]
+    fn input_exhausted() {
+        let mut output = Vec::new();
+        let mut m = machine(",", &[], &mut output);
+
+        match m.step() {
+            Err(MachineError::Io { source: _ }) => (),
+            x => panic!("Reading past the end of the input should fail: {:?}", x),
+        };
+    }
+}
@@ -0,0 +1,466 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use crate::diagnostic::SourceMap;
+use crate::diagnostic::Span;
+use crate::diagnostic::Spanned;
+use crate::lower;
+use crate::wmach::{Argument, LabelId, Macro, Stmt, Target, WmachErr};
+
+type Block = Vec<Spanned<Stmt>>;
+
+#[derive(Debug, Clone)]
+enum Binding {
+    Label(LabelId),
+    // Along with how many times the body has used it so far.
+    Block(Block, Cell<usize>),
+}
+
+// What names mean inside the macro body currently being expanded. At the top level both are
+// empty and every name means itself.
+#[derive(Default)]
+struct Scope {
+    bindings: HashMap<LabelId, Binding>,
+    // Labels the body defines for itself, mapped to the names this expansion gives them.
+    renames: HashMap<LabelId, LabelId>,
+}
+
+struct Expander<'a> {
+    sources: &'a SourceMap,
+    macros: HashMap<LabelId, Macro>,
+
+    // Every label name in the source plus every name handed out so far, so that fresh names can
+    // never collide with anything.
+    used: HashSet<LabelId>,
+    expansions: usize,
+
+    // The macros currently being expanded, innermost last.
+    stack: Vec<LabelId>,
+}
+
+// Replace every macro invocation with the body of the macro. Labels defined inside a body are
+// renamed on each expansion so that a macro can be used more than once, and so are those in a
+// block argument the body uses more than once.
+pub fn expand(sources: &SourceMap, statements: Block) -> Result<Block, WmachErr> {
+    let mut macros = HashMap::new();
+    let mut program = Vec::new();
+    for stmt in statements {
+        match stmt.node {
+            Stmt::Macro(definition) => {
+                let name = definition.name.node.clone();
+                if macros.contains_key(&name) {
+                    return Err(WmachErr::DuplicateMacro {
+                        name,
+                        snippet: sources.snippet(definition.name.span),
+                    });
+                }
+
+                check_params(sources, &definition)?;
+                macros.insert(name, definition);
+            }
+            _ => program.push(stmt),
+        }
+    }
+
+    let mut used = HashSet::new();
+    collect_labels(&program, &mut used);
+    for definition in macros.values() {
+        collect_labels(&definition.body, &mut used);
+    }
+
+    let mut expander = Expander {
+        sources,
+        macros,
+
+        used,
+        expansions: 0,
+
+        stack: Vec::new(),
+    };
+
+    expander.expand(&program, &Scope::default())
+}
+
+fn check_params(sources: &SourceMap, definition: &Macro) -> Result<(), WmachErr> {
+    let mut seen = HashSet::new();
+    for param in definition.params.iter() {
+        if !seen.insert(&param.node) {
+            Err(WmachErr::DuplicateParameter {
+                param: param.node.clone(),
+                snippet: sources.snippet(param.span),
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+// Labels defined by a block, including those in block arguments it passes on.
+pub fn collect_labels(statements: &[Spanned<Stmt>], labels: &mut HashSet<LabelId>) {
+    for stmt in statements.iter() {
+        match &stmt.node {
+            Stmt::Label(label) => {
+                labels.insert(label.clone());
+            }
+            Stmt::Invoke(_, args) => {
+                for arg in args.iter() {
+                    if let Argument::Block(block) = &arg.node {
+                        collect_labels(block, labels);
+                    }
+                }
+            }
+            Stmt::Macro(definition) => collect_labels(&definition.body, labels),
+            Stmt::Repeat(_, body) => collect_labels(body, labels),
+            _ => (),
+        }
+    }
+}
+
+impl<'a> Expander<'a> {
+    fn expand(&mut self, statements: &[Spanned<Stmt>], scope: &Scope) -> Result<Block, WmachErr> {
+        let mut expanded = Vec::new();
+
+        for stmt in statements.iter() {
+            match &stmt.node {
+                Stmt::Label(label) => {
+                    let label = self.label(scope, label, stmt.span)?;
+                    expanded.push(Spanned::new(Stmt::Label(label), stmt.span));
+                }
+                Stmt::Jmp(branch_t, branch_f) => {
+                    let branch_t = self.target(scope, branch_t)?;
+                    let branch_f = self.target(scope, branch_f)?;
+                    expanded.push(Spanned::new(Stmt::Jmp(branch_t, branch_f), stmt.span));
+                }
+                Stmt::Invoke(name, args) => expanded.extend(self.invoke(scope, name, args)?),
+                Stmt::Repeat(count, body) => {
+                    let body = self.expand(body, scope)?;
+                    expanded.push(Spanned::new(Stmt::Repeat(*count, body), stmt.span));
+                }
+                Stmt::Macro(definition) => Err(WmachErr::NestedMacro {
+                    name: definition.name.node.clone(),
+                    snippet: self.sources.snippet(definition.name.span),
+                })?,
+                _ => expanded.push(stmt.clone()),
+            }
+        }
+
+        Ok(expanded)
+    }
+
+    fn label(&self, scope: &Scope, label: &LabelId, span: Span) -> Result<LabelId, WmachErr> {
+        if let Some(renamed) = scope.renames.get(label) {
+            return Ok(renamed.clone());
+        }
+
+        match scope.bindings.get(label) {
+            Some(Binding::Label(bound)) => Ok(bound.clone()),
+            Some(Binding::Block(..)) => Err(WmachErr::MisusedParameter {
+                param: label.clone(),
+                expected: "label",
+                snippet: self.sources.snippet(span),
+            }),
+            None => Ok(label.clone()),
+        }
+    }
+
+    fn target(&self, scope: &Scope, target: &Spanned<Target>) -> Result<Spanned<Target>, WmachErr> {
+        match &target.node {
+            Target::Name(label) => {
+                let label = self.label(scope, label, target.span)?;
+                Ok(Spanned::new(Target::Name(label), target.span))
+            }
+            Target::NextAddress => Ok(target.clone()),
+        }
+    }
+
+    fn invoke(
+        &mut self,
+        scope: &Scope,
+        name: &Spanned<LabelId>,
+        args: &[Spanned<Argument>],
+    ) -> Result<Block, WmachErr> {
+        // Block arguments were expanded in the caller's scope already, so they go in as they are
+        // the first time. Like the passes of a repeat, every time after that gets fresh names for
+        // the labels the block defines, and anything else it jumps to is still the caller's.
+        match scope.bindings.get(&name.node) {
+            Some(Binding::Block(block, splices)) if args.is_empty() => {
+                splices.set(splices.get() + 1);
+                if splices.get() == 1 {
+                    return Ok(block.clone());
+                }
+
+                let mut locals = HashSet::new();
+                collect_labels(block, &mut locals);
+                self.expansions += 1;
+                let renames: HashMap<LabelId, LabelId> = locals
+                    .into_iter()
+                    .map(|label| {
+                        let fresh = self.fresh(&name.node, &label);
+                        (label, fresh)
+                    })
+                    .collect();
+
+                return Ok(block.iter().map(|stmt| lower::rename(stmt, &renames)).collect());
+            }
+            Some(Binding::Block(..)) => Err(WmachErr::MacroArity {
+                name: name.node.clone(),
+                expected: 0,
+                found: args.len(),
+                snippet: self.sources.snippet(name.span),
+            })?,
+            Some(Binding::Label(_)) => Err(WmachErr::MisusedParameter {
+                param: name.node.clone(),
+                expected: "block",
+                snippet: self.sources.snippet(name.span),
+            })?,
+            None => (),
+        };
+
+        let definition = match self.macros.get(&name.node) {
+            Some(definition) => definition.clone(),
+            None => Err(WmachErr::UnknownMacro {
+                name: name.node.clone(),
+                snippet: self.sources.snippet(name.span),
+            })?,
+        };
+
+        if self.stack.contains(&name.node) {
+            Err(WmachErr::RecursiveMacro {
+                name: name.node.clone(),
+                snippet: self.sources.snippet(name.span),
+            })?;
+        }
+
+        if args.len() != definition.params.len() {
+            Err(WmachErr::MacroArity {
+                name: name.node.clone(),
+                expected: definition.params.len(),
+                found: args.len(),
+                snippet: self.sources.snippet(name.span),
+            })?;
+        }
+
+        let mut bindings = HashMap::new();
+        for (param, arg) in definition.params.iter().zip(args.iter()) {
+            let binding = match &arg.node {
+                Argument::Label(label) => Binding::Label(self.label(scope, label, arg.span)?),
+                Argument::Block(block) => Binding::Block(self.expand(block, scope)?, Cell::new(0)),
+            };
+            bindings.insert(param.node.clone(), binding);
+        }
+
+        let mut locals = HashSet::new();
+        collect_labels(&definition.body, &mut locals);
+        self.expansions += 1;
+        let mut renames = HashMap::new();
+        for local in locals {
+            if !bindings.contains_key(&local) {
+                let fresh = self.fresh(&name.node, &local);
+                renames.insert(local, fresh);
+            }
+        }
+
+        self.stack.push(name.node.clone());
+        let expanded = self.expand(&definition.body, &Scope { bindings, renames });
+        self.stack.pop();
+
+        expanded
+    }
+
+    // A name nobody has written or been given yet, e.g. inc'3'loop for the label loop in the
+    // third expansion overall, which happened to be of inc.
+    fn fresh(&mut self, name: &str, label: &str) -> LabelId {
+        let mut suffix = String::new();
+        loop {
+            let candidate = format!("{}'{}{}'{}", name, self.expansions, suffix, label);
+            if self.used.insert(candidate.clone()) {
+                return candidate;
+            }
+            suffix.push('\'');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use crate::machine::{Machine, Status};
+    use crate::wmach::{Insn, Program, SeekOp};
+
+    fn run(src: &str) -> Machine<&'static [u8], std::io::Sink> {
+        let program = Program::from_str(src).expect("should parse fine");
+        let mut machine = Machine::with_io(&program, &[][..], std::io::sink());
+
+        let status = machine.run(1000).expect("should run fine");
+        assert_eq!(status, Status::Halted);
+        machine
+    }
+
+    #[test]
+    fn labels_are_hygienic() {
+        let src = "
+            macro skip_ones(done) {
+                loop: jmp next, done
+                next: > jmp loop, loop
+            }
+
+            + > + <
+            skip_ones(first)
+            first: +
+            skip_ones(second)
+            second:
+        ";
+        let machine = run(src);
+
+        assert_eq!(machine.tape().head(), 3);
+        assert_eq!(machine.tape().bits(), vec![true, true, true, false]);
+    }
+
+    #[test]
+    fn repeats_in_macros() {
+        let src = "
+            macro twice(body) { repeat 2 { body() } }
+            macro skip_ones() { loop: jmp next, done next: > jmp loop, loop done: }
+
+            + > + > > + > + <4
+            twice({ skip_ones() > })
+        ";
+        let machine = run(src);
+
+        assert_eq!(machine.tape().head(), 6);
+    }
+
+    #[test]
+    fn fresh_names_avoid_user_labels() {
+        let src = "
+            macro spin() { loop: jmp loop }
+            skip_ones'1'loop: spin()
+        ";
+        let program = Program::from_str(src).expect("should parse fine");
+
+        assert_eq!(program.labels.len(), 2);
+        assert!(program.labels.contains_key("skip_ones'1'loop"));
+        assert!(!program.labels.contains_key("loop"));
+    }
+
+    #[test]
+    fn block_arguments() {
+        let src = "
+            macro twice(body) { body() body() }
+            twice({ > })
+            twice({ twice({ < }) })
+        ";
+        let program = Program::from_str(src).expect("should parse fine");
+        let seeks: Vec<Insn> = [SeekOp::Right, SeekOp::Right]
+            .iter()
+            .chain([SeekOp::Left; 4].iter())
+            .map(|op| Insn::Seek(*op, 1))
+            .collect();
+
+        assert_eq!(format!("{:?}", program.instructions), format!("{:?}", seeks));
+
+        // Labels the block defines get new names each time round, the ones it only uses don't.
+        let src = "
+            macro twice(body) { body() body() }
+            twice({ l: + jmp l, done })
+            twice({ repeat 2 { m: - jmp m, done } })
+            done: halt
+        ";
+        let program = Program::from_str(src).expect("labels in blocks shouldn't clash");
+        assert_eq!(program.labels.get("l"), Some(&0));
+        assert_eq!(program.labels.get("body'2'l"), Some(&2));
+        assert_eq!(program.labels.get("body'4'm"), Some(&8));
+        assert_eq!(program.instructions[1], Insn::Jmp(0, 12));
+        assert_eq!(program.instructions[3], Insn::Jmp(2, 12));
+        assert_eq!(program.instructions[9], Insn::Jmp(8, 12));
+    }
+
+    #[test]
+    fn block_labels_belong_to_the_caller() {
+        let src = "
+            macro wrap(body) { outer: body() }
+            macro spin_once() {
+                wrap({ inner: jmp inner, done })
+                done:
+            }
+            spin_once()
+            spin_once()
+        ";
+        let machine = run(src);
+
+        assert_eq!(machine.pc(), 2);
+    }
+
+    #[test]
+    fn unknown_macro() {
+        match Program::from_str("+\n  nope(x)") {
+            Err(WmachErr::UnknownMacro { name, snippet }) => {
+                assert_eq!(name, "nope");
+                assert_eq!(snippet.location().line, 2);
+                assert_eq!(snippet.location().column, 3);
+            }
+            x => panic!("Failed to reject the unknown macro: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn wrong_arity() {
+        let src = "macro m(a, b) { jmp a, b }\nm(x)";
+        match Program::from_str(src) {
+            Err(WmachErr::MacroArity {
+                expected: 2,
+                found: 1,
+                ..
+            }) => (),
+            x => panic!("Failed to reject the bad invocation: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn recursion() {
+        let src = "macro a() { b() }\nmacro b() { a() }\na()";
+        match Program::from_str(src) {
+            Err(WmachErr::RecursiveMacro { name, .. }) => assert_eq!(name, "a"),
+            x => panic!("Failed to reject the recursive macro: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn misused_parameters() {
+        match Program::from_str("macro m(body) { jmp body }\nm({ + })") {
+            Err(WmachErr::MisusedParameter {
+                expected: "label", ..
+            }) => (),
+            x => panic!("Failed to reject the block used as a label: {:?}", x),
+        };
+
+        match Program::from_str("macro m(out) { out() }\nm(x)") {
+            Err(WmachErr::MisusedParameter {
+                expected: "block", ..
+            }) => (),
+            x => panic!("Failed to reject the label used as a block: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn bad_definitions() {
+        match Program::from_str("macro m() { + }\nmacro m() { - }") {
+            Err(WmachErr::DuplicateMacro { snippet, .. }) => assert_eq!(snippet.location().line, 2),
+            x => panic!("Failed to reject the duplicate macro: {:?}", x),
+        };
+
+        match Program::from_str("macro m(a, a) { + }") {
+            Err(WmachErr::DuplicateParameter { param, .. }) => assert_eq!(param, "a"),
+            x => panic!("Failed to reject the duplicate parameter: {:?}", x),
+        };
+
+        match Program::from_str("macro m() { macro n() { } }\nm()") {
+            Err(WmachErr::NestedMacro { name, .. }) => assert_eq!(name, "n"),
+            x => panic!("Failed to reject the nested macro: {:?}", x),
+        };
+    }
+}
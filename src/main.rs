@@ -0,0 +1,197 @@
+use anyhow::Result;
+use thiserror::Error;
+
+use std::io::BufRead;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+extern crate getopts;
+
+use bones::binary;
+use bones::brainfuck;
+use bones::compiler::{self, Backend};
+use bones::lint;
+use bones::optimize;
+use bones::structured;
+use bones::tessera;
+use bones::wmach;
+
+#[derive(Error, Debug)]
+pub enum BoneError {
+    #[error("Command-line help requested.")]
+    Help,
+
+    #[error("Missing filename.")]
+    MissingFilename,
+
+    #[error("Missing source code.")]
+    MissingSource,
+}
+
+fn go(tiles: &mut tessera::Program) -> Result<()> {
+    loop {
+        match tiles.step()? {
+            tessera::Status::Running => (),
+            status @ tessera::Status::Halted { .. } => {
+                eprintln!("{}", status);
+                return Ok(());
+            }
+            status @ tessera::Status::Breakpoint { .. } => {
+                eprintln!("{}\ntape: {}", status, tiles.tape());
+                pause()?;
+            }
+        }
+    }
+
+    /*
+     * TODO
+     * - Make east/west pips a different type from north/south?
+     */
+}
+
+// stdin belongs to the program, so wait on the terminal instead. If nobody is watching stderr, or
+// there's no terminal at all, we just keep going.
+fn pause() -> Result<()> {
+    if !std::io::stderr().is_terminal() {
+        return Ok(());
+    }
+
+    if let Ok(tty) = std::fs::File::open("/dev/tty") {
+        eprint!("press enter to continue");
+        std::io::BufReader::new(tty).read_line(&mut String::new())?;
+    }
+
+    Ok(())
+}
+
+// The source from -f or -s, for the front ends that don't load files themselves.
+fn read(matches: &getopts::Matches) -> Result<String> {
+    match matches.opt_str("f") {
+        Some(filename) => Ok(std::fs::read_to_string(filename)?),
+        None => Ok(matches.opt_str("s").ok_or(BoneError::MissingSource)?),
+    }
+}
+
+// Whichever front end the options ask for, down to a program that still knows where it came from.
+fn compile(matches: &getopts::Matches) -> Result<binary::Image> {
+    let ast = if matches.opt_present("b") {
+        brainfuck::translate(&read(matches)?)?
+    } else if matches.opt_present("w") {
+        structured::translate(&read(matches)?)?
+    } else if matches.opt_present("f") {
+        let filename = matches.opt_str("f").ok_or(BoneError::MissingFilename)?;
+
+        wmach::Ast::from_file(Path::new(&filename))?
+    } else if matches.opt_present("src") {
+        let src = matches.opt_str("src").ok_or(BoneError::MissingSource)?;
+
+        wmach::Ast::from_str(&src)?
+    } else {
+        panic!("Fix the required matches in the command line parser.");
+    };
+
+    if matches.opt_present("lint") {
+        for warning in lint::lint(&ast)? {
+            eprintln!("warning: {}\n", warning);
+        }
+    }
+
+    Ok(binary::Image {
+        program: ast.resolve_labels()?,
+        sources: Some(binary::SourceInfo::new(&ast)?),
+    })
+}
+
+fn usage(opts: getopts::Options) -> Result<()> {
+    let brief = "Usage: bones FILE [options]";
+    eprintln!("{}", opts.usage(brief));
+
+    eprintln!("Kinds of --emit:");
+    for emitter in compiler::EMITTERS.iter() {
+        eprintln!("    {:<8} {}", emitter.name, emitter.about);
+    }
+
+    Err(BoneError::Help)?;
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    let mut opts = getopts::Options::new();
+    opts.optopt("f", "file", "source file to interpret", "NAME");
+    opts.optopt("s", "src", "source string to interpret", "SRC-CODE");
+    opts.optflag("b", "brainfuck", "the source is Brainfuck rather than wmach");
+    opts.optflag("w", "structured", "the source uses if/while/loop/proc rather than jumps");
+    opts.optflag("O", "optimize", "optimise the program before compiling it");
+    opts.optopt("", "emit", "write the program out as KIND rather than running it", "KIND");
+    opts.optopt("o", "output", "where --emit writes to instead of stdout, binary if there is no --emit", "NAME");
+    opts.optflag("", "fmt", "the same as --emit wmach");
+    opts.optflag("", "dot", "the same as --emit dot");
+    opts.optflag("", "base64", "the same as --emit base64");
+    opts.optflag("", "lint", "warn about suspicious code before running it");
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = opts.parse(&args[1..])?;
+    if matches.opt_present("h") || !(matches.opt_present("f") || matches.opt_present("s")) {
+        usage(opts)?;
+    }
+
+    // Files written by -o are loaded as they are, skipping the front end altogether.
+    let image = match matches.opt_str("f") {
+        Some(filename) if !(matches.opt_present("b") || matches.opt_present("w")) => {
+            let bytes = std::fs::read(&filename)?;
+            if binary::is_binary(&bytes) {
+                Some(binary::Image::decode(&bytes)?)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    };
+
+    let mut image = match image {
+        Some(image) => image,
+        None => compile(&matches)?,
+    };
+
+    if matches.opt_present("O") {
+        let report = optimize::PassManager::default().run(&mut image.program);
+        eprintln!("{}", report);
+
+        // The spans no longer line up with the instructions.
+        image.sources = None;
+    }
+
+    // Every kind of output goes through the one pipeline, the old flags just pick a kind for you.
+    let kind = if let Some(kind) = matches.opt_str("emit") {
+        Some(kind)
+    } else if matches.opt_present("fmt") {
+        Some("wmach".to_string())
+    } else if matches.opt_present("dot") {
+        Some("dot".to_string())
+    } else if matches.opt_present("base64") {
+        Some("base64".to_string())
+    } else if matches.opt_present("o") {
+        Some("binary".to_string())
+    } else {
+        None
+    };
+
+    if let Some(kind) = kind {
+        let bytes = compiler::emitter(&kind)?.emit(&image)?;
+        match matches.opt_str("o") {
+            Some(filename) => std::fs::write(filename, bytes)?,
+            None => std::io::stdout().write_all(&bytes)?,
+        }
+        return Ok(());
+    }
+
+    let mut tiles = tessera::Tiles.compile(&image.program)?;
+
+    go(&mut tiles)?;
+
+    Ok(())
+}
@@ -93,6 +93,10 @@ pub struct Mosaic {
     program: tessera::Program,
     mosaic: Vec<TileRow>,
     running: bool,
+
+    // Rows where a ! went off. We stop computing after each one until somebody resumes us.
+    breakpoints: Vec<usize>,
+    paused: bool,
 }
 impl<'a> Mosaic {
     pub fn new(source_code: &str) -> anyhow::Result<Self> {
@@ -124,20 +128,33 @@ impl<'a> Mosaic {
 
             tessera::Program::new(tile_set, border_tile, initial_state_vec)?
         } else {
-            wmach::Program::from_str(source_code)?
-                .compile()?
+            // Seeks stay as long as they were written, tessera does each one in a single row.
+            let program = wmach::Ast::from_str(source_code)?.resolve_labels()?;
+            tessera::Tiles.compile(&program)?
         };
 
+        Ok(Self::with_tiles(program))
+    }
+
+    // For programs that have already been through the front end, e.g. ones loaded from binary.
+    pub fn from_program(program: &wmach::Program) -> anyhow::Result<Self> {
+        Ok(Self::with_tiles(tessera::Tiles.compile(program)?))
+    }
+
+    fn with_tiles(program: tessera::Program) -> Self {
         let mosaic = vec![TileRow {
             offset: 0,
             tiles: program.state(),
         }];
 
-        Ok(Self {
+        Self {
             program: program,
             mosaic: mosaic,
             running: true,
-        })
+
+            breakpoints: Vec::new(),
+            paused: false,
+        }
     }
 
     pub fn get_tile(&self, row: i32, col: i32, options: &TileRetrieval) -> Option<tiling::Tile> {
@@ -171,12 +188,22 @@ impl<'a> Mosaic {
     pub fn compute(&mut self, row_start: i32, row_end: i32, col_start: i32, col_end: i32) -> Result<ComputeCertificate, tessera::MosaicError> {
         // calculate new tiles, if necessary
         if col_end >= 0 {
-            while self.mosaic.len() <= (col_end as usize) && self.running {
-                if let Err(e) = self.program.step() {
-                    log!("Unable to step: {:?}", e);
-                    self.running = false;
-                    break;
-                }
+            while self.mosaic.len() <= (col_end as usize) && self.running && !self.paused {
+                let status = match self.program.step() {
+                    // Every row from here on is the same as the last one so there's nothing left
+                    // to draw.
+                    Ok(status @ tessera::Status::Halted { .. }) => {
+                        log!("{}", status);
+                        self.running = false;
+                        break;
+                    }
+                    Ok(status) => status,
+                    Err(e) => {
+                        log!("Unable to step: {:?}", e);
+                        self.running = false;
+                        break;
+                    }
+                };
 
                 let state = self.program.state();
 
@@ -223,6 +250,12 @@ impl<'a> Mosaic {
                     offset: prev_offset + offset,
                     tiles: state,
                 });
+
+                if let tessera::Status::Breakpoint { .. } = status {
+                    log!("{}, tape: {}", status, self.program.tape());
+                    self.breakpoints.push(self.mosaic.len() - 1);
+                    self.paused = true;
+                }
             }
         }
 
@@ -234,6 +267,19 @@ impl<'a> Mosaic {
         })
     }
 
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    // Every row a breakpoint went off in so far, oldest first.
+    pub fn breakpoints(&self) -> &[usize] {
+        &self.breakpoints
+    }
+
     pub fn tile_range(&'a self, proof: ComputeCertificate, options: TileRetrieval) -> TileView<'a> {
         // assert that compute() was called before. We seemingly have to split this up due to
         // mutable borrows being required to store the computation not mixing well with immutable
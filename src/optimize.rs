@@ -0,0 +1,432 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::wmach::{Insn, InsnOffset, Program};
+
+// What a pass did to a program.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Changes {
+    // Instructions that are gone altogether.
+    pub removed: usize,
+    // Instructions that were changed in place.
+    pub rewritten: usize,
+}
+
+impl Changes {
+    fn any(&self) -> bool {
+        self.removed > 0 || self.rewritten > 0
+    }
+}
+
+impl std::ops::AddAssign for Changes {
+    fn add_assign(&mut self, other: Changes) {
+        self.removed += other.removed;
+        self.rewritten += other.rewritten;
+    }
+}
+
+// A rewrite of a program that doesn't change what it does to the tape or its IO. Every
+// instruction turns into a pile of dominoes in tessera, so each one we can get rid of makes the
+// Row solver's life easier.
+pub trait Pass {
+    fn name(&self) -> &'static str;
+    fn run(&self, program: &mut Program) -> Changes;
+}
+
+// A jmp that lands on another jmp can go straight to wherever that one goes. Jumps don't touch the
+// tape so we already know which way the second one will go.
+pub struct JumpThreading;
+
+// Nothing can jump or fall into it.
+pub struct Unreachable;
+
+// In + - + only the last write matters.
+pub struct CollapseWrites;
+
+// > < goes nowhere.
+pub struct CancelSeeks;
+
+impl Pass for JumpThreading {
+    fn name(&self) -> &'static str {
+        "jump threading"
+    }
+
+    fn run(&self, program: &mut Program) -> Changes {
+        let mut changes = Changes::default();
+
+        for offset in 0..program.instructions.len() {
+            if let Insn::Jmp(branch_t, branch_f) = program.instructions[offset] {
+                let threaded_t = thread(&program.instructions, branch_t, true);
+                let threaded_f = thread(&program.instructions, branch_f, false);
+
+                if (threaded_t, threaded_f) != (branch_t, branch_f) {
+                    program.instructions[offset] = Insn::Jmp(threaded_t, threaded_f);
+                    changes.rewritten += 1;
+                }
+            }
+        }
+
+        changes
+    }
+}
+
+// Follow jumps from target for as long as the bit under the head decides where they go.
+fn thread(code: &[Insn], mut target: InsnOffset, bit: bool) -> InsnOffset {
+    // Going around more times than there are instructions means we're in a loop of jumps.
+    for _ in 0..code.len() {
+        let next = match code.get(target) {
+            Some(Insn::Jmp(branch_t, branch_f)) => {
+                if bit {
+                    *branch_t
+                } else {
+                    *branch_f
+                }
+            }
+            _ => break,
+        };
+
+        if next == target {
+            break;
+        }
+        target = next;
+    }
+
+    target
+}
+
+impl Pass for Unreachable {
+    fn name(&self) -> &'static str {
+        "unreachable code"
+    }
+
+    fn run(&self, program: &mut Program) -> Changes {
+        let code = &program.instructions;
+        let mut reachable = vec![false; code.len()];
+
+        let mut pending = VecDeque::from(vec![0]);
+        while let Some(offset) = pending.pop_front() {
+            if offset >= code.len() || reachable[offset] {
+                continue;
+            }
+            reachable[offset] = true;
+
+            match code[offset] {
+                Insn::Jmp(branch_t, branch_f) => {
+                    pending.push_back(branch_t);
+                    pending.push_back(branch_f);
+                }
+                Insn::Halt => (),
+                _ => pending.push_back(offset + 1),
+            }
+        }
+
+        let dead: Vec<bool> = reachable.iter().map(|reachable| !reachable).collect();
+        Changes {
+            removed: remove(program, &dead),
+            rewritten: 0,
+        }
+    }
+}
+
+impl Pass for CollapseWrites {
+    fn name(&self) -> &'static str {
+        "collapse writes"
+    }
+
+    fn run(&self, program: &mut Program) -> Changes {
+        let targets = jump_targets(&program.instructions);
+        let code = &program.instructions;
+
+        // A write is dead if the very next thing to happen is another write. That's only certain
+        // if nobody can jump in between the two.
+        let mut dead = vec![false; code.len()];
+        for offset in 1..code.len() {
+            if let (Insn::Write(_), Insn::Write(_)) = (&code[offset - 1], &code[offset]) {
+                dead[offset - 1] = !targets[offset];
+            }
+        }
+
+        Changes {
+            removed: remove(program, &dead),
+            rewritten: 0,
+        }
+    }
+}
+
+impl Pass for CancelSeeks {
+    fn name(&self) -> &'static str {
+        "cancel seeks"
+    }
+
+    fn run(&self, program: &mut Program) -> Changes {
+        let targets = jump_targets(&program.instructions);
+        let code = &mut program.instructions;
+        let mut changes = Changes::default();
+
+        let mut offset = 1;
+        while offset < code.len() {
+            match (code[offset - 1].clone(), code[offset].clone()) {
+                (Insn::Seek(first, n), Insn::Seek(second, m)) if first != second && !targets[offset] => {
+                    // Only ever shorten seeks so single step programs stay that way.
+                    let cancelled = std::cmp::min(n, m);
+                    code[offset - 1] = Insn::Seek(first, n - cancelled);
+                    code[offset] = Insn::Seek(second, m - cancelled);
+                    changes.rewritten += (n > cancelled) as usize + (m > cancelled) as usize;
+                    offset += 2;
+                }
+                _ => offset += 1,
+            }
+        }
+
+        // Whatever went all the way down to nothing is gone rather than rewritten.
+        let dead: Vec<bool> = code.iter().map(|insn| matches!(insn, Insn::Seek(_, 0))).collect();
+        changes.removed = remove(program, &dead);
+
+        changes
+    }
+}
+
+// Which offsets something other than falling through can get to. The entry point counts.
+fn jump_targets(code: &[Insn]) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    targets[0] = true;
+
+    for insn in code.iter() {
+        if let Insn::Jmp(branch_t, branch_f) = insn {
+            targets[*branch_t] = true;
+            targets[*branch_f] = true;
+        }
+    }
+
+    targets
+}
+
+// Drop the dead instructions. Anything that pointed at one of them points at whatever follows it
+// instead.
+fn remove(program: &mut Program, dead: &[bool]) -> usize {
+    let mut addresses = Vec::with_capacity(dead.len() + 1);
+    let mut address = 0;
+    for dead in dead.iter() {
+        addresses.push(address);
+        if !dead {
+            address += 1;
+        }
+    }
+    addresses.push(address);
+
+    let removed = dead.len() - address;
+    if removed == 0 {
+        return 0;
+    }
+
+    let code = std::mem::take(&mut program.instructions);
+    program.instructions = code
+        .into_iter()
+        .zip(dead.iter())
+        .filter(|(_, dead)| !**dead)
+        .map(|(insn, _)| match insn {
+            Insn::Jmp(branch_t, branch_f) => Insn::Jmp(addresses[branch_t], addresses[branch_f]),
+            insn => insn,
+        })
+        .collect();
+
+    for offset in program.labels.values_mut() {
+        *offset = addresses[*offset];
+    }
+
+    removed
+}
+
+// How much each pass did, added up over every round.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub before: usize,
+    pub after: usize,
+    pub passes: Vec<(&'static str, Changes)>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, changes) in self.passes.iter() {
+            writeln!(
+                f,
+                "{}: {} removed, {} rewritten",
+                name, changes.removed, changes.rewritten
+            )?;
+        }
+
+        write!(f, "{} instructions down to {}", self.before, self.after)
+    }
+}
+
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        PassManager::new()
+            .with(JumpThreading)
+            .with(Unreachable)
+            .with(CollapseWrites)
+            .with(CancelSeeks)
+    }
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        PassManager { passes: Vec::new() }
+    }
+
+    pub fn with<P: Pass + 'static>(mut self, pass: P) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    // One pass tends to make work for another (threading leaves jumps unreachable, removing those
+    // brings seeks next to each other, ...) so keep going around until nothing changes.
+    pub fn run(&self, program: &mut Program) -> Report {
+        let mut report = Report {
+            before: program.instructions.len(),
+            after: 0,
+            passes: self.passes.iter().map(|pass| (pass.name(), Changes::default())).collect(),
+        };
+
+        loop {
+            let mut changed = false;
+            for (pass, (_, total)) in self.passes.iter().zip(report.passes.iter_mut()) {
+                let changes = pass.run(program);
+                changed |= changes.any();
+                *total += changes;
+            }
+
+            if !changed {
+                break;
+            }
+        }
+
+        report.after = program.instructions.len();
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use crate::machine::{Machine, Status};
+    use crate::wmach::{SeekOp, WriteOp};
+
+    fn optimize(src: &str) -> (Program, Program, Report) {
+        let original = Program::from_str(src).expect("should parse fine");
+        let mut optimized = original.clone();
+        let report = PassManager::default().run(&mut optimized);
+
+        (original, optimized, report)
+    }
+
+    // Both programs should leave the tape the same way.
+    fn equivalent(original: &Program, optimized: &Program) {
+        let mut machine = Machine::with_io(original, &[][..], std::io::sink());
+        assert_eq!(machine.run(1000).expect("should run fine"), Status::Halted);
+        let mut other = Machine::with_io(optimized, &[][..], std::io::sink());
+        assert_eq!(other.run(1000).expect("should run fine"), Status::Halted);
+
+        assert_eq!(machine.tape().head(), other.tape().head());
+        for position in -10..10 {
+            assert_eq!(machine.tape().get(position), other.tape().get(position));
+        }
+    }
+
+    #[test]
+    fn threads_jumps() {
+        let mut program = Program::from_str("jmp a, b a: jmp c, c b: jmp c, d c: + d: -").expect("should parse fine");
+        let changes = JumpThreading.run(&mut program);
+
+        assert_eq!(changes.rewritten, 1);
+        match program.instructions[0] {
+            // The true branch lands on an unconditional jump to c. The false branch lands on
+            // jmp c, d knowing the bit is 0, so that goes to d.
+            Insn::Jmp(3, 4) => (),
+            ref insn => panic!("jmp was not threaded: {:?}", insn),
+        };
+    }
+
+    #[test]
+    fn threading_survives_loops() {
+        let mut program = Program::from_str("a: jmp b b: jmp a").expect("should parse fine");
+        JumpThreading.run(&mut program);
+
+        assert_eq!(program.instructions.len(), 2);
+    }
+
+    #[test]
+    fn removes_unreachable() {
+        let mut program = Program::from_str("jmp end, end + > end: <").expect("should parse fine");
+        let changes = Unreachable.run(&mut program);
+
+        assert_eq!(changes.removed, 2);
+        assert_eq!(format!("{:?}", program.instructions), "[Jmp(1, 1), Seek(Left, 1)]");
+        assert_eq!(program.labels.get("end"), Some(&1));
+
+        let mut program = Program::from_str("+ halt > <").expect("should parse fine");
+        Unreachable.run(&mut program);
+        assert_eq!(format!("{:?}", program.instructions), "[Write(Set), Halt]");
+    }
+
+    #[test]
+    fn collapses_writes() {
+        let mut program = Program::from_str("+ - + target: - + jmp target").expect("should parse fine");
+        let changes = CollapseWrites.run(&mut program);
+
+        assert_eq!(changes.removed, 3);
+        match &program.instructions[..] {
+            [Insn::Write(WriteOp::Set), Insn::Write(WriteOp::Set), Insn::Jmp(1, 3)] => (),
+            code => panic!("writes were not collapsed: {:?}", code),
+        };
+    }
+
+    #[test]
+    fn cancels_seeks() {
+        let mut program = Program::from_str("> < + > > < <").expect("should parse fine");
+        let changes = CancelSeeks.run(&mut program);
+        assert_eq!(changes.removed, 4);
+        assert_eq!(format!("{:?}", program.instructions), "[Write(Set), Seek(Right, 1), Seek(Left, 1)]");
+
+        // Run-length seeks shrink instead.
+        let ast = crate::wmach::Ast::from_str(">>> <").expect("should parse fine");
+        let mut program = ast.resolve_labels().expect("should resolve fine");
+        let changes = CancelSeeks.run(&mut program);
+        assert_eq!(changes, Changes { removed: 1, rewritten: 1 });
+        assert!(matches!(program.instructions[..], [Insn::Seek(SeekOp::Right, 2)]));
+    }
+
+    #[test]
+    fn seeks_stay_put_when_jumped_between() {
+        let mut program = Program::from_str("> back: < jmp back").expect("should parse fine");
+        let changes = CancelSeeks.run(&mut program);
+
+        assert_eq!(changes, Changes::default());
+    }
+
+    #[test]
+    fn runs_to_a_fixed_point() {
+        let src = "
+            + > + <
+            scan: jmp right, done
+            right: > < > jmp over, over
+            over: jmp scan, scan
+            + + + never: -
+            done: - + > > < <
+        ";
+        let (original, optimized, report) = optimize(src);
+
+        assert_eq!(report.before, original.instructions.len());
+        assert_eq!(report.after, optimized.instructions.len());
+        assert_eq!(optimized.instructions.len(), 8);
+        assert!(report.to_string().ends_with("20 instructions down to 8"));
+        equivalent(&original, &optimized);
+    }
+}
@@ -203,9 +203,39 @@ impl Renderer {
             self.draw_triangle(tile_context.coord.0, tile_context.coord.1, tiling::Direction::South, s);
             self.draw_triangle(tile_context.coord.0, tile_context.coord.1, tiling::Direction::West, w);
         }
+
+        // Last, call out the rows where a breakpoint went off.
+        for col in self.model.breakpoints().iter().map(|col| *col as i32) {
+            if col >= col_start && col <= col_end {
+                self.highlight_row(col);
+            }
+        }
+    }
+
+    fn highlight_row(&self, col: i32) {
+        let tile_height: f64 = Renderer::TILE_HEIGHT * self.view.zoom;
+        let y = (col as f64) * tile_height + self.view.offset().y as f64;
+
+        self.canvas_ctx.save();
+        {
+            self.canvas_ctx.set_fill_style_str("rgba(255, 0, 0, 0.35)");
+            self.canvas_ctx.fill_rect(0.0, y, self.canvas.width().into(), tile_height);
+
+            self.canvas_ctx.set_stroke_style_str("#ff0000");
+            self.canvas_ctx.set_line_width(2.0);
+            self.canvas_ctx.stroke_rect(0.0, y, self.canvas.width().into(), tile_height);
+        }
+        self.canvas_ctx.restore();
     }
 
     pub fn update_pointer(&mut self, event: view_port::PointerEvent) {
+        // Clicking anywhere gets us going again after a breakpoint.
+        if let view_port::PointerEvent::Down(_) = event {
+            if self.model.paused() {
+                self.model.resume();
+            }
+        }
+
         // TODO make this a bool
         if self.view.update_cursor(event).is_ok() {
             self.render();
@@ -259,6 +289,11 @@ impl Renderer {
     }
 
     pub fn periodic(&mut self) {
+        // Sit on the breakpoint until somebody clicks.
+        if self.model.paused() {
+            return;
+        }
+
         _ = self.view.update_cursor(view_port::PointerEvent::Down(Coord::new(0, 0)));
         _ = self.view.update_cursor(view_port::PointerEvent::Move(Coord::new(-1, -3)));
         let value_never_used_and_does_not_matter = 9999;
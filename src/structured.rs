@@ -0,0 +1,526 @@
+use std::collections::HashMap;
+
+use nom::{
+    branch::alt, bytes::complete::tag, combinator::cut, combinator::map, combinator::not,
+    combinator::opt, multi::many0, sequence::pair, sequence::preceded, sequence::terminated,
+    sequence::tuple,
+};
+use thiserror::Error;
+
+use crate::diagnostic::Snippet;
+use crate::diagnostic::SourceMap;
+use crate::diagnostic::Span;
+use crate::diagnostic::Spanned;
+use crate::wmach::{self, Ast, IoOp, LabelId, Program, Stmt, Target, WmachErr, WriteOp};
+
+// A little structured language on top of wmach, so that nobody has to write jmp spaghetti by
+// hand:
+//
+//     proc skip_ones { while bit { > } }
+//
+//     loop {
+//         skip_ones()
+//         if not bit { halt } else { - }
+//     }
+//
+// The wmach primitives (+ - < > , . ! halt) are all still there, conditions test the bit under
+// the head, and procedures get inlined wherever they are called. It all lowers to plain wmach
+// statements with made up labels, so everything past the parser is shared with wmach proper.
+
+#[derive(Debug, Error)]
+pub enum StructuredErr {
+    #[error("Unable to parse ``{text}''\n{snippet}")]
+    SyntaxError { text: String, snippet: Snippet },
+
+    #[error("There is no loop to break out of here\n{snippet}")]
+    BreakOutsideLoop { snippet: Snippet },
+
+    #[error("Unknown procedure: {name}\n{snippet}")]
+    UnknownProcedure { name: String, snippet: Snippet },
+
+    #[error("Duplicate procedure: {name}\n{snippet}")]
+    DuplicateProcedure { name: String, snippet: Snippet },
+
+    // Everything gets inlined, so there is no way to stop.
+    #[error("Procedure {name} ends up calling itself\n{snippet}")]
+    RecursiveProcedure { name: String, snippet: Snippet },
+
+    #[error("{source}")]
+    Wmach {
+        #[from]
+        source: WmachErr,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum Node {
+    // + - < > , . ! and halt, exactly as wmach has them (>8 and all).
+    Primitive(Stmt),
+
+    // The bool is the value of the bit that picks the first block.
+    If(bool, Vec<Spanned<Node>>, Vec<Spanned<Node>>),
+    While(bool, Vec<Spanned<Node>>),
+    Loop(Vec<Spanned<Node>>),
+    Break,
+    Call(LabelId),
+}
+
+#[derive(Debug, Clone)]
+pub struct Proc {
+    pub name: Spanned<LabelId>,
+    pub body: Vec<Spanned<Node>>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Item {
+    Proc(Proc),
+    Node(Spanned<Node>),
+}
+
+// if, else and friends only count when they aren't the start of some longer name.
+fn keyword<'a>(word: &'static str) -> impl Fn(&'a str) -> nom::IResult<&'a str, &'a str> {
+    terminated(tag(word), not(wmach::label_segment))
+}
+
+fn primitive(input: &str) -> nom::IResult<&str, Node> {
+    let stmt = alt((
+        map(tag("+"), |_| Stmt::Write(WriteOp::Set)),
+        map(tag("-"), |_| Stmt::Write(WriteOp::Unset)),
+        map(tag(","), |_| Stmt::Io(IoOp::In)),
+        map(tag("."), |_| Stmt::Io(IoOp::Out)),
+        map(tag("!"), |_| Stmt::Debug),
+        map(keyword("halt"), |_| Stmt::Halt),
+        wmach::seek_op,
+    ));
+
+    map(stmt, Node::Primitive)(input)
+}
+
+// bit | not bit
+fn condition(input: &str) -> nom::IResult<&str, bool> {
+    alt((
+        map(keyword("bit"), |_| true),
+        map(tuple((keyword("not"), wmach::blank1, keyword("bit"))), |_| false),
+    ))(input)
+}
+
+// { node ... }
+fn block(input: &str) -> nom::IResult<&str, Vec<Spanned<Node>>> {
+    let (input, _) = pair(tag("{"), wmach::blank)(input)?;
+
+    cut(terminated(many0(any_node), tag("}")))(input)
+}
+
+fn if_node(input: &str) -> nom::IResult<&str, Node> {
+    let (input, (_, _, bit, _, then)) =
+        tuple((keyword("if"), wmach::blank1, condition, wmach::blank, block))(input)?;
+
+    // else if ... is just an else with an if as the only thing in it.
+    let else_if = |input| {
+        let (rest, node) = if_node(input)?;
+        Ok((rest, vec![Spanned::new(node, Span::between(input, rest))]))
+    };
+    let (input, otherwise) = opt(preceded(
+        tuple((wmach::blank, keyword("else"), wmach::blank)),
+        alt((block, else_if)),
+    ))(input)?;
+
+    Ok((input, Node::If(bit, then, otherwise.unwrap_or_default())))
+}
+
+fn while_node(input: &str) -> nom::IResult<&str, Node> {
+    let (input, (_, _, bit, _, body)) =
+        tuple((keyword("while"), wmach::blank1, condition, wmach::blank, block))(input)?;
+
+    Ok((input, Node::While(bit, body)))
+}
+
+fn loop_node(input: &str) -> nom::IResult<&str, Node> {
+    let (input, (_, _, body)) = tuple((keyword("loop"), wmach::blank, block))(input)?;
+
+    Ok((input, Node::Loop(body)))
+}
+
+// name()
+fn call(input: &str) -> nom::IResult<&str, Node> {
+    let (input, (name, _, _, _, _)) =
+        tuple((wmach::label_segment, wmach::blank, tag("("), wmach::blank, tag(")")))(input)?;
+
+    Ok((input, Node::Call(name.to_string())))
+}
+
+fn node(input: &str) -> nom::IResult<&str, Node> {
+    alt((
+        if_node,
+        while_node,
+        loop_node,
+        map(keyword("break"), |_| Node::Break),
+        primitive,
+        call,
+    ))(input)
+}
+
+fn any_node(input: &str) -> nom::IResult<&str, Spanned<Node>> {
+    let (rest, node) = node(input)?;
+    let node = Spanned::new(node, Span::between(input, rest));
+
+    let (rest, _) = wmach::blank(rest)?;
+
+    Ok((rest, node))
+}
+
+// proc name { node ... }
+fn proc(input: &str) -> nom::IResult<&str, Proc> {
+    let (input, _) = pair(keyword("proc"), wmach::blank1)(input)?;
+
+    let name = |input| {
+        let (rest, name) = wmach::label_segment(input)?;
+        Ok((rest, Spanned::new(name.to_string(), Span::between(input, rest))))
+    };
+    let (input, (name, _, body)) = cut(tuple((name, wmach::blank, block)))(input)?;
+
+    Ok((input, Proc { name, body }))
+}
+
+fn any_item(input: &str) -> nom::IResult<&str, Item> {
+    let (rest, item) = alt((map(proc, Item::Proc), map(any_node, Item::Node)))(input)?;
+    let (rest, _) = wmach::blank(rest)?;
+
+    Ok((rest, item))
+}
+
+fn syntax_error(unparsed: &str, rest: &str) -> StructuredErr {
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let span = Span::between(rest, &rest[end..]);
+
+    StructuredErr::SyntaxError {
+        text: rest[..end].to_string(),
+        snippet: Snippet::new(unparsed, span).expect("rest should be the end of unparsed"),
+    }
+}
+
+pub fn parse(unparsed: &str) -> Result<Vec<Item>, StructuredErr> {
+    let (rest, items) = preceded(wmach::blank, many0(any_item))(unparsed).map_err(|e| match e {
+        nom::Err::Error((rest, _)) | nom::Err::Failure((rest, _)) => syntax_error(unparsed, rest),
+        nom::Err::Incomplete(_) => syntax_error(unparsed, ""),
+    })?;
+
+    if !rest.is_empty() {
+        Err(syntax_error(unparsed, rest))?;
+    }
+
+    Ok(items)
+}
+
+// Turns nodes into wmach statements. Every statement points back at the node it came from.
+struct Lowering<'a> {
+    sources: &'a SourceMap,
+    procs: HashMap<&'a str, &'a Proc>,
+
+    statements: Vec<Spanned<Stmt>>,
+    fresh: usize,
+
+    // Where a break goes for each loop we're inside of, innermost last.
+    loops: Vec<LabelId>,
+    // The procedures being inlined right now, innermost last.
+    calls: Vec<&'a str>,
+}
+
+impl<'a> Lowering<'a> {
+    fn emit(&mut self, stmt: Stmt, span: Span) {
+        self.statements.push(Spanned::new(stmt, span));
+    }
+
+    // A prefix for labels nobody else has used. Nobody can write a label in this language, so
+    // they can't clash with anything.
+    fn fresh(&mut self, what: &str) -> LabelId {
+        self.fresh += 1;
+        format!("{}{}", what, self.fresh)
+    }
+
+    fn label(&mut self, label: &str, span: Span) {
+        self.emit(Stmt::Label(label.to_string()), span);
+    }
+
+    fn goto(&mut self, label: &str, span: Span) {
+        let target = Spanned::new(Target::Name(label.to_string()), span);
+        self.emit(Stmt::Jmp(target.clone(), target), span);
+    }
+
+    // Off to `then` if the bit is `bit`, otherwise to `otherwise`.
+    fn branch(&mut self, bit: bool, then: &str, otherwise: &str, span: Span) {
+        let then = Spanned::new(Target::Name(then.to_string()), span);
+        let otherwise = Spanned::new(Target::Name(otherwise.to_string()), span);
+
+        if bit {
+            self.emit(Stmt::Jmp(then, otherwise), span);
+        } else {
+            self.emit(Stmt::Jmp(otherwise, then), span);
+        }
+    }
+
+    fn block(&mut self, nodes: &'a [Spanned<Node>]) -> Result<(), StructuredErr> {
+        for node in nodes {
+            self.node(node)?;
+        }
+
+        Ok(())
+    }
+
+    // The body of a loop, with `end` as the place to break to.
+    fn body(&mut self, nodes: &'a [Spanned<Node>], end: &str) -> Result<(), StructuredErr> {
+        self.loops.push(end.to_string());
+        self.block(nodes)?;
+        self.loops.pop();
+
+        Ok(())
+    }
+
+    fn node(&mut self, node: &'a Spanned<Node>) -> Result<(), StructuredErr> {
+        let span = node.span;
+
+        match &node.node {
+            Node::Primitive(stmt) => self.emit(stmt.clone(), span),
+            Node::If(bit, then, otherwise) => {
+                let prefix = self.fresh("if");
+                let (then_label, else_label) = (format!("{}'then", prefix), format!("{}'else", prefix));
+                let end = format!("{}'end", prefix);
+
+                self.branch(*bit, &then_label, &else_label, span);
+                self.label(&then_label, span);
+                self.block(then)?;
+                self.goto(&end, span);
+                self.label(&else_label, span);
+                self.block(otherwise)?;
+                self.label(&end, span);
+            }
+            Node::While(bit, body) => {
+                let prefix = self.fresh("while");
+                let (body_label, end) = (format!("{}'body", prefix), format!("{}'end", prefix));
+
+                self.label(&prefix, span);
+                self.branch(*bit, &body_label, &end, span);
+                self.label(&body_label, span);
+                self.body(body, &end)?;
+                self.goto(&prefix, span);
+                self.label(&end, span);
+            }
+            Node::Loop(body) => {
+                let prefix = self.fresh("loop");
+                let end = format!("{}'end", prefix);
+
+                self.label(&prefix, span);
+                self.body(body, &end)?;
+                self.goto(&prefix, span);
+                self.label(&end, span);
+            }
+            Node::Break => match self.loops.last().cloned() {
+                Some(end) => self.goto(&end, span),
+                None => Err(StructuredErr::BreakOutsideLoop {
+                    snippet: self.sources.snippet(span),
+                })?,
+            },
+            Node::Call(name) => {
+                let proc = match self.procs.get(name.as_str()) {
+                    Some(proc) => *proc,
+                    None => Err(StructuredErr::UnknownProcedure {
+                        name: name.clone(),
+                        snippet: self.sources.snippet(span),
+                    })?,
+                };
+
+                if self.calls.contains(&name.as_str()) {
+                    Err(StructuredErr::RecursiveProcedure {
+                        name: name.clone(),
+                        snippet: self.sources.snippet(span),
+                    })?;
+                }
+
+                // A break only gets out of loops inside the procedure itself, not whatever loop
+                // it happens to be called from.
+                let loops = std::mem::take(&mut self.loops);
+                self.calls.push(&proc.name.node);
+                self.block(&proc.body)?;
+                self.calls.pop();
+                self.loops = loops;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn translate(source: &str) -> Result<Ast, StructuredErr> {
+    let mut sources = SourceMap::new();
+    sources.add(None, source.to_string());
+
+    let items = parse(source)?;
+
+    let mut procs = HashMap::new();
+    for item in &items {
+        if let Item::Proc(proc) = item {
+            if procs.insert(proc.name.node.as_str(), proc).is_some() {
+                Err(StructuredErr::DuplicateProcedure {
+                    name: proc.name.node.clone(),
+                    snippet: sources.snippet(proc.name.span),
+                })?;
+            }
+        }
+    }
+
+    let statements = {
+        let mut lowering = Lowering {
+            sources: &sources,
+            procs,
+            statements: Vec::new(),
+            fresh: 0,
+            loops: Vec::new(),
+            calls: Vec::new(),
+        };
+
+        for item in &items {
+            if let Item::Node(node) = item {
+                lowering.node(node)?;
+            }
+        }
+
+        lowering.statements
+    };
+
+    Ok(Ast {
+        sources,
+        statements,
+    })
+}
+
+// Straight to the single-step form that the interpreter and the tile compiler both take.
+pub fn program(source: &str) -> Result<Program, StructuredErr> {
+    Ok(translate(source)?.resolve_labels()?.expand_seeks())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::lockstep::Lockstep;
+    use crate::machine::{Machine, Status};
+
+    fn run(source: &str, input: &[u8]) -> Vec<u8> {
+        let program = program(source).expect("should translate fine");
+        let mut output = Vec::new();
+        {
+            let mut machine = Machine::with_io(&program, input, &mut output);
+            let status = machine.run(100_000).expect("should run fine");
+            assert_eq!(status, Status::Halted);
+        }
+
+        output
+    }
+
+    #[test]
+    fn if_else() {
+        // Flip every bit of a byte on the way through.
+        let source = format!("proc flip {{ if bit {{ - }} else {{ + }} }} {}", ", flip() . ".repeat(8));
+        assert_eq!(run(&source, &[0b0000_0101]), &[0b1111_1010]);
+
+        let source = "+ if not bit { halt } else if bit { . . . . . . . . } else { halt }";
+        assert_eq!(run(source, &[]), &[0xff]);
+    }
+
+    #[test]
+    fn loops_and_breaks() {
+        // Write out a run of ones then skip back over them.
+        let source = "
+            + > + > + < <
+            while bit { . > }
+            loop {
+                < if not bit { break }
+                -
+            }
+        ";
+        let program = program(source).expect("should translate fine");
+        let mut machine = Machine::with_io(&program, &[][..], Vec::new());
+        assert_eq!(machine.run(1000).expect("should run fine"), Status::Halted);
+
+        assert_eq!(machine.tape().head(), -1);
+        assert_eq!(machine.tape().bits(), vec![false; 5]);
+    }
+
+    #[test]
+    fn procs_are_inlined() {
+        let source = "
+            proc echo { , . , . , . , . , . , . , . , . }
+            proc twice { echo() echo() }
+            twice() twice()
+        ";
+        let input = [0x12, 0x34, 0x56, 0x78];
+        assert_eq!(run(source, &input), input);
+
+        // Each call gets its own labels.
+        let ast = translate("proc p { loop { break } } p() p()").expect("should translate fine");
+        ast.resolve_labels().expect("should not have duplicate labels");
+    }
+
+    #[test]
+    fn tiles_agree() {
+        let source = "
+            proc skip { while bit { > } }
+            + > + < skip() + < <
+            loop { if bit { - > } else { break } }
+        ";
+        let program = program(source).expect("should translate fine");
+        let mut lockstep = Lockstep::new(&program, &[]).expect("should compile fine");
+
+        let status = lockstep.run(10_000).expect("tiles should agree with the machine");
+        assert_eq!(status, Status::Halted);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        match translate("loop {\n  + jmp\n}") {
+            Err(StructuredErr::SyntaxError { text, snippet }) => {
+                assert_eq!(text, "jmp");
+                assert_eq!(snippet.location().line, 2);
+                assert_eq!(snippet.location().column, 5);
+            }
+            x => panic!("Failed to catch the jmp: {:?}", x),
+        };
+
+        match translate("while bits { }") {
+            Err(StructuredErr::SyntaxError { .. }) => (),
+            x => panic!("Failed to catch the bad condition: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn semantic_errors() {
+        match translate("+ break") {
+            Err(StructuredErr::BreakOutsideLoop { snippet }) => assert_eq!(snippet.location().column, 3),
+            x => panic!("Failed to catch the stray break: {:?}", x),
+        };
+
+        // Breaks don't leak out of procedures.
+        match translate("proc p { break } loop { p() }") {
+            Err(StructuredErr::BreakOutsideLoop { .. }) => (),
+            x => panic!("Failed to catch the break in p: {:?}", x),
+        };
+
+        match translate("nope()") {
+            Err(StructuredErr::UnknownProcedure { name, .. }) => assert_eq!(name, "nope"),
+            x => panic!("Failed to catch the unknown procedure: {:?}", x),
+        };
+
+        match translate("proc p { } proc p { }") {
+            Err(StructuredErr::DuplicateProcedure { name, snippet }) => {
+                assert_eq!(name, "p");
+                assert_eq!(snippet.location().column, 17);
+            }
+            x => panic!("Failed to catch the duplicate: {:?}", x),
+        };
+
+        match translate("proc a { b() } proc b { if bit { a() } } a()") {
+            Err(StructuredErr::RecursiveProcedure { name, .. }) => assert_eq!(name, "a"),
+            x => panic!("Failed to catch the recursion: {:?}", x),
+        };
+    }
+}
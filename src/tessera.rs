@@ -63,16 +63,43 @@ pub enum MosaicError {
     EmptyInitialState,
 }
 
+// Where the tiles are at after a step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Running,
+    // The head is sat on a halt, so the row came out the same as the one before it and every row
+    // from here on will too. step is how many rows it took to get there.
+    Halted { step: usize },
+    // A ! went off while making the row for this step.
+    Breakpoint { step: usize },
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Running => write!(f, "running"),
+            Status::Halted { step } => write!(f, "halted at step {}", step),
+            Status::Breakpoint { step } => write!(f, "breakpoint at step {}", step),
+        }
+    }
+}
+
 #[derive(Debug)]
-pub struct Program {
+pub struct Program<I: std::io::Read = std::io::Stdin, O: std::io::Write = std::io::Stdout> {
     pile: DominoPile,
     border: TileRef,
 
-    io: IoBuffer<std::io::Stdin, std::io::Stdout>,
+    io: IoBuffer<I, O>,
     state: BoardStateRef,
+    steps: usize,
+
+    // The tiles for halt instructions. A row that repeats itself is only halted if the head is on
+    // one of these, since jumping to yourself doesn't change the row either but the machine is
+    // still running. Tile sets that don't have any stop at the first row that repeats.
+    halts: HashSet<TileRef>,
 }
 
-impl std::fmt::Display for Program {
+impl<I: std::io::Read, O: std::io::Write> std::fmt::Display for Program<I, O> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_fmt(format_args!(
             "Program(Code: {}; Border: {}; State: (",
@@ -189,8 +216,31 @@ impl Program {
 
             io: IoBuffer::new(),
             state: state,
+            steps: 0,
+
+            halts: HashSet::new(),
         })
     }
+}
+
+impl<I: std::io::Read, O: std::io::Write> Program<I, O> {
+    // Swap out where the machine's input and output go.
+    pub fn with_io<R: std::io::Read, W: std::io::Write>(self, input: R, output: W) -> Program<R, W> {
+        Program {
+            pile: self.pile,
+            border: self.border,
+
+            io: IoBuffer::with_io(input, output),
+            state: self.state,
+            steps: self.steps,
+
+            halts: self.halts,
+        }
+    }
+
+    pub fn pile(&self) -> &DominoPile {
+        &self.pile
+    }
 
     pub fn border(&self) -> Tile {
         self.pile[self.border]
@@ -208,8 +258,10 @@ impl Program {
             .collect()
     }
 
-    fn perform_io(&mut self, state: BoardStateRef) -> Result<BoardStateRef, MosaicError> {
+    // Also says whether any breakpoints went off along the way.
+    fn perform_io(&mut self, state: BoardStateRef) -> Result<(BoardStateRef, bool), MosaicError> {
         let mut next = Vec::with_capacity(state.len());
+        let mut breakpoint = false;
         for r in state.into_iter() {
             let r = match self.pile.get_side_effects(&r) {
                 SideEffects::Out(bit) => {
@@ -226,23 +278,66 @@ impl Program {
                         alts[0]
                     }
                 }
+                SideEffects::Break => {
+                    breakpoint = true;
+
+                    r
+                }
                 SideEffects::Pure(_) => r,
             };
             next.push(r);
         }
 
-        Ok(next)
+        Ok((next, breakpoint))
+    }
+
+    // The tape as written on the southern pips of the current row, with the head in brackets.
+    pub fn tape(&self) -> String {
+        self.state
+            .iter()
+            .map(|r| self.pile[*r].south)
+            .filter(|pip| *pip != UNALLOCATED_PIP)
+            .map(|pip| match pip {
+                ZERO_PIP => "0".to_string(),
+                ONE_PIP => "1".to_string(),
+                // See pip_from_components: the low bit is what's under the head.
+                head => format!("[{}]", head & 1),
+            })
+            .collect()
+    }
+
+    // How many rows have been computed past the initial one.
+    pub fn steps(&self) -> usize {
+        self.steps
     }
 
     // evolve current state to next state
-    pub fn step(&mut self) -> Result<(), MosaicError> {
+    pub fn step(&mut self) -> Result<Status, MosaicError> {
         let next = Row::new(&self.pile, &self.border, &self.state)?.to_vec()?;
-        let next = self.perform_io(next)?;
+        let (next, breakpoint) = self.perform_io(next)?;
+
+        // The southern pips are all the next row gets to see, so once they come out the same as
+        // last time nothing is ever going to change again. There's no point in counting the row.
+        let souths = |state: &BoardStateRef| -> Vec<Pip> {
+            state.iter().map(|r| self.pile[*r].south).collect()
+        };
+        let halted = self.halts.is_empty() || next.iter().any(|r| self.halts.contains(r));
+        if halted && souths(&next) == souths(&self.state) {
+            return Ok(Status::Halted { step: self.steps });
+        }
 
         self.state = next;
+        self.steps += 1;
 
-        Ok(())
+        if breakpoint {
+            Ok(Status::Breakpoint { step: self.steps })
+        } else {
+            Ok(Status::Running)
+        }
     }
+}
+
+impl Program {
 
     fn mk_write(position: usize, value: &wmach::WriteOp) -> Vec<Domino> {
         let mut set = Vec::new();
@@ -267,27 +362,35 @@ impl Program {
         set.into_iter().map(Domino::pure).collect()
     }
 
-    fn mk_seek(position: usize, direction: &wmach::SeekOp) -> Vec<Domino> {
+    // The head tile hands a countdown to its neighbour, which hands one less to the next, and so
+    // on until the tile that gets 1 picks the head up. That way the head can cross any number of
+    // cells in a single row:
+    //
+    //   > by 3:  [head] -3-> [bit] -2-> [bit] -1-> [head]
+    //
+    // binds has the pip for each step of the countdown, for 1 first. These must be UNIQUE to the
+    // instruction in order to rule out annoying matching problems. The last step uses the offset
+    // of the instruction, which also makes a seek by one cell exactly what it always was. The idea
+    // here is that position MAY be "1" which we treat as a magic number. This shouldn't ever occur
+    // in an east/west pip. This may change later but I hope not. Also, YOLO.
+    fn mk_seek(position: usize, direction: &wmach::SeekOp, binds: &[Pip]) -> Vec<Domino> {
         let mut set = Vec::new();
-        // This must be UNIQUE per instruction in order to rule out annoying
-        // matching problems. We'll simply use the offset for this instruction
-        // and be done with it. The idea here is that position MAY be "1" which
-        // we treat as a magic number. This shouldn't ever occur in an
-        // east/west pip. This may change later but I hope not. Also, YOLO.
-        // TODO Make this into a variable that we increment after each use to
-        // ensure we never re-use a value.
-        let bind: Pip = position;
-        assert!(bind > 0);
+        assert!(binds.iter().all(|bind| *bind > 0));
+        assert_eq!(binds.first(), Some(&position));
+
+        // The (east, west) pips of a tile that a signal arrives at on one side and leaves by the
+        // other.
+        let passing = |arriving: Pip, leaving: Pip| match direction {
+            wmach::SeekOp::Left => (arriving, leaving),
+            wmach::SeekOp::Right => (leaving, arriving),
+        };
 
         // Entry point tiles.
         {
             let north_0 = pip_from_components(position, 0);
             let north_1 = pip_from_components(position, 1);
 
-            let (east, west) = match direction {
-                wmach::SeekOp::Left => (EMPTY_PIP, bind),
-                wmach::SeekOp::Right => (bind, EMPTY_PIP),
-            };
+            let (east, west) = passing(EMPTY_PIP, binds[binds.len() - 1]);
 
             let south_0 = ZERO_PIP;
             let south_1 = ONE_PIP;
@@ -299,16 +402,27 @@ impl Program {
             set.push(tile_1);
         }
 
+        // Cells along the way leave their bit alone. Cells of the void become a 0 on the tape.
+        for step in (1..binds.len()).rev() {
+            let (east, west) = passing(binds[step], binds[step - 1]);
+
+            let tile_0 = Tile::new(ZERO_PIP, east, ZERO_PIP, west);
+            set.push(tile_0);
+
+            let tile_1 = Tile::new(ONE_PIP, east, ONE_PIP, west);
+            set.push(tile_1);
+
+            let tile_u = Tile::new(UNALLOCATED_PIP, east, ZERO_PIP, west);
+            set.push(tile_u);
+        }
+
         // Next, bound, tile.
         {
             let north_0 = ZERO_PIP;
             let north_1 = ONE_PIP;
             let north_u = UNALLOCATED_PIP;
 
-            let (east, west) = match direction {
-                wmach::SeekOp::Left => (bind, EMPTY_PIP),
-                wmach::SeekOp::Right => (EMPTY_PIP, bind),
-            };
+            let (east, west) = passing(binds[0], EMPTY_PIP);
 
             let south_0 = pip_from_components(position + 1, 0);
             let south_1 = pip_from_components(position + 1, 1);
@@ -367,6 +481,67 @@ impl Program {
         set
     }
 
+    // Like a write that leaves the bit alone, except that someone gets told about it.
+    fn mk_debug(position: usize) -> Vec<Domino> {
+        let mut set = Vec::new();
+
+        let east = EMPTY_PIP;
+        let west = EMPTY_PIP;
+
+        for bit in 0..2 {
+            let north = pip_from_components(position, bit);
+            let south = pip_from_components(position + 1, bit);
+
+            let tile = Tile::new(north, east, south, west);
+            set.push(tile);
+        }
+
+        set.into_iter().map(Domino::breakpoint).collect()
+    }
+
+    // The head just sits there, keeping whatever bit it is on.
+    fn mk_halt(position: usize) -> Vec<Domino> {
+        let mut set = Vec::new();
+
+        let east = EMPTY_PIP;
+        let west = EMPTY_PIP;
+
+        for bit in 0..2 {
+            let pip = pip_from_components(position, bit);
+
+            let tile = Tile::new(pip, east, pip, west);
+            set.push(tile);
+        }
+
+        set.into_iter().map(Domino::pure).collect()
+    }
+
+    // fresh is the next pip nothing has used yet, for seeks to count down with.
+    fn mk_insn(position: usize, insn: &wmach::Insn, fresh: &mut Pip) -> Vec<Domino> {
+        match insn {
+            wmach::Insn::Write(value) => Program::mk_write(position, value),
+            // Going nowhere is the same as going on to the next instruction.
+            wmach::Insn::Seek(_, 0) => {
+                let next = position + 1 - BASE_OFFSET;
+                Program::mk_jmp(position, &next, &next)
+            }
+            wmach::Insn::Seek(direction, count) => {
+                let mut binds = vec![position];
+                for _ in 1..*count {
+                    binds.push(*fresh);
+                    *fresh += 1;
+                }
+                assert!(*fresh < UNALLOCATED_PIP);
+
+                Program::mk_seek(position, direction, &binds)
+            }
+            wmach::Insn::Io(rw) => Program::mk_io(position, rw),
+            wmach::Insn::Jmp(branch_t, branch_f) => Program::mk_jmp(position, branch_t, branch_f),
+            wmach::Insn::Debug => Program::mk_debug(position),
+            wmach::Insn::Halt => Program::mk_halt(position),
+        }
+    }
+
     fn mk_jmp(position: usize, br_t: &wmach::InsnOffset, br_f: &wmach::InsnOffset) -> Vec<Domino> {
         let mut set = Vec::new();
 
@@ -390,14 +565,24 @@ impl Program {
 }
 
 // This means we only get a single row to setup the environment
-const BASE_OFFSET: usize = 1;
+pub const BASE_OFFSET: usize = 1;
+
+// How many dominoes an instruction adds to the set.
+pub fn tile_count(insn: &wmach::Insn) -> usize {
+    Program::mk_insn(BASE_OFFSET, insn, &mut (BASE_OFFSET + 1)).len()
+}
+
+// Nothing in here can be blamed on one instruction in particular.
+impl compiler::Located for MosaicError {}
 
 // This is our compiler from w-machine to wang tiles.
-impl compiler::Backend<Program> for wmach::Program {
+pub struct Tiles;
+
+impl compiler::Backend for Tiles {
     type Target = Program;
     type Error = MosaicError;
 
-    fn compile(&self) -> Result<Self::Target, Self::Error> {
+    fn compile(&self, program: &wmach::Program) -> Result<Self::Target, Self::Error> {
         let mut set: Vec<Tile> = Vec::new();
 
         // Void Wranglers
@@ -441,24 +626,52 @@ impl compiler::Backend<Program> for wmach::Program {
         let initial_east = Tile::new(UNALLOCATED_PIP, UNALLOCATED_PIP, EMPTY_PIP, unique_magic);
         set.push(initial_east);
 
+        // Whatever the program put on the tape goes either side of the head, in tiles like the
+        // initial one that hand the next row a plain bit instead. With no data at all this is
+        // just the initial tile on its own.
+        let data = &program.data;
+        let mut first_row = vec![initial_west];
+        for i in 0..std::cmp::max(data.bits.len(), data.head + 1) {
+            let bit = data.bits.get(i).cloned().unwrap_or(false);
+            let south = if i == data.head {
+                pip_from_components(BASE_OFFSET, bit as usize)
+            } else if bit {
+                ONE_PIP
+            } else {
+                ZERO_PIP
+            };
+
+            // Every 0 (and a head on a 0, which is the initial tile) is the same tile.
+            let tile = Tile::new(UNALLOCATED_PIP, unique_magic, south, unique_magic);
+            if !set.contains(&tile) {
+                set.push(tile);
+            }
+            first_row.push(tile);
+        }
+        first_row.push(initial_east);
+
         // Convert the pure tiles into dominoes.
         let mut set: Vec<Domino> = set.into_iter().map(Domino::pure).collect();
 
-        for (i, insn) in self.instructions.iter().enumerate() {
-            let i = i + BASE_OFFSET;
-            let mut translated = match insn {
-                wmach::Insn::Write(value) => Program::mk_write(i, value),
-                wmach::Insn::Seek(direction) => Program::mk_seek(i, &direction),
-                wmach::Insn::Io(rw) => Program::mk_io(i, &rw),
-                wmach::Insn::Jmp(branch_t, branch_f) => Program::mk_jmp(i, &branch_t, &branch_f),
-                wmach::Insn::Debug => {
-                    todo!("debug: {:?}", insn);
-                }
-            };
-
-            set.append(&mut translated);
+        // Seeks go as far as they say in a single row. The pips they count down with come after
+        // every instruction's position, which mk_seek has already claimed.
+        let end = program.instructions.len() + BASE_OFFSET;
+        let mut fresh = end + 1;
+        let mut halts = Vec::new();
+        for (i, insn) in program.instructions.iter().enumerate() {
+            let mut dominoes = Program::mk_insn(i + BASE_OFFSET, insn, &mut fresh);
+            if *insn == wmach::Insn::Halt {
+                halts.extend(dominoes.iter().map(|domino| domino.tile));
+            }
+            set.append(&mut dominoes);
         }
 
+        // Falling off the end (or jumping there) halts as well, rather than leaving the head with
+        // no tiles to go on.
+        let mut dominoes = Program::mk_halt(end);
+        halts.extend(dominoes.iter().map(|domino| domino.tile));
+        set.append(&mut dominoes);
+
         //
         // The starting configuration for our tape lays just enough ground work to ensure
         // subsequent matches line up in a way commensurate with the w-machine we're emulating.
@@ -478,12 +691,14 @@ impl compiler::Backend<Program> for wmach::Program {
         //  0 = EMPTY_PIP
         //  S = start_pip
         //
+        // with any data the program has on either side of the middle tile, and the bit under the
+        // head in S.
+        //
 
-        Program::new(
-            set.into_iter().collect(),
-            border,
-            vec![initial_west, initial, initial_east],
-        )
+        let mut tiles = Program::new(set.into_iter().collect(), border, first_row)?;
+        tiles.halts = halts.iter().filter_map(|tile| tiles.pile.get(tile).copied()).collect();
+
+        Ok(tiles)
     }
 }
 
@@ -491,6 +706,11 @@ impl compiler::Backend<Program> for wmach::Program {
 mod tests {
     use super::*;
 
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    use crate::compiler::Backend;
+
     #[test]
     fn set_and_shift_program() {
         let border = Tile::new(0, 0, 0, 0);
@@ -522,6 +742,101 @@ mod tests {
         assert_eq!(state, board.state);
     }
 
+    #[test]
+    fn breakpoints_are_reported() {
+        let program = wmach::Program::from_str("+ ! > !").expect("should parse fine");
+        let mut board = Tiles.compile(&program).expect("should compile fine");
+
+        let statuses: Vec<Status> = (0..4)
+            .map(|_| board.step().expect("should step successfully"))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                Status::Running,
+                Status::Breakpoint { step: 2 },
+                Status::Running,
+                Status::Breakpoint { step: 4 },
+            ]
+        );
+        // The row starts a cell west of where the head started.
+        assert_eq!(board.tape(), "01[0]");
+    }
+
+    #[test]
+    fn data_goes_in_the_first_row() {
+        let program = wmach::Program::from_str("data bits 101 at 1 - < +").expect("should parse fine");
+        let mut board = Tiles.compile(&program).expect("should compile fine");
+        assert_eq!(board.tape(), "01[0]10");
+
+        for _ in 0..3 {
+            board.step().expect("should step successfully");
+        }
+        assert_eq!(board.tape(), "0[1]010");
+    }
+
+    #[test]
+    fn data_tiles_go_in_once() {
+        let program = wmach::Program::from_str("data bits 0110100 at 3 +").expect("should parse fine");
+        let board = Tiles.compile(&program).expect("should compile fine");
+        assert_eq!(board.tape(), "0011[0]1000");
+
+        let tiles: Vec<Tile> = board.pile().tiles().map(|(_, tile)| tile).collect();
+        let unique: HashSet<Tile> = tiles.iter().cloned().collect();
+        assert_eq!(tiles.len(), unique.len());
+
+        // The one and the zero go in alongside the border, the initial tile and its neighbours.
+        let bare = wmach::Program::from_str("+").expect("should parse fine");
+        assert_eq!(Tiles.compile(&bare).expect("should compile fine").pile().tiles().count() + 2, tiles.len());
+    }
+
+    #[test]
+    fn seeks_take_a_single_row() {
+        let ast = wmach::Ast::from_str("data bits 1 >4 + <8 +").expect("should parse fine");
+        let program = ast.resolve_labels().expect("should resolve fine");
+        let mut board = Tiles.compile(&program).expect("should compile fine");
+        assert_eq!(board.tape(), "0[1]0");
+
+        // Off the east end of the tape and then all the way past the west end.
+        let tapes: Vec<String> = (0..4)
+            .map(|_| {
+                board.step().expect("should step successfully");
+                board.tape()
+            })
+            .collect();
+        assert_eq!(tapes, vec!["01000[0]", "01000[1]", "[0]00010001", "[1]00010001"]);
+    }
+
+    #[test]
+    fn long_seeks_take_no_time() {
+        // Growing the row a tile at a time took minutes for these.
+        let expected = [
+            ("data bits 1 >3000 +", format!("01{}[1]", "0".repeat(2999))),
+            ("data bits 1 <3000 +", format!("[1]{}10", "0".repeat(2999))),
+        ];
+        for (source, tape) in expected {
+            let ast = wmach::Ast::from_str(source).expect("should parse fine");
+            let program = ast.resolve_labels().expect("should resolve fine");
+            let mut board = Tiles.compile(&program).expect("should compile fine");
+
+            let started = Instant::now();
+            board.step().expect("should step successfully");
+            board.step().expect("should step successfully");
+            assert!(started.elapsed() < Duration::from_secs(5), "{} took {:?}", source, started.elapsed());
+            assert_eq!(board.tape(), tape);
+        }
+    }
+
+    #[test]
+    fn tile_counts() {
+        let program = wmach::Program::from_str(">>> + jmp end end:").expect("should parse fine");
+        let counts: Vec<usize> = program.instructions.iter().map(tile_count).collect();
+        assert_eq!(counts, vec![5, 5, 5, 2, 2]);
+
+        // Two to start, three for each of the two cells along the way and three to finish.
+        assert_eq!(tile_count(&wmach::Insn::Seek(wmach::SeekOp::Left, 3)), 11);
+    }
+
     #[test]
     fn check_empty_state() {
         let border = Tile::new(0, 0, 0, 0);
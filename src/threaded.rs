@@ -0,0 +1,592 @@
+use std::convert::Infallible;
+
+use crate::compiler;
+use crate::io_buffer::IoBuffer;
+use crate::machine::{MachineError, Status};
+use crate::wmach;
+
+// The fast way to run a w-machine program, for when the tiles and even machine.rs are too slow.
+//
+// Code is cut up into basic blocks and each block becomes a chain of closures, one per primitive,
+// ending in one that says where to go next. Runs of seeks are added up into a single move (so
+// > > < > is one closure that moves 2) and ! does nothing here so it disappears altogether. The
+// steps still count every instruction the block stood in for.
+//
+// Blocks end at every jump, every bit of IO and every halt, and right before anything a jump can
+// land on. They also end after MAX_CHAIN instructions so a long straight line of code doesn't turn
+// into closures nested deep enough to blow the stack.
+const MAX_CHAIN: usize = 64;
+
+const WORD_BITS: usize = u64::BITS as usize;
+
+// An unbounded, bidirectional tape of bits like machine::Tape, except packed 64 to a word. The
+// word under the head is kept out of the vector (like Memory.c's CacheState) and only written back
+// when the head moves off it, so most reads and writes never touch memory.
+#[derive(Debug, Clone)]
+pub struct Tape {
+    words: Vec<u64>,
+    // Bit indices into words of position 0 and of the head.
+    origin: usize,
+    head: usize,
+    // words[head / WORD_BITS] as it really is.
+    word: u64,
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tape {
+    pub fn new() -> Self {
+        Tape::with_data(&wmach::Data::default())
+    }
+
+    // Starts out holding data, with position 0 wherever its head is.
+    pub fn with_data(data: &wmach::Data) -> Self {
+        let mut words = vec![0; data.bits.len() / WORD_BITS + 1];
+        for (index, bit) in data.bits.iter().enumerate() {
+            words[index / WORD_BITS] |= (*bit as u64) << (index % WORD_BITS);
+        }
+
+        Tape {
+            word: words[data.head / WORD_BITS],
+            words,
+            origin: data.head,
+            head: data.head,
+        }
+    }
+
+    pub fn read(&self) -> bool {
+        (self.word >> (self.head % WORD_BITS)) & 1 != 0
+    }
+
+    pub fn write(&mut self, bit: bool) {
+        let mask = 1 << (self.head % WORD_BITS);
+        if bit {
+            self.word |= mask;
+        } else {
+            self.word &= !mask;
+        }
+    }
+
+    // Negative is to the left.
+    pub fn seek(&mut self, distance: isize) {
+        let index = self.head / WORD_BITS;
+        let head = self.head as isize + distance;
+
+        if head >= 0 && (head as usize) / WORD_BITS == index {
+            self.head = head as usize;
+            return;
+        }
+
+        self.words[index] = self.word;
+        let head = if head < 0 {
+            // Growing by at least as much as we have keeps this from happening too often.
+            let needed = (-head) as usize / WORD_BITS + 1;
+            let grow = needed.max(self.words.len());
+            self.words.splice(0..0, std::iter::repeat_n(0, grow));
+            self.origin += grow * WORD_BITS;
+            (head + (grow * WORD_BITS) as isize) as usize
+        } else {
+            let needed = head as usize / WORD_BITS + 1;
+            if needed > self.words.len() {
+                let grow = needed.max(self.words.len() * 2);
+                self.words.resize(grow, 0);
+            }
+            head as usize
+        };
+
+        self.head = head;
+        self.word = self.words[head / WORD_BITS];
+    }
+
+    pub fn head(&self) -> isize {
+        self.head as isize - self.origin as isize
+    }
+
+    pub fn get(&self, position: isize) -> bool {
+        let index = position + self.origin as isize;
+        if index < 0 {
+            return false;
+        }
+
+        let index = index as usize;
+        let word = if index / WORD_BITS == self.head / WORD_BITS {
+            self.word
+        } else {
+            self.words.get(index / WORD_BITS).cloned().unwrap_or(0)
+        };
+
+        (word >> (index % WORD_BITS)) & 1 != 0
+    }
+}
+
+// Where the next block is, or the instruction we halted on when there isn't one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Block(usize),
+    Halt(wmach::InsnOffset),
+}
+
+// What the runner has to do once a block's closures are done with the tape. IO is left to the
+// runner so the program doesn't have to know what it's talking to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Exit {
+    Goto(Target),
+    Input(Target),
+    Output(Target),
+}
+
+type Link = Box<dyn Fn(&mut Tape) -> Exit>;
+
+enum Prim {
+    Write(bool),
+    Seek(isize),
+}
+
+struct Block {
+    start: wmach::InsnOffset,
+    // How many instructions running the block counts as.
+    steps: usize,
+    run: Link,
+}
+
+pub struct Program {
+    blocks: Vec<Block>,
+    data: wmach::Data,
+}
+
+impl std::fmt::Debug for Program {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let starts: Vec<_> = self.blocks.iter().map(|block| block.start).collect();
+        f.debug_struct("Program").field("blocks", &starts).field("data", &self.data).finish()
+    }
+}
+
+impl Program {
+    pub fn blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn entry(&self) -> Target {
+        if self.blocks.is_empty() {
+            Target::Halt(0)
+        } else {
+            Target::Block(0)
+        }
+    }
+}
+
+fn chain(prims: Vec<Prim>, last: Link) -> Link {
+    prims.into_iter().rev().fold(last, |next, prim| match prim {
+        Prim::Write(bit) => Box::new(move |tape: &mut Tape| {
+            tape.write(bit);
+            next(tape)
+        }),
+        Prim::Seek(distance) => Box::new(move |tape: &mut Tape| {
+            tape.seek(distance);
+            next(tape)
+        }),
+    })
+}
+
+pub struct Threaded;
+
+impl compiler::Backend for Threaded {
+    type Target = Program;
+    type Error = Infallible;
+
+    fn compile(&self, program: &wmach::Program) -> Result<Self::Target, Self::Error> {
+        let code = &program.instructions;
+
+        let mut leaders = vec![false; code.len()];
+        if let Some(first) = leaders.first_mut() {
+            *first = true;
+        }
+        for (position, insn) in code.iter().enumerate() {
+            let targets = match insn {
+                wmach::Insn::Jmp(branch_t, branch_f) => vec![*branch_t, *branch_f, position + 1],
+                wmach::Insn::Io(_) | wmach::Insn::Halt => vec![position + 1],
+                _ => vec![],
+            };
+            for target in targets.into_iter().filter(|target| *target < code.len()) {
+                leaders[target] = true;
+            }
+        }
+        let mut length = 0;
+        for leader in leaders.iter_mut() {
+            if *leader || length == MAX_CHAIN {
+                *leader = true;
+                length = 0;
+            }
+            length += 1;
+        }
+
+        let mut index = vec![None; code.len()];
+        for (block, (position, _)) in leaders.iter().enumerate().filter(|(_, leader)| **leader).enumerate() {
+            index[position] = Some(block);
+        }
+        // Anywhere outside the code halts, just like it does for the machine.
+        let target = |position: wmach::InsnOffset| match index.get(position) {
+            Some(Some(block)) => Target::Block(*block),
+            _ => Target::Halt(position),
+        };
+
+        let mut blocks = Vec::new();
+        for start in (0..code.len()).filter(|position| leaders[*position]) {
+            let mut prims = Vec::new();
+            let mut distance = 0;
+            let mut position = start;
+            let mut steps = 0;
+            let last: Link = loop {
+                let next = target(position + 1);
+                match &code[position] {
+                    // The halt itself isn't a step.
+                    wmach::Insn::Halt => {
+                        let halt = Target::Halt(position);
+                        break Box::new(move |_: &mut Tape| Exit::Goto(halt));
+                    }
+                    _ => steps += 1,
+                }
+                match &code[position] {
+                    wmach::Insn::Write(value) => {
+                        if distance != 0 {
+                            prims.push(Prim::Seek(distance));
+                            distance = 0;
+                        }
+                        prims.push(Prim::Write(*value == wmach::WriteOp::Set));
+                    }
+                    wmach::Insn::Seek(wmach::SeekOp::Left, count) => distance -= *count as isize,
+                    wmach::Insn::Seek(wmach::SeekOp::Right, count) => distance += *count as isize,
+                    wmach::Insn::Debug | wmach::Insn::Halt => (),
+                    wmach::Insn::Io(wmach::IoOp::In) => break Box::new(move |_: &mut Tape| Exit::Input(next)),
+                    wmach::Insn::Io(wmach::IoOp::Out) => break Box::new(move |_: &mut Tape| Exit::Output(next)),
+                    wmach::Insn::Jmp(branch_t, branch_f) => {
+                        let (branch_t, branch_f) = (target(*branch_t), target(*branch_f));
+                        break Box::new(move |tape: &mut Tape| {
+                            Exit::Goto(if tape.read() { branch_t } else { branch_f })
+                        });
+                    }
+                }
+
+                if matches!(next, Target::Block(_)) || position + 1 == code.len() {
+                    break Box::new(move |_: &mut Tape| Exit::Goto(next));
+                }
+                position += 1;
+            };
+            if distance != 0 {
+                prims.push(Prim::Seek(distance));
+            }
+
+            blocks.push(Block {
+                start,
+                steps,
+                run: chain(prims, last),
+            });
+        }
+
+        Ok(Program {
+            blocks,
+            data: program.data.clone(),
+        })
+    }
+}
+
+// Runs a threaded::Program the way machine::Machine runs a wmach::Program. It only stops between
+// blocks, so run can go a few steps past the limit it was given.
+pub struct Runner<'a, I: std::io::Read, O: std::io::Write> {
+    program: &'a Program,
+    at: Target,
+    tape: Tape,
+    steps: usize,
+
+    io: IoBuffer<I, O>,
+    // The IO the last block ended in, until it's been done. The steps don't count it yet.
+    waiting: Option<Exit>,
+}
+
+impl<'a> Runner<'a, std::io::Stdin, std::io::Stdout> {
+    pub fn new(program: &'a Program) -> Self {
+        Runner::with_io(program, std::io::stdin(), std::io::stdout())
+    }
+}
+
+impl<'a, I: std::io::Read, O: std::io::Write> Runner<'a, I, O> {
+    pub fn with_io(program: &'a Program, input: I, output: O) -> Self {
+        Runner {
+            program,
+            at: program.entry(),
+            tape: Tape::with_data(&program.data),
+            steps: 0,
+
+            io: IoBuffer::with_io(input, output),
+            waiting: None,
+        }
+    }
+
+    // The instruction the runner is going to carry on from.
+    pub fn pc(&self) -> wmach::InsnOffset {
+        // IO always ends a block, so it's right before wherever the block goes next.
+        let (at, back) = match self.waiting {
+            Some(Exit::Input(next)) | Some(Exit::Output(next)) => (next, 1),
+            _ => (self.at, 0),
+        };
+
+        let position = match at {
+            Target::Block(block) => self.program.blocks[block].start,
+            Target::Halt(position) => position,
+        };
+        position - back
+    }
+
+    pub fn tape(&self) -> &Tape {
+        &self.tape
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn status(&self) -> Status {
+        match self.at {
+            Target::Block(_) => Status::Running,
+            Target::Halt(_) => Status::Halted,
+        }
+    }
+
+    // Step until the program halts or at least max_steps instructions have executed. An IO error
+    // leaves the runner on the IO that failed, like it does the machine, and running again starts
+    // by trying it again.
+    pub fn run(&mut self, max_steps: usize) -> Result<Status, MachineError> {
+        self.finish()?;
+
+        while self.steps < max_steps {
+            let block = match self.at {
+                Target::Block(block) => &self.program.blocks[block],
+                Target::Halt(_) => break,
+            };
+
+            match (block.run)(&mut self.tape) {
+                Exit::Goto(next) => {
+                    self.at = next;
+                    self.steps += block.steps;
+                }
+                io => {
+                    self.waiting = Some(io);
+                    self.steps += block.steps - 1;
+                    self.finish()?;
+                }
+            }
+        }
+
+        Ok(self.status())
+    }
+
+    // Does the IO the runner is waiting on, if there is any, and moves on past it.
+    fn finish(&mut self) -> Result<(), MachineError> {
+        let next = match self.waiting {
+            Some(Exit::Input(next)) => {
+                let bit = self.io.get()?;
+                self.tape.write(bit);
+                next
+            }
+            Some(Exit::Output(next)) => {
+                self.io.put(self.tape.read())?;
+                next
+            }
+            _ => return Ok(()),
+        };
+
+        self.waiting = None;
+        self.at = next;
+        self.steps += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    use crate::compiler::Backend;
+    use crate::machine::Machine;
+    use crate::tessera;
+
+    fn compile(program: &wmach::Program) -> Program {
+        match Threaded.compile(program) {
+            Ok(program) => program,
+            Err(never) => match never {},
+        }
+    }
+
+    // Both the single-step program from_str gives and the run-length one from resolve_labels
+    // should end up exactly where the machine does.
+    fn agrees(src: &str, input: &[u8]) {
+        let run_length = wmach::Ast::from_str(src)
+            .expect("should parse fine")
+            .resolve_labels()
+            .expect("labels should resolve");
+        let single_step = wmach::Program::from_str(src).expect("should parse fine");
+
+        for program in [run_length, single_step].iter() {
+            let mut expected = Vec::new();
+            let mut machine = Machine::with_io(program, input, &mut expected);
+            let status = machine.run(100_000).expect("should run fine");
+            assert_eq!(status, Status::Halted, "{} should halt", src);
+
+            let threaded = compile(program);
+            let mut output = Vec::new();
+            let mut runner = Runner::with_io(&threaded, input, &mut output);
+            assert_eq!(runner.run(100_000).expect("should run fine"), Status::Halted);
+
+            assert_eq!(runner.steps(), machine.steps(), "{}", src);
+            assert_eq!(runner.pc(), machine.pc(), "{}", src);
+            assert_eq!(runner.tape().head(), machine.tape().head(), "{}", src);
+            let start = machine.tape().start();
+            for position in start - 8..start + machine.tape().bits().len() as isize + 8 {
+                assert_eq!(runner.tape().get(position), machine.tape().get(position), "{} at {}", src, position);
+            }
+            drop(runner);
+            drop(machine);
+            assert_eq!(output, expected, "{}", src);
+        }
+    }
+
+    #[test]
+    fn tape_crosses_words() {
+        let mut tape = Tape::new();
+
+        tape.seek(-70);
+        tape.write(true);
+        assert_eq!(tape.head(), -70);
+        tape.seek(200);
+        tape.write(true);
+        tape.seek(-1);
+        assert!(!tape.read());
+        tape.seek(1);
+        assert!(tape.read());
+
+        assert!(tape.get(-70));
+        assert!(tape.get(130));
+        assert!(!tape.get(0));
+        assert!(!tape.get(-1000));
+        assert!(!tape.get(1000));
+    }
+
+    #[test]
+    fn seeks_are_fused() {
+        let program = wmach::Program::from_str("> > < > + >3 <5 jmp done, done\ndone: halt").expect("should parse fine");
+        let threaded = compile(&program);
+        assert_eq!(threaded.blocks(), 2);
+
+        let mut runner = Runner::with_io(&threaded, &[][..], Vec::new());
+        runner.run(1).expect("should run fine");
+        assert_eq!(runner.steps(), 14);
+        assert_eq!(runner.tape().head(), 0);
+        assert!(runner.tape().get(2));
+    }
+
+    #[test]
+    fn agrees_with_the_machine() {
+        agrees("+ > + > + < <\nscan: jmp right, done\nright: > jmp scan, scan\ndone:", &[]);
+        agrees("+ > halt +", &[]);
+        agrees("data bits 1101 at 2 < - > > +", &[]);
+        agrees("<100 + >200 + <*37 ! + >3 jmp end, end\nend: +", &[]);
+        agrees(",.,.,.,.,.,.,.,.", b"A");
+        agrees("repeat 100 { + > - > }\nback: < jmp out, back\nout: halt", &[]);
+    }
+
+    // Carries on counting in binary forever.
+    const COUNTER: &str = "
+        data bits 10 at 1
+        inc: jmp carry, set
+        carry: - > jmp inc, inc
+        set: +
+        back: < jmp home, back
+        home: > jmp inc, inc
+    ";
+
+    #[test]
+    fn run_is_bounded() {
+        let program = wmach::Program::from_str(COUNTER).expect("should parse fine");
+        let threaded = compile(&program);
+        let mut runner = Runner::with_io(&threaded, &[][..], Vec::new());
+
+        assert_eq!(runner.run(1000).expect("should run fine"), Status::Running);
+        assert!(runner.steps() >= 1000);
+        assert!(runner.steps() < 1000 + MAX_CHAIN);
+    }
+
+    // Fails the first time it's read from and then reads from bytes.
+    struct Flaky<'a> {
+        failed: bool,
+        bytes: &'a [u8],
+    }
+
+    impl std::io::Read for Flaky<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if !self.failed {
+                self.failed = true;
+                return Err(std::io::Error::other("not yet"));
+            }
+            self.bytes.read(buf)
+        }
+    }
+
+    #[test]
+    fn io_errors_can_be_retried() {
+        let program = wmach::Program::from_str("> ,.,.,.,.,.,.,.,.").expect("should parse fine");
+        let threaded = compile(&program);
+        let mut output = Vec::new();
+        let mut runner = Runner::with_io(&threaded, Flaky { failed: false, bytes: b"A" }, &mut output);
+
+        match runner.run(100) {
+            Err(MachineError::Io { source: _ }) => (),
+            x => panic!("The first read should fail: {:?}", x),
+        };
+        // Still on the , and not about to go over the > again.
+        assert_eq!((runner.pc(), runner.steps(), runner.tape().head()), (1, 1, 1));
+
+        assert_eq!(runner.run(100).expect("should run fine"), Status::Halted);
+        assert_eq!((runner.steps(), runner.tape().head()), (17, 1));
+        drop(runner);
+        assert_eq!(output, b"A");
+    }
+
+    // A benchmark rather than a test: cargo test --release steps_per_second -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn steps_per_second() {
+        let program = wmach::Ast::from_str(COUNTER)
+            .expect("should parse fine")
+            .resolve_labels()
+            .expect("labels should resolve");
+        let budget = Duration::from_secs(2);
+
+        let mut tiles = tessera::Tiles.compile(&program).expect("should compile fine");
+        let started = Instant::now();
+        let mut steps = 0;
+        while started.elapsed() < budget {
+            tiles.step().expect("should step fine");
+            steps += 1;
+        }
+        let tile_rate = steps as f64 / started.elapsed().as_secs_f64();
+
+        let threaded = compile(&program);
+        let mut runner = Runner::with_io(&threaded, &[][..], Vec::new());
+        let started = Instant::now();
+        while started.elapsed() < budget {
+            runner.run(runner.steps() + 1_000_000).expect("should run fine");
+        }
+        let threaded_rate = runner.steps() as f64 / started.elapsed().as_secs_f64();
+
+        eprintln!("tessera:  {:>14.0} steps/s", tile_rate);
+        eprintln!("threaded: {:>14.0} steps/s ({:.0}x)", threaded_rate, threaded_rate / tile_rate);
+        assert!(threaded_rate > tile_rate);
+    }
+}
@@ -1,5 +1,6 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt;
 use std::ops::Index;
 use std::ops::Neg;
@@ -128,29 +129,27 @@ pub enum SideEffects<T> {
     Pure(PurityBias),
     In(InputAlts<T>),
     Out(bool),
+    // Nothing happens to the tape, but whoever is driving the tiles wants to know it got here.
+    Break,
 }
 
-impl<T: std::cmp::Eq> Ord for SideEffects<T> {
-    fn cmp(&self, other: &Self) -> Ordering {
+impl<T> SideEffects<T> {
+    // Dominoes are kept in [Out; In; Break; Pure] order.
+    fn rank(&self) -> u8 {
         match self {
-            SideEffects::Out(_) => match other {
-                SideEffects::Out(_) => Ordering::Equal,
-                SideEffects::In(_) => Ordering::Less,
-                SideEffects::Pure(_) => Ordering::Less,
-            },
-            SideEffects::In(_) => match other {
-                SideEffects::Out(_) => Ordering::Greater,
-                SideEffects::In(_) => Ordering::Equal,
-                SideEffects::Pure(_) => Ordering::Less,
-            },
-            SideEffects::Pure(_) => match other {
-                SideEffects::Out(_) => Ordering::Greater,
-                SideEffects::In(_) => Ordering::Greater,
-                SideEffects::Pure(_) => Ordering::Equal,
-            },
+            SideEffects::Out(_) => 0,
+            SideEffects::In(_) => 1,
+            SideEffects::Break => 2,
+            SideEffects::Pure(_) => 3,
         }
     }
 }
+
+impl<T: std::cmp::Eq> Ord for SideEffects<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.rank().cmp(&other.rank())
+    }
+}
 impl<T: std::cmp::Eq> PartialOrd for SideEffects<T> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
@@ -178,6 +177,10 @@ impl<T> SideEffects<T> {
             _ => false,
         }
     }
+
+    pub fn is_breakpoint(&self) -> bool {
+        matches!(self, SideEffects::Break)
+    }
 }
 
 impl<T> std::fmt::Display for SideEffects<T> {
@@ -187,6 +190,7 @@ impl<T> std::fmt::Display for SideEffects<T> {
             SideEffects::Pure(_) => f.write_str("Pure"),
             SideEffects::In(_) => f.write_str("In"),
             SideEffects::Out(_) => f.write_str("Out"),
+            SideEffects::Break => f.write_str("Break"),
         }?;
 
         Ok(())
@@ -220,6 +224,13 @@ impl Domino {
             tile: tile,
         }
     }
+
+    pub fn breakpoint(tile: Tile) -> Domino {
+        Domino {
+            side_effect: SideEffects::Break,
+            tile,
+        }
+    }
 }
 
 impl std::fmt::Display for Domino {
@@ -236,12 +247,13 @@ pub type TileRef = u32;
 // This is the "code" of the domino computer: a collection of tiles!
 #[derive(Debug)]
 pub struct DominoPile {
-    // [Out; In; Pure := [Valid; Hidden]]
+    // [Out; In; Break; Pure := [Valid; Hidden]]
     buffer: Vec<Tile>,
     as_ref: HashMap<Tile, TileRef>,
 
     input: HashMap<TileRef, InputAlts<TileRef>>,
     output: HashMap<TileRef, bool>,
+    breakpoints: HashSet<TileRef>,
 
     impure_watermark: TileRef,
     hidden_watermark: TileRef,
@@ -249,7 +261,7 @@ pub struct DominoPile {
 
 impl DominoPile {
     pub fn new(mut dominoes: Vec<Domino>) -> Self {
-        // Sort dominoes into [Out; In; Pure] order.
+        // Sort dominoes into [Out; In; Break; Pure] order.
         dominoes.sort_unstable_by(|x, y| x.side_effect.cmp(&y.side_effect));
 
         // If a reference is strictly less than watermark then it must be an IO
@@ -272,7 +284,7 @@ impl DominoPile {
         });
 
         // Now buffer contains every Tile that the machine can use. It is also
-        // ordered [Out, In, Pure] so we can quickly check to see what style a
+        // ordered [Out, In, Break, Pure] so we can quickly check to see what style a
         // TileRef refers to. All that is left is to create Input and Output
         // lookup tables.
         let mut buffer: Vec<Tile> = dominoes.iter().map(|domino| domino.tile).clone().collect();
@@ -316,18 +328,30 @@ impl DominoPile {
             .clone()
             .collect();
 
+        let breakpoints: HashSet<TileRef> = dominoes
+            .iter()
+            .filter(|domino| domino.side_effect.is_breakpoint())
+            .map(|domino| as_ref[&domino.tile])
+            .collect();
+
         DominoPile {
             buffer: buffer,
             as_ref: as_ref,
 
             input: input,
             output: output,
+            breakpoints,
 
             impure_watermark: watermark,
             hidden_watermark: hidden_watermark,
         }
     }
 
+    // Every tile that can be placed, i.e. everything but the input alternates.
+    pub fn tiles(&self) -> impl Iterator<Item = (TileRef, Tile)> + '_ {
+        (0..self.hidden_watermark).map(move |r| (r, self.buffer[r as usize]))
+    }
+
     pub fn get(&self, tile: &Tile) -> Option<&TileRef> {
         self.as_ref.get(tile)
     }
@@ -343,6 +367,8 @@ impl DominoPile {
             SideEffects::In(*alts)
         } else if let Some(value) = self.output.get(tile_ref) {
             SideEffects::Out(*value)
+        } else if self.breakpoints.contains(tile_ref) {
+            SideEffects::Break
         } else {
             panic!("This should never happen.");
         }
@@ -542,12 +568,14 @@ mod tile_tests {
                 [Tile::new(255, 255, 255, 255), Tile::new(127, 127, 127, 127)],
             ),
             Domino::output(Tile::new(2, 2, 2, 2), false),
+            Domino::breakpoint(Tile::new(3, 3, 3, 3)),
         ];
 
         #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
         enum LookingFor {
             Out,
             In,
+            Break,
             Pure,
             Hidden,
         }
@@ -567,11 +595,18 @@ mod tile_tests {
                 match pile.get_tile_side_effects(tile) {
                     SideEffects::In(_) => (),
                     SideEffects::Out(_) => panic!("DominoPile::buffer is misordered"),
+                    SideEffects::Break => state = LookingFor::Break,
                     SideEffects::Pure(_) => {
                         state = LookingFor::Pure;
                         ()
                     }
                 };
+            } else if state == LookingFor::Break {
+                match pile.get_tile_side_effects(tile) {
+                    SideEffects::Break => (),
+                    SideEffects::Pure(_) => state = LookingFor::Pure,
+                    _ => panic!("DominoPile::buffer is misordered"),
+                };
             } else if state == LookingFor::Pure {
                 match pile.get_tile_side_effects(tile) {
                     SideEffects::Pure(PurityBias::Nothing) => (),
@@ -0,0 +1,702 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::compiler;
+use crate::wmach;
+
+// Compiles a w-machine program into a standalone WebAssembly module, so the page (or anything else
+// with a wasm engine) can run it at full speed rather than a row of tiles at a time.
+//
+// The module imports its IO a bit at a time and exports:
+//  - run: runs the program until it halts or runs off the end of its instructions.
+//  - memory: the tape, 8 bits to a byte, least significant first.
+//  - head: the bit in memory the head is on, kept up to date whenever run returns.
+//  - origin: the bit in memory that was under the head when the program started.
+//
+// The tape starts in the middle of memory and seeking off either end of it traps.
+//
+// There's no goto in wasm, so instructions a jump can land on start a case of a loop around a
+// br_table. Jumps set $pc to the case they want and branch back to the top of the loop, and
+// everything else falls through into the next case just like it falls through to the next
+// instruction.
+#[derive(Error, Debug)]
+pub enum WasmError {
+    #[error("Instruction {position} seeks {distance} cells, which doesn't fit in an i32.")]
+    SeekTooFar {
+        position: wmach::InsnOffset,
+        distance: usize,
+    },
+
+    #[error("The data has {length} bits with the head {head} along, which doesn't fit in the half of the tape it goes in.")]
+    DataTooBig { length: usize, head: usize },
+}
+
+impl compiler::Located for WasmError {
+    fn position(&self) -> Option<wmach::InsnOffset> {
+        match self {
+            WasmError::SeekTooFar { position, .. } => Some(*position),
+            WasmError::DataTooBig { .. } => None,
+        }
+    }
+}
+
+// How big the tape is, in 64KiB wasm pages.
+pub const TAPE_PAGES: u32 = 16;
+const PAGE_BITS: u32 = 65536 * 8;
+
+// The imports come first in the function index space, then our own functions.
+const GET: u32 = 0;
+const PUT: u32 = 1;
+const DEBUG: u32 = 2;
+const READ: u32 = 3;
+const WRITE: u32 = 4;
+const RUN: u32 = 5;
+
+// Everything is an i32, so a function type is just how many of those it takes and gives.
+const TYPES: [(usize, usize); 5] = [(0, 1), (1, 0), (1, 1), (2, 0), (0, 0)];
+const IMPORTS: [(&str, &str, u32); 3] = [("io", "get", 0), ("io", "put", 1), ("io", "debug", 1)];
+
+const HEAD: u32 = 0;
+const ORIGIN: u32 = 1;
+
+// Just the instructions we use.
+#[derive(Debug, Clone, PartialEq)]
+enum Op {
+    Block,
+    Loop,
+    End,
+    Br(u32),
+    BrTable(Vec<u32>, u32),
+    Call(u32),
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    Load8,
+    Store8,
+    Const(i32),
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Shl,
+    ShrU,
+    // Only makes it into the text.
+    Comment(String),
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Block => write!(f, "block"),
+            Op::Loop => write!(f, "loop"),
+            Op::End => write!(f, "end"),
+            Op::Br(depth) => write!(f, "br {}", depth),
+            Op::BrTable(depths, default) => {
+                write!(f, "br_table")?;
+                for depth in depths.iter().chain(std::iter::once(default)) {
+                    write!(f, " {}", depth)?;
+                }
+                Ok(())
+            }
+            Op::Call(func) => write!(f, "call {}", func),
+            Op::Select => write!(f, "select"),
+            Op::LocalGet(local) => write!(f, "local.get {}", local),
+            Op::LocalSet(local) => write!(f, "local.set {}", local),
+            Op::GlobalGet(global) => write!(f, "global.get {}", global),
+            Op::GlobalSet(global) => write!(f, "global.set {}", global),
+            Op::Load8 => write!(f, "i32.load8_u"),
+            Op::Store8 => write!(f, "i32.store8"),
+            Op::Const(value) => write!(f, "i32.const {}", value),
+            Op::Add => write!(f, "i32.add"),
+            Op::Sub => write!(f, "i32.sub"),
+            Op::And => write!(f, "i32.and"),
+            Op::Or => write!(f, "i32.or"),
+            Op::Xor => write!(f, "i32.xor"),
+            Op::Shl => write!(f, "i32.shl"),
+            Op::ShrU => write!(f, "i32.shr_u"),
+            Op::Comment(text) => write!(f, ";; {}", text),
+        }
+    }
+}
+
+fn unsigned(bytes: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn signed(bytes: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn name(bytes: &mut Vec<u8>, name: &str) {
+    unsigned(bytes, name.len() as u32);
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+impl Op {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        // The blocks never leave anything on the stack, and the only memory we touch is bytes.
+        const EMPTY: u8 = 0x40;
+        const MEMARG: [u8; 2] = [0, 0];
+
+        match self {
+            Op::Block => bytes.extend_from_slice(&[0x02, EMPTY]),
+            Op::Loop => bytes.extend_from_slice(&[0x03, EMPTY]),
+            Op::End => bytes.push(0x0b),
+            Op::Br(depth) => {
+                bytes.push(0x0c);
+                unsigned(bytes, *depth);
+            }
+            Op::BrTable(depths, default) => {
+                bytes.push(0x0e);
+                unsigned(bytes, depths.len() as u32);
+                for depth in depths.iter().chain(std::iter::once(default)) {
+                    unsigned(bytes, *depth);
+                }
+            }
+            Op::Call(func) => {
+                bytes.push(0x10);
+                unsigned(bytes, *func);
+            }
+            Op::Select => bytes.push(0x1b),
+            Op::LocalGet(local) => {
+                bytes.push(0x20);
+                unsigned(bytes, *local);
+            }
+            Op::LocalSet(local) => {
+                bytes.push(0x21);
+                unsigned(bytes, *local);
+            }
+            Op::GlobalGet(global) => {
+                bytes.push(0x23);
+                unsigned(bytes, *global);
+            }
+            Op::GlobalSet(global) => {
+                bytes.push(0x24);
+                unsigned(bytes, *global);
+            }
+            Op::Load8 => {
+                bytes.push(0x2d);
+                bytes.extend_from_slice(&MEMARG);
+            }
+            Op::Store8 => {
+                bytes.push(0x3a);
+                bytes.extend_from_slice(&MEMARG);
+            }
+            Op::Const(value) => {
+                bytes.push(0x41);
+                signed(bytes, *value);
+            }
+            Op::Add => bytes.push(0x6a),
+            Op::Sub => bytes.push(0x6b),
+            Op::And => bytes.push(0x71),
+            Op::Or => bytes.push(0x72),
+            Op::Xor => bytes.push(0x73),
+            Op::Shl => bytes.push(0x74),
+            Op::ShrU => bytes.push(0x76),
+            Op::Comment(_) => (),
+        }
+    }
+}
+
+struct Func {
+    ty: u32,
+    locals: u32,
+    body: Vec<Op>,
+}
+
+// (param $head) (result i32), the bit under $head.
+fn read() -> Func {
+    let body = vec![
+        Op::LocalGet(0),
+        Op::Const(3),
+        Op::ShrU,
+        Op::Load8,
+        Op::LocalGet(0),
+        Op::Const(7),
+        Op::And,
+        Op::ShrU,
+        Op::Const(1),
+        Op::And,
+    ];
+
+    Func { ty: 2, locals: 0, body }
+}
+
+// (param $head $bit), puts the bottom bit of $bit under $head.
+fn write() -> Func {
+    let shift = || vec![Op::LocalGet(0), Op::Const(7), Op::And];
+    let address = || vec![Op::LocalGet(0), Op::Const(3), Op::ShrU];
+
+    let mut body = address();
+    body.extend(address());
+    body.push(Op::Load8);
+    body.push(Op::Const(1));
+    body.extend(shift());
+    body.extend(vec![Op::Shl, Op::Const(-1), Op::Xor, Op::And]);
+    body.extend(vec![Op::LocalGet(1), Op::Const(1), Op::And]);
+    body.extend(shift());
+    body.extend(vec![Op::Shl, Op::Or, Op::Store8]);
+
+    Func { ty: 3, locals: 0, body }
+}
+
+pub struct Module {
+    funcs: Vec<Func>,
+    // Bytes to put at the start of the tape, which is where origin is.
+    data: Vec<u8>,
+    data_offset: u32,
+    head: u32,
+}
+
+impl Module {
+    // The bit in memory that was under the head when the program started.
+    pub fn origin(&self) -> u32 {
+        self.head
+    }
+
+    // The binary form, for WebAssembly.instantiate and friends.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = b"\0asm".to_vec();
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+
+        let mut section = |id: u8, count: usize, contents: Vec<u8>| {
+            let mut vec = Vec::new();
+            unsigned(&mut vec, count as u32);
+            vec.extend(contents);
+
+            bytes.push(id);
+            unsigned(&mut bytes, vec.len() as u32);
+            bytes.extend(vec);
+        };
+
+        let mut types = Vec::new();
+        for (params, results) in TYPES.iter() {
+            types.push(0x60);
+            for count in [params, results] {
+                unsigned(&mut types, *count as u32);
+                types.extend(std::iter::repeat_n(0x7f, *count));
+            }
+        }
+        section(1, TYPES.len(), types);
+
+        let mut imports = Vec::new();
+        for (module, field, ty) in IMPORTS.iter() {
+            name(&mut imports, module);
+            name(&mut imports, field);
+            imports.push(0x00);
+            unsigned(&mut imports, *ty);
+        }
+        section(2, IMPORTS.len(), imports);
+
+        let mut funcs = Vec::new();
+        for func in self.funcs.iter() {
+            unsigned(&mut funcs, func.ty);
+        }
+        section(3, self.funcs.len(), funcs);
+
+        let mut memory = vec![0x00];
+        unsigned(&mut memory, TAPE_PAGES);
+        section(5, 1, memory);
+
+        let mut globals = Vec::new();
+        for mutable in [true, false] {
+            globals.extend_from_slice(&[0x7f, mutable as u8]);
+            Op::Const(self.head as i32).encode(&mut globals);
+            Op::End.encode(&mut globals);
+        }
+        section(6, 2, globals);
+
+        let mut exports = Vec::new();
+        for (export, kind, index) in [("memory", 2, 0), ("run", 0, RUN), ("head", 3, HEAD), ("origin", 3, ORIGIN)] {
+            name(&mut exports, export);
+            exports.push(kind);
+            unsigned(&mut exports, index);
+        }
+        section(7, 4, exports);
+
+        let mut code = Vec::new();
+        for func in self.funcs.iter() {
+            let mut body = Vec::new();
+            if func.locals == 0 {
+                unsigned(&mut body, 0);
+            } else {
+                unsigned(&mut body, 1);
+                unsigned(&mut body, func.locals);
+                body.push(0x7f);
+            }
+            for op in func.body.iter() {
+                op.encode(&mut body);
+            }
+            Op::End.encode(&mut body);
+
+            unsigned(&mut code, body.len() as u32);
+            code.extend(body);
+        }
+        section(10, self.funcs.len(), code);
+
+        if !self.data.is_empty() {
+            let mut data = vec![0x00];
+            Op::Const(self.data_offset as i32).encode(&mut data);
+            Op::End.encode(&mut data);
+            unsigned(&mut data, self.data.len() as u32);
+            data.extend_from_slice(&self.data);
+            section(11, 1, data);
+        }
+
+        bytes
+    }
+}
+
+// The text form, which goes through wat2wasm and friends to the same thing encode gives.
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let i32s = |count: usize| vec!["i32"; count].join(" ");
+
+        writeln!(f, "(module")?;
+        for (index, (params, results)) in TYPES.iter().enumerate() {
+            write!(f, "  (type (;{};) (func", index)?;
+            if *params > 0 {
+                write!(f, " (param {})", i32s(*params))?;
+            }
+            if *results > 0 {
+                write!(f, " (result {})", i32s(*results))?;
+            }
+            writeln!(f, "))")?;
+        }
+        for (index, (module, field, ty)) in IMPORTS.iter().enumerate() {
+            writeln!(f, "  (import \"{}\" \"{}\" (func (;{};) (type {})))", module, field, index, ty)?;
+        }
+        for (index, func) in self.funcs.iter().enumerate() {
+            let (params, results) = TYPES[func.ty as usize];
+            write!(f, "  (func (;{};) (type {})", IMPORTS.len() + index, func.ty)?;
+            if params > 0 {
+                write!(f, " (param {})", i32s(params))?;
+            }
+            if results > 0 {
+                write!(f, " (result {})", i32s(results))?;
+            }
+            writeln!(f)?;
+            if func.locals > 0 {
+                writeln!(f, "    (local {})", i32s(func.locals as usize))?;
+            }
+            for op in func.body.iter() {
+                writeln!(f, "    {}", op)?;
+            }
+            writeln!(f, "  )")?;
+        }
+        writeln!(f, "  (memory (;0;) {})", TAPE_PAGES)?;
+        writeln!(f, "  (global (;{};) (mut i32) (i32.const {}))", HEAD, self.head)?;
+        writeln!(f, "  (global (;{};) i32 (i32.const {}))", ORIGIN, self.head)?;
+        writeln!(f, "  (export \"memory\" (memory 0))")?;
+        writeln!(f, "  (export \"run\" (func {}))", RUN)?;
+        writeln!(f, "  (export \"head\" (global {}))", HEAD)?;
+        writeln!(f, "  (export \"origin\" (global {}))", ORIGIN)?;
+        if !self.data.is_empty() {
+            write!(f, "  (data (;0;) (i32.const {}) \"", self.data_offset)?;
+            for byte in self.data.iter() {
+                write!(f, "\\{:02x}", byte)?;
+            }
+            writeln!(f, "\")")?;
+        }
+        writeln!(f, ")")
+    }
+}
+
+pub struct Wasm;
+
+impl compiler::Backend for Wasm {
+    type Target = Module;
+    type Error = WasmError;
+
+    fn compile(&self, program: &wmach::Program) -> Result<Self::Target, Self::Error> {
+        let code = &program.instructions;
+
+        // Every case starts at an instruction something jumps to, apart from the first one which
+        // is there even for an empty program.
+        let mut cases: Vec<wmach::InsnOffset> = vec![0];
+        for insn in code.iter() {
+            if let wmach::Insn::Jmp(branch_t, branch_f) = insn {
+                cases.extend([*branch_t, *branch_f].iter().filter(|target| **target < code.len()));
+            }
+        }
+        cases.sort();
+        cases.dedup();
+        // Anywhere else is off the end of the br_table, which halts.
+        let case = |target: &wmach::InsnOffset| cases.binary_search(target).unwrap_or(cases.len()) as i32;
+
+        // Which labels name each case, for the comments.
+        let mut names: Vec<Vec<&str>> = vec![Vec::new(); cases.len()];
+        for (name, offset) in program.labels.iter() {
+            if let Ok(index) = cases.binary_search(offset) {
+                names[index].push(name);
+            }
+        }
+
+        const PC: u32 = 0;
+        const HEAD_LOCAL: u32 = 1;
+
+        let count = cases.len() as u32;
+        let mut body = vec![Op::GlobalGet(HEAD), Op::LocalSet(HEAD_LOCAL), Op::Block, Op::Loop];
+        body.extend(std::iter::repeat_n(Op::Block, cases.len()));
+        body.push(Op::LocalGet(PC));
+        body.push(Op::BrTable((0..count).collect(), count + 1));
+
+        for (index, start) in cases.iter().enumerate() {
+            let end = cases.get(index + 1).cloned().unwrap_or(code.len());
+            // How far out the loop and the block around it are from this case.
+            let dispatch = count - 1 - index as u32;
+            let exit = dispatch + 1;
+
+            body.push(Op::End);
+            names[index].sort();
+            body.push(Op::Comment(if names[index].is_empty() {
+                format!("{}", start)
+            } else {
+                format!("{}: {}", start, names[index].join(", "))
+            }));
+
+            for (position, insn) in code.iter().enumerate().take(end).skip(*start) {
+                match insn {
+                    wmach::Insn::Write(value) => body.extend(vec![
+                        Op::LocalGet(HEAD_LOCAL),
+                        Op::Const((*value == wmach::WriteOp::Set) as i32),
+                        Op::Call(WRITE),
+                    ]),
+                    wmach::Insn::Seek(direction, distance) => {
+                        let step = i32::try_from(*distance)
+                            .map_err(|_| WasmError::SeekTooFar { position, distance: *distance })?;
+                        body.extend(vec![
+                            Op::LocalGet(HEAD_LOCAL),
+                            Op::Const(step),
+                            if *direction == wmach::SeekOp::Left { Op::Sub } else { Op::Add },
+                            Op::LocalSet(HEAD_LOCAL),
+                        ]);
+                    }
+                    wmach::Insn::Io(wmach::IoOp::In) => {
+                        body.extend(vec![Op::LocalGet(HEAD_LOCAL), Op::Call(GET), Op::Call(WRITE)])
+                    }
+                    wmach::Insn::Io(wmach::IoOp::Out) => {
+                        body.extend(vec![Op::LocalGet(HEAD_LOCAL), Op::Call(READ), Op::Call(PUT)])
+                    }
+                    wmach::Insn::Jmp(branch_t, branch_f) => body.extend(vec![
+                        Op::Const(case(branch_t)),
+                        Op::Const(case(branch_f)),
+                        Op::LocalGet(HEAD_LOCAL),
+                        Op::Call(READ),
+                        Op::Select,
+                        Op::LocalSet(PC),
+                        Op::Br(dispatch),
+                    ]),
+                    // The host gets to see where the head is, which is enough to find the tape.
+                    wmach::Insn::Debug => body.extend(vec![Op::LocalGet(HEAD_LOCAL), Op::Call(DEBUG)]),
+                    wmach::Insn::Halt => body.push(Op::Br(exit)),
+                }
+            }
+        }
+        body.extend(vec![Op::End, Op::End, Op::LocalGet(HEAD_LOCAL), Op::GlobalSet(HEAD)]);
+
+        // The data goes in the middle of the tape, at the start of a byte, so it and its head have
+        // to fit in the half after that.
+        let data_offset = TAPE_PAGES * PAGE_BITS / 2 / 8;
+        let room = (TAPE_PAGES * PAGE_BITS / 2) as usize;
+        let (length, head) = (program.data.bits.len(), program.data.head);
+        if length > room || head >= room {
+            Err(WasmError::DataTooBig { length, head })?;
+        }
+        let mut data = vec![0; program.data.bits.len().div_ceil(8)];
+        for (index, bit) in program.data.bits.iter().enumerate() {
+            data[index / 8] |= (*bit as u8) << (index % 8);
+        }
+
+        Ok(Module {
+            funcs: vec![read(), write(), Func { ty: 4, locals: 2, body }],
+            data,
+            data_offset,
+            head: data_offset * 8 + head as u32,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use crate::compiler::Backend;
+    use crate::machine::{Machine, Status};
+
+    #[derive(Default)]
+    struct Host {
+        input: Vec<bool>,
+        output: Vec<bool>,
+        debugs: Vec<u32>,
+    }
+
+    fn bits(bytes: &[u8]) -> Vec<bool> {
+        bytes.iter().flat_map(|byte| (0..8).map(move |bit| byte & (1 << bit) != 0)).collect()
+    }
+
+    fn bytes(bits: &[bool]) -> Vec<u8> {
+        bits.chunks(8)
+            .filter(|chunk| chunk.len() == 8)
+            .map(|chunk| chunk.iter().enumerate().fold(0, |byte, (bit, set)| byte | ((*set as u8) << bit)))
+            .collect()
+    }
+
+    fn compile(src: &str) -> Module {
+        let program = wmach::Ast::from_str(src)
+            .expect("should parse fine")
+            .resolve_labels()
+            .expect("labels should resolve");
+
+        Wasm.compile(&program).expect("should compile fine")
+    }
+
+    // Runs the module in wasmi and hands back the host along with the memory and head after.
+    fn run(module: &[u8], input: &[u8]) -> (Host, Vec<u8>, i32) {
+        let engine = wasmi::Engine::default();
+        let module = wasmi::Module::new(&engine, module).expect("should validate");
+        let host = Host { input: bits(input).into_iter().rev().collect(), ..Host::default() };
+        let mut store = wasmi::Store::new(&engine, host);
+
+        let mut linker = wasmi::Linker::<Host>::new(&engine);
+        linker
+            .func_wrap("io", "get", |mut caller: wasmi::Caller<'_, Host>| -> i32 {
+                caller.data_mut().input.pop().expect("input should last") as i32
+            })
+            .expect("link");
+        linker
+            .func_wrap("io", "put", |mut caller: wasmi::Caller<'_, Host>, bit: i32| {
+                caller.data_mut().output.push(bit != 0)
+            })
+            .expect("link");
+        linker
+            .func_wrap("io", "debug", |mut caller: wasmi::Caller<'_, Host>, head: i32| {
+                caller.data_mut().debugs.push(head as u32)
+            })
+            .expect("link");
+
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .expect("should instantiate");
+        instance
+            .get_typed_func::<(), ()>(&store, "run")
+            .expect("run is exported")
+            .call(&mut store, ())
+            .expect("should run fine");
+
+        let head = instance.get_global(&store, "head").expect("head is exported").get(&store).i32().expect("i32");
+        let memory = instance.get_memory(&store, "memory").expect("memory is exported").data(&store).to_vec();
+
+        (store.into_data(), memory, head)
+    }
+
+    fn agrees(src: &str, input: &[u8]) {
+        let program = wmach::Ast::from_str(src)
+            .expect("should parse fine")
+            .resolve_labels()
+            .expect("labels should resolve");
+        let mut expected = Vec::new();
+        let mut machine = Machine::with_io(&program, input, &mut expected);
+        assert_eq!(machine.run(100_000).expect("should run fine"), Status::Halted);
+
+        let module = Wasm.compile(&program).expect("should compile fine");
+        let (host, memory, head) = run(&module.encode(), input);
+        let origin = module.origin() as isize;
+
+        assert_eq!(head as isize - origin, machine.tape().head(), "{}", src);
+        let start = machine.tape().start();
+        for position in start..start + machine.tape().bits().len() as isize {
+            let bit = (origin + position) as usize;
+            assert_eq!(memory[bit / 8] & (1 << (bit % 8)) != 0, machine.tape().get(position), "{} at {}", src, position);
+        }
+        drop(machine);
+        assert_eq!(bytes(&host.output), expected, "{}", src);
+    }
+
+    #[test]
+    fn agrees_with_the_machine() {
+        agrees("+ > + > + < <\nscan: jmp right, done\nright: > jmp scan, scan\ndone:", &[]);
+        agrees("+ > halt +", &[]);
+        agrees("data bits 1101 at 2 < - > > +", &[]);
+        agrees("<100 + >200 + <*37 + >3 jmp end, end\nend: +", &[]);
+        agrees(",.,.,.,.,.,.,.,.", b"A");
+        agrees("data \"hi\" repeat 16 { . > }", &[]);
+        agrees("", &[]);
+    }
+
+    #[test]
+    fn debug_sees_the_head() {
+        let module = compile("> ! >3 !");
+        let (host, _, _) = run(&module.encode(), &[]);
+
+        assert_eq!(host.debugs, vec![module.origin() + 1, module.origin() + 4]);
+    }
+
+    #[test]
+    fn text_matches_binary() {
+        let module = compile("data bits 101\nloop: . > jmp loop, done\ndone: , halt");
+
+        let text = module.to_string();
+        assert!(text.contains(";; 0: loop"), "{}", text);
+        assert_eq!(wat::parse_str(&text).expect("should assemble"), module.encode());
+    }
+
+    #[test]
+    fn seek_too_far() {
+        let program = wmach::Program {
+            instructions: vec![wmach::Insn::Seek(wmach::SeekOp::Right, usize::MAX)],
+            labels: wmach::LabelMap::new(),
+            data: wmach::Data::default(),
+        };
+
+        match Wasm.compile(&program) {
+            Err(WasmError::SeekTooFar { position: 0, .. }) => (),
+            result => panic!("expected the seek to be too far: {:?}", result.map(|module| module.to_string())),
+        }
+    }
+
+    #[test]
+    fn data_too_big() {
+        let half = (TAPE_PAGES * PAGE_BITS / 2) as usize;
+        let program = |length: usize, head: usize| wmach::Program {
+            instructions: vec![wmach::Insn::Halt],
+            labels: wmach::LabelMap::new(),
+            data: wmach::Data { bits: vec![true; length], head },
+        };
+
+        let module = Wasm.compile(&program(half, half - 1)).expect("should compile fine");
+        let (_, memory, head) = run(&module.encode(), &[]);
+        assert_eq!(head as usize, half * 2 - 1);
+        assert_eq!(memory[memory.len() - 1], 0xff);
+
+        for (length, head) in [(half + 1, 0), (0, half), (0, usize::MAX)] {
+            match Wasm.compile(&program(length, head)) {
+                Err(error @ WasmError::DataTooBig { .. }) => assert_eq!(compiler::Located::position(&error), None),
+                result => panic!("expected {} bits at {} not to fit: {:?}", length, head, result.map(|_| ())),
+            }
+        }
+    }
+}
@@ -1,18 +1,27 @@
 use std::collections::HashMap;
 use std::fmt;
-use std::fs::File;
-use std::io::Read;
 use std::path::Path;
 use std::str::FromStr;
 
 use anyhow::Result;
 use thiserror::Error;
 
+use crate::cfg::Cfg;
+use crate::diagnostic::Snippet;
+use crate::diagnostic::SourceMap;
+use crate::diagnostic::Span;
+use crate::diagnostic::Spanned;
+use crate::loader;
+use crate::lower;
+use crate::macros;
+
 use nom::{
-    branch::alt, bytes::complete::is_not, bytes::complete::tag, bytes::complete::take_while1,
-    character::complete::multispace0, character::complete::multispace1,
-    character::complete::space0, combinator::opt, multi::many0, sequence::pair,
-    sequence::separated_pair, sequence::tuple,
+    branch::alt, bytes::complete::tag, bytes::complete::take_till, bytes::complete::take_until,
+    bytes::complete::take_while1, character::complete::digit1, character::complete::hex_digit1,
+    character::complete::multispace1, combinator::cut, combinator::map, combinator::map_res,
+    combinator::opt, combinator::recognize, combinator::verify, multi::many0, multi::many1, multi::separated_list,
+    multi::separated_nonempty_list, sequence::delimited, sequence::pair, sequence::preceded,
+    sequence::separated_pair, sequence::terminated, sequence::tuple,
 };
 
 #[derive(Debug, Error)]
@@ -20,12 +29,65 @@ pub enum WmachErr {
     #[error("{message}")]
     GeneralError { message: String },
 
-    #[error("Duplicate label: {label}")]
-    DuplicateLabel { label: String },
+    #[error("Unable to parse ``{text}''\n{snippet}")]
+    SyntaxError { text: String, snippet: Snippet },
+
+    #[error("Duplicate label: {label}\n{snippet}")]
+    DuplicateLabel { label: String, snippet: Snippet },
+
+    #[error("The tape can only be set up once\n{snippet}")]
+    DuplicateData { snippet: Snippet },
 
     // this realy should be a LabelId but I don't know how to pull it out of the Target
-    #[error("At instruction {offset} unknown target ``{target}'' referenced")]
-    UnknownTarget { offset: InsnOffset, target: Target },
+    #[error("At instruction {offset} unknown target ``{target}'' referenced\n{snippet}")]
+    UnknownTarget {
+        offset: InsnOffset,
+        target: Target,
+        snippet: Snippet,
+    },
+
+    #[error("Duplicate macro: {name}\n{snippet}")]
+    DuplicateMacro { name: String, snippet: Snippet },
+
+    #[error("Macro {name} must be defined at the top level\n{snippet}")]
+    NestedMacro { name: String, snippet: Snippet },
+
+    #[error("Duplicate parameter: {param}\n{snippet}")]
+    DuplicateParameter { param: String, snippet: Snippet },
+
+    #[error("Unknown macro ``{name}'' invoked\n{snippet}")]
+    UnknownMacro { name: String, snippet: Snippet },
+
+    #[error("Macro {name} takes {expected} arguments but was given {found}\n{snippet}")]
+    MacroArity {
+        name: String,
+        expected: usize,
+        found: usize,
+        snippet: Snippet,
+    },
+
+    #[error("Macro {name} invokes itself\n{snippet}")]
+    RecursiveMacro { name: String, snippet: Snippet },
+
+    #[error("Parameter ``{param}'' is used as a {expected} but was not given one\n{snippet}")]
+    MisusedParameter {
+        param: String,
+        expected: &'static str,
+        snippet: Snippet,
+    },
+
+    #[error("Unable to read {path}: {err}\n{snippet}")]
+    UnreadableFile {
+        path: String,
+        err: std::io::Error,
+        snippet: Snippet,
+    },
+
+    #[error("{path} ends up including itself\n{snippet}")]
+    IncludeCycle { path: String, snippet: Snippet },
+
+    #[error("Repeating {count} times makes more than {} statements\n{snippet}", lower::MAX_UNROLLED)]
+    TooManyRepeats { count: usize, snippet: Snippet },
 
     #[error("IO error: {err}")]
     IoError { err: std::io::Error },
@@ -37,25 +99,110 @@ impl From<std::io::Error> for WmachErr {
     }
 }
 
+impl WmachErr {
+    fn snippet_mut(&mut self) -> Option<&mut Snippet> {
+        match self {
+            WmachErr::SyntaxError { snippet, .. }
+            | WmachErr::DuplicateLabel { snippet, .. }
+            | WmachErr::DuplicateData { snippet, .. }
+            | WmachErr::UnknownTarget { snippet, .. }
+            | WmachErr::DuplicateMacro { snippet, .. }
+            | WmachErr::NestedMacro { snippet, .. }
+            | WmachErr::DuplicateParameter { snippet, .. }
+            | WmachErr::UnknownMacro { snippet, .. }
+            | WmachErr::MacroArity { snippet, .. }
+            | WmachErr::RecursiveMacro { snippet, .. }
+            | WmachErr::MisusedParameter { snippet, .. }
+            | WmachErr::UnreadableFile { snippet, .. }
+            | WmachErr::IncludeCycle { snippet, .. }
+            | WmachErr::TooManyRepeats { snippet, .. } => Some(snippet),
+            WmachErr::GeneralError { .. } | WmachErr::IoError { .. } => None,
+        }
+    }
+
+    pub fn snippet(&self) -> Option<&Snippet> {
+        match self {
+            WmachErr::SyntaxError { snippet, .. }
+            | WmachErr::DuplicateLabel { snippet, .. }
+            | WmachErr::DuplicateData { snippet, .. }
+            | WmachErr::UnknownTarget { snippet, .. }
+            | WmachErr::DuplicateMacro { snippet, .. }
+            | WmachErr::NestedMacro { snippet, .. }
+            | WmachErr::DuplicateParameter { snippet, .. }
+            | WmachErr::UnknownMacro { snippet, .. }
+            | WmachErr::MacroArity { snippet, .. }
+            | WmachErr::RecursiveMacro { snippet, .. }
+            | WmachErr::MisusedParameter { snippet, .. }
+            | WmachErr::UnreadableFile { snippet, .. }
+            | WmachErr::IncludeCycle { snippet, .. }
+            | WmachErr::TooManyRepeats { snippet, .. } => Some(snippet),
+            WmachErr::GeneralError { .. } | WmachErr::IoError { .. } => None,
+        }
+    }
+
+    // Name the file the error came from in its snippet.
+    pub fn with_origin(mut self, origin: &str) -> Self {
+        if let Some(snippet) = self.snippet_mut() {
+            snippet.set_origin(origin);
+        }
+
+        self
+    }
+}
+
 // This is what we get from Stmts
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Insn {
     Write(WriteOp),
-    Seek(SeekOp),
+    // How far to go. Only the lower::expand_seeks form guarantees this is always 1.
+    Seek(SeekOp, usize),
     Io(IoOp),
     Jmp(InsnOffset, InsnOffset),
     Debug,
+    // Stays put forever. Running off the end of the instructions does the same.
+    Halt,
 }
 
 // This is what we get from a file
 #[derive(Debug, Clone)]
 pub enum Stmt {
     Write(WriteOp),
-    Seek(SeekOp),
+    // Consecutive seeks in the same direction are folded together: > > > is Seek(Right, 3), and
+    // so are >3 and >*3.
+    Seek(SeekOp, usize),
     Io(IoOp),
     Label(LabelId),
-    Jmp(Target, Target),
+    Jmp(Spanned<Target>, Spanned<Target>),
     Debug,
+    Halt,
+    // Not an instruction, it says what is on the tape before the first one runs.
+    Data(Data),
+    // repeat N { body }, which stays as it is until lower::expand_repeats unrolls it.
+    Repeat(usize, Vec<Spanned<Stmt>>),
+
+    // These only exist until macros::expand gets rid of them.
+    Macro(Macro),
+    Invoke(Spanned<LabelId>, Vec<Spanned<Argument>>),
+
+    // And these until the loader replaces them with the statements of the file they name.
+    Include(Spanned<String>),
+    Import(Spanned<String>, Spanned<LabelId>),
+}
+
+// macro name(param, ...) { body }
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub name: Spanned<LabelId>,
+    pub params: Vec<Spanned<LabelId>>,
+    pub body: Vec<Spanned<Stmt>>,
+}
+
+// A parameter either stands in for a label or for a block of statements. Block parameters are
+// spliced into the body by invoking them like a macro without arguments.
+#[derive(Debug, Clone)]
+pub enum Argument {
+    Label(LabelId),
+    Block(Vec<Spanned<Stmt>>),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -76,6 +223,45 @@ pub enum IoOp {
     Out,
 }
 
+// The tape before the program starts: bits from west to east, with the head on bits[head]. Every
+// other cell is 0, so the default is the empty tape every program used to start from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Data {
+    pub bits: Vec<bool>,
+    pub head: usize,
+}
+
+impl Data {
+    // Spells out the cells between the end of the bits and the head, if there are any.
+    pub fn new(mut bits: Vec<bool>, head: usize) -> Self {
+        if bits.len() <= head {
+            bits.resize(head + 1, false);
+        }
+
+        Data { bits, head }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Data::default()
+    }
+}
+
+// Always as bits, since bytes and text both turn into those straight away.
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "data bits ")?;
+        for bit in self.bits.iter() {
+            write!(f, "{}", if *bit { 1 } else { 0 })?;
+        }
+
+        if self.head > 0 {
+            write!(f, " at {}", self.head)?;
+        }
+
+        Ok(())
+    }
+}
+
 pub type LabelId = String;
 pub type InsnOffset = usize;
 pub type LabelMap = HashMap<LabelId, InsnOffset>;
@@ -94,93 +280,206 @@ impl fmt::Display for Target {
     }
 }
 
+// Instructions print as the source they came from, except that jumps only know offsets.
+impl fmt::Display for Insn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Insn::Write(WriteOp::Set) => write!(f, "+"),
+            Insn::Write(WriteOp::Unset) => write!(f, "-"),
+            // Runs get a count rather than being spelt out, they can be a long way.
+            Insn::Seek(direction, count) => {
+                let symbol = match direction {
+                    SeekOp::Left => "<",
+                    SeekOp::Right => ">",
+                };
+                match count {
+                    1 => write!(f, "{}", symbol),
+                    _ => write!(f, "{}{}", symbol, count),
+                }
+            }
+            Insn::Io(IoOp::In) => write!(f, ","),
+            Insn::Io(IoOp::Out) => write!(f, "."),
+            Insn::Jmp(branch_t, branch_f) => write!(f, "jmp {}, {}", branch_t, branch_f),
+            Insn::Debug => write!(f, "!"),
+            Insn::Halt => write!(f, "halt"),
+        }
+    }
+}
+
 pub type Code = Vec<Insn>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Program {
     pub instructions: Code,
     pub labels: LabelMap,
+    pub data: Data,
 }
 
-impl FromStr for Program {
-    type Err = WmachErr;
+// Source that parses back into the same program. Labels go on their own line at the offset they
+// name and instructions are indented underneath.
+//
+// Runs of seeks in the same direction are written as one statement since that's how the parser
+// reads them anyway. So what comes back from parsing the output is always the same program once
+// seeks are expanded, and is exactly the same program only if its runs were as long as they could
+// be, which they are straight out of the parser. Seek(Right, 2), Seek(Right, 3) prints as >5 and
+// comes back as Seek(Right, 5).
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = &self.instructions;
 
-    fn from_str(unparsed: &str) -> Result<Program, WmachErr> {
-        let statements = Program::parse_statements(unparsed)?;
-
-        // make jmp table
-        let mut jmp_table: LabelMap = HashMap::new();
-        let mut offset: InsnOffset = 0;
-        for stmt in statements.iter() {
-            if let Stmt::Label(label_id) = stmt {
-                if jmp_table.contains_key(label_id) {
-                    Err(WmachErr::DuplicateLabel {
-                        label: label_id.to_owned(),
-                    })?
+        let mut names: HashMap<InsnOffset, Vec<LabelId>> = HashMap::new();
+        for (label, offset) in self.labels.iter() {
+            names.entry(*offset).or_default().push(label.clone());
+        }
+        for labels in names.values_mut() {
+            labels.sort();
+        }
+
+        // Jumps can only go to labels, so anywhere without one gets a made up one. The only
+        // exception is falling through to the next instruction, which doesn't need a name.
+        for (offset, insn) in code.iter().enumerate() {
+            if let Insn::Jmp(branch_t, branch_f) = insn {
+                let mut targets = vec![*branch_t];
+                if *branch_f != offset + 1 {
+                    targets.push(*branch_f);
                 }
 
-                jmp_table.insert(label_id.to_owned(), offset);
-            } else {
-                offset += 1;
+                for target in targets {
+                    if names.contains_key(&target) {
+                        continue;
+                    }
+
+                    let mut label = format!("_{}", target);
+                    while self.labels.contains_key(&label) {
+                        label.push('\'');
+                    }
+                    names.insert(target, vec![label]);
+                }
             }
         }
 
-        // XXX Might want to bind the previous offset and the subsequent offset more tightly.
-        // Maybe we can use the type-system somehow.
+        if !self.data.is_empty() {
+            writeln!(f, "{}", self.data)?;
+        }
 
-        // make instructions
-        let mut insns: Vec<Insn> = Vec::new();
-        for (offset, stmt) in statements
-            .iter()
-            .filter(|stmt| {
-                // Skip labels
-                match stmt {
-                    Stmt::Label(_) => false,
-                    _ => true,
+        let mut offset = 0;
+        loop {
+            for label in names.get(&offset).into_iter().flatten() {
+                writeln!(f, "{}:", label)?;
+            }
+
+            let insn = match code.get(offset) {
+                Some(insn) => insn,
+                None => break,
+            };
+            offset += 1;
+
+            match insn {
+                Insn::Seek(direction, count) => {
+                    let mut count = *count;
+                    while let Some(Insn::Seek(next, more)) = code.get(offset) {
+                        if next != direction || names.contains_key(&offset) {
+                            break;
+                        }
+
+                        count += more;
+                        offset += 1;
+                    }
+
+                    writeln!(f, "    {}", Insn::Seek(*direction, count))?;
                 }
-            })
-            .enumerate()
-        {
-            let insn = match stmt {
-                Stmt::Write(value) => Insn::Write(*value),
-                Stmt::Seek(direction) => Insn::Seek(*direction),
-                Stmt::Io(rw) => Insn::Io(*rw),
-                Stmt::Jmp(branch_t, branch_f) => {
-                    let target_address = |branch: &Target| match branch {
-                        Target::NextAddress => Some(offset + 1),
-                        Target::Name(label_id) => jmp_table.get(label_id).cloned(),
+                Insn::Jmp(branch_t, branch_f) => {
+                    write!(f, "    jmp {}", names[branch_t][0])?;
+                    match names.get(branch_f) {
+                        Some(labels) => writeln!(f, ", {}", labels[0])?,
+                        None => writeln!(f)?,
                     };
-
-                    // missing label error
-                    let t = target_address(branch_t).ok_or(WmachErr::UnknownTarget {
-                        offset: offset,
-                        target: branch_t.to_owned(),
-                    })?;
-                    let f = target_address(branch_f).ok_or(WmachErr::UnknownTarget {
-                        offset: offset,
-                        target: branch_f.to_owned(),
-                    })?;
-
-                    Insn::Jmp(t, f)
                 }
-                Stmt::Debug => Insn::Debug,
+                _ => writeln!(f, "    {}", insn)?,
+            }
+        }
 
-                _ => {
-                    panic!("Shouldn't reach this");
-                }
-            };
+        Ok(())
+    }
+}
 
-            insns.push(insn);
-        }
+impl FromStr for Program {
+    type Err = WmachErr;
+
+    fn from_str(unparsed: &str) -> Result<Program, WmachErr> {
+        Ok(Ast::from_str(unparsed)?.resolve_labels()?.expand_seeks())
+    }
+}
+
+impl Program {
+    pub fn cfg(&self) -> Cfg {
+        Cfg::new(self)
+    }
+
+    // See lower::expand_seeks.
+    pub fn expand_seeks(&self) -> Program {
+        lower::expand_seeks(self)
+    }
+}
+
+// What the front end makes of a program: the statements of every file it is made of, with
+// includes, imports and macros already dealt with. Consumers pick the lowering passes they need
+// from here on, see lower.rs.
+#[derive(Debug)]
+pub struct Ast {
+    pub sources: SourceMap,
+    pub statements: Vec<Spanned<Stmt>>,
+}
+
+impl FromStr for Ast {
+    type Err = WmachErr;
+
+    fn from_str(unparsed: &str) -> Result<Ast, WmachErr> {
+        let (sources, statements) = loader::load_str(unparsed)?;
+        let statements = macros::expand(&sources, statements)?;
 
-        Ok(Program {
-            instructions: insns,
-            labels: jmp_table,
+        Ok(Ast {
+            sources,
+            statements,
         })
     }
 }
 
+impl Ast {
+    pub fn from_file(filename: &Path) -> Result<Ast, WmachErr> {
+        let (sources, statements) = loader::load_file(filename)?;
+        let statements = macros::expand(&sources, statements)?;
+
+        Ok(Ast {
+            sources,
+            statements,
+        })
+    }
+
+    // An editor's copy of filename, which may not have been saved. Includes and imports are still
+    // found next to filename.
+    pub fn from_buffer(filename: &Path, text: &str) -> Result<Ast, WmachErr> {
+        let (sources, statements) = loader::load_buffer(filename, text)?;
+        let statements = macros::expand(&sources, statements)?;
+
+        Ok(Ast {
+            sources,
+            statements,
+        })
+    }
+
+    // See lower::resolve_labels.
+    pub fn resolve_labels(&self) -> Result<Program, WmachErr> {
+        lower::resolve_labels(&self.sources, &self.statements)
+    }
+}
+
+// Libraries put their labels in a namespace: lib.loop
 fn label(input: &str) -> nom::IResult<&str, &str> {
+    recognize(pair(label_segment, many0(pair(tag("."), label_segment))))(input)
+}
+
+pub(crate) fn label_segment(input: &str) -> nom::IResult<&str, &str> {
     take_while1(|input| {
         // misc = { "'" | '_' }
         // label_id = (alpha | digit | misc)+
@@ -198,141 +497,336 @@ fn label(input: &str) -> nom::IResult<&str, &str> {
 fn label_op(input: &str) -> nom::IResult<&str, Stmt> {
     let colon = tag(":");
 
-    let (input, (label_id, _, _, _)) = tuple((label, space0, colon, multispace0))(input)?;
+    let (input, (label_id, _, _)) = tuple((label, blank, colon))(input)?;
     let label_id = label_id.to_string();
 
     Ok((input, Stmt::Label(label_id)))
 }
 
+fn name(input: &str) -> nom::IResult<&str, Spanned<LabelId>> {
+    let (rest, name) = label(input)?;
+
+    Ok((rest, Spanned::new(name.to_string(), Span::between(input, rest))))
+}
+
+fn target(input: &str) -> nom::IResult<&str, Spanned<Target>> {
+    let (rest, name) = label(input)?;
+    let target = Target::Name(name.to_string());
+
+    Ok((rest, Spanned::new(target, Span::between(input, rest))))
+}
+
 fn jmp_op(input: &str) -> nom::IResult<&str, Stmt> {
     let op = tag("jmp");
-    let (input, (_, true_branch)) = separated_pair(op, multispace1, label)(input)?;
-    let true_branch = Target::Name(true_branch.to_string());
+    let (input, (_, true_branch)) = separated_pair(op, blank1, target)(input)?;
 
     let separator = tag(",");
-    let (input, result) = opt(tuple((
-        multispace0,
-        separator,
-        multispace0,
-        label,
-        multispace0,
-    )))(input)?;
+    let (input, result) = opt(tuple((blank, separator, blank, target)))(input)?;
     let false_branch = match result {
-        Some((_, _, _, name, _)) => Target::Name(name.to_string()),
-        None => Target::NextAddress,
+        Some((_, _, _, target)) => target,
+        None => Spanned::new(Target::NextAddress, Span::between(input, input)),
     };
 
     Ok((input, Stmt::Jmp(true_branch, false_branch)))
 }
 
+fn halt_op(input: &str) -> nom::IResult<&str, Stmt> {
+    let op = tag("halt");
+    let (input, _) = op(input)?;
+
+    Ok((input, Stmt::Halt))
+}
+
 fn set_op(input: &str) -> nom::IResult<&str, Stmt> {
     let op = tag("+");
-    let (input, (_, _)) = pair(op, multispace0)(input)?;
+    let (input, _) = op(input)?;
 
     Ok((input, Stmt::Write(WriteOp::Set)))
 }
 
 fn unset_op(input: &str) -> nom::IResult<&str, Stmt> {
     let op = tag("-");
-    let (input, (_, _)) = tuple((op, multispace0))(input)?;
+    let (input, _) = op(input)?;
 
     Ok((input, Stmt::Write(WriteOp::Unset)))
 }
 
-fn seek_left_op(input: &str) -> nom::IResult<&str, Stmt> {
-    let op = tag("<");
-    let (input, (_, _)) = tuple((op, multispace0))(input)?;
+fn seek_direction(input: &str) -> nom::IResult<&str, SeekOp> {
+    alt((
+        map(tag("<"), |_| SeekOp::Left),
+        map(tag(">"), |_| SeekOp::Right),
+    ))(input)
+}
+
+// > or >8 or >*8, the last two being 8 cells at once.
+fn seek_run(input: &str) -> nom::IResult<&str, (SeekOp, usize)> {
+    let count = preceded(opt(tag("*")), verify(number, |count| *count > 0));
+    let (input, (direction, count)) = pair(seek_direction, opt(count))(input)?;
 
-    Ok((input, Stmt::Seek(SeekOp::Left)))
+    Ok((input, (direction, count.unwrap_or(1))))
 }
 
-fn seek_right_op(input: &str) -> nom::IResult<&str, Stmt> {
-    let op = tag(">");
-    let (input, (_, _)) = tuple((op, multispace0))(input)?;
+pub(crate) fn seek_op(input: &str) -> nom::IResult<&str, Stmt> {
+    let (mut input, (direction, mut count)) = seek_run(input)?;
 
-    Ok((input, Stmt::Seek(SeekOp::Right)))
+    // Keep going for as long as we're heading the same way.
+    while let Ok((rest, (next, more))) = preceded(blank, seek_run)(input) {
+        if next != direction {
+            break;
+        }
+
+        count += more;
+        input = rest;
+    }
+
+    Ok((input, Stmt::Seek(direction, count)))
 }
 
 fn input_op(input: &str) -> nom::IResult<&str, Stmt> {
     let op = tag(",");
-    let (input, (_, _)) = tuple((op, multispace0))(input)?;
+    let (input, _) = op(input)?;
 
     Ok((input, Stmt::Io(IoOp::In)))
 }
 
 fn output_op(input: &str) -> nom::IResult<&str, Stmt> {
     let op = tag(".");
-    let (input, (_, _)) = tuple((op, multispace0))(input)?;
+    let (input, _) = op(input)?;
 
     Ok((input, Stmt::Io(IoOp::Out)))
 }
 
 fn debug_op(input: &str) -> nom::IResult<&str, Stmt> {
     let op = tag("!");
-    let (input, (_, _)) = tuple((op, multispace0))(input)?;
+    let (input, _) = op(input)?;
 
     Ok((input, Stmt::Debug))
 }
 
+fn number(input: &str) -> nom::IResult<&str, usize> {
+    alt((
+        map_res(preceded(tag("0x"), hex_digit1), |digits| usize::from_str_radix(digits, 16)),
+        map_res(digit1, usize::from_str),
+    ))(input)
+}
+
+// bits 0110
+fn data_bits(input: &str) -> nom::IResult<&str, Vec<bool>> {
+    let digits = take_while1(|c| c == '0' || c == '1');
+    let (input, (_, _, digits)) = tuple((tag("bits"), blank1, digits))(input)?;
+
+    Ok((input, digits.chars().map(|c| c == '1').collect()))
+}
+
+// Bytes go on the tape least significant bit first, the same as IoBuffer reads them in.
+fn bytes_to_bits(bytes: &[u8]) -> Vec<bool> {
+    bytes
+        .iter()
+        .flat_map(|byte| (0..8).map(move |i| byte & (1 << i) != 0))
+        .collect()
+}
+
+// bytes 72, 0x69
+fn data_bytes(input: &str) -> nom::IResult<&str, Vec<bool>> {
+    let byte = map_res(number, u8::try_from);
+    let separator = tuple((blank, tag(","), blank));
+    let (input, (_, _, bytes)) =
+        tuple((tag("bytes"), blank1, separated_nonempty_list(separator, byte)))(input)?;
+
+    Ok((input, bytes_to_bits(&bytes)))
+}
+
+// "Hi"
+fn data_ascii(input: &str) -> nom::IResult<&str, Vec<bool>> {
+    let quote = tag("\"");
+    let (input, text) = delimited(&quote, take_till(|c| c == '"' || c == '\n'), &quote)(input)?;
+
+    Ok((input, bytes_to_bits(text.as_bytes())))
+}
+
+// data (bits ... | bytes ... | "...") [at N]
+fn data_op(input: &str) -> nom::IResult<&str, Stmt> {
+    let op = tag("data");
+    let (input, (_, _, bits)) =
+        tuple((op, blank1, alt((data_bits, data_bytes, data_ascii))))(input)?;
+
+    let at = tuple((blank1, tag("at"), blank1));
+    let (input, head) = opt(preceded(at, number))(input)?;
+
+    Ok((input, Stmt::Data(Data::new(bits, head.unwrap_or(0)))))
+}
+
+// repeat N { stmt ... }
+fn repeat_op(input: &str) -> nom::IResult<&str, Stmt> {
+    let op = tag("repeat");
+    let (input, (_, _, count, _, body)) = tuple((op, blank1, number, blank, block))(input)?;
+
+    Ok((input, Stmt::Repeat(count, body)))
+}
+
+// ( item, ... )
+fn list<'a, O, F>(item: F) -> impl Fn(&'a str) -> nom::IResult<&'a str, Vec<O>>
+where
+    F: Fn(&'a str) -> nom::IResult<&'a str, O>,
+{
+    let separator = tuple((blank, tag(","), blank));
+
+    delimited(
+        pair(tag("("), blank),
+        separated_list(separator, item),
+        pair(blank, tag(")")),
+    )
+}
+
+// { stmt ... }
+fn block(input: &str) -> nom::IResult<&str, Vec<Spanned<Stmt>>> {
+    let (input, _) = pair(tag("{"), blank)(input)?;
+
+    // Once we are inside the braces, complain about whatever stopped us instead of backtracking
+    // all the way out to the start of the block.
+    cut(terminated(many0(any_statement), tag("}")))(input)
+}
+
+fn macro_op(input: &str) -> nom::IResult<&str, Stmt> {
+    let op = tag("macro");
+    let (input, (_, _, name, _, params, _, body)) =
+        tuple((op, blank1, name, blank, list(name), blank, block))(input)?;
+
+    Ok((input, Stmt::Macro(Macro { name, params, body })))
+}
+
+fn argument(input: &str) -> nom::IResult<&str, Spanned<Argument>> {
+    let (rest, arg) = alt((
+        map(block, Argument::Block),
+        map(label, |name: &str| Argument::Label(name.to_string())),
+    ))(input)?;
+
+    Ok((rest, Spanned::new(arg, Span::between(input, rest))))
+}
+
+fn invoke_op(input: &str) -> nom::IResult<&str, Stmt> {
+    let (input, (name, _, args)) = tuple((name, blank, list(argument)))(input)?;
+
+    Ok((input, Stmt::Invoke(name, args)))
+}
+
+// "path/to/file.wm"
+fn path(input: &str) -> nom::IResult<&str, Spanned<String>> {
+    let quote = tag("\"");
+    let (rest, path) = delimited(&quote, take_till(|c| c == '"' || c == '\n'), &quote)(input)?;
+
+    Ok((rest, Spanned::new(path.to_string(), Span::between(input, rest))))
+}
+
+// Either a path or the name of a library next to us, which is short for "name.wm".
+fn library(input: &str) -> nom::IResult<&str, Spanned<String>> {
+    let by_name = map(name, |name| {
+        Spanned::new(format!("{}.wm", name.node), name.span)
+    });
+
+    alt((path, by_name))(input)
+}
+
+fn include_op(input: &str) -> nom::IResult<&str, Stmt> {
+    let op = tag("include");
+    let (input, (_, path)) = separated_pair(op, blank1, path)(input)?;
+
+    Ok((input, Stmt::Include(path)))
+}
+
+fn import_op(input: &str) -> nom::IResult<&str, Stmt> {
+    let op = tag("import");
+    let (input, (_, _, path, _, _, _, namespace)) =
+        tuple((op, blank1, library, blank1, tag("as"), blank1, name))(input)?;
+
+    Ok((input, Stmt::Import(path, namespace)))
+}
+
 fn statement(input: &str) -> nom::IResult<&str, Stmt> {
     alt((
         label_op,
+        include_op,
+        import_op,
+        macro_op,
+        data_op,
+        repeat_op,
+        invoke_op,
         jmp_op,
+        halt_op,
         set_op,
         unset_op,
-        seek_left_op,
-        seek_right_op,
+        seek_op,
         input_op,
         output_op,
         debug_op,
     ))(input)
 }
 
-fn comment(input: &str) -> nom::IResult<&str, ()> {
-    let (input, _) = tuple((tag("/*"), is_not("*/"), tag("*/"), multispace0))(input)?;
+fn block_comment(input: &str) -> nom::IResult<&str, &str> {
+    recognize(tuple((tag("/*"), take_until("*/"), tag("*/"))))(input)
+}
+
+fn line_comment(input: &str) -> nom::IResult<&str, &str> {
+    let opener = alt((tag("//"), tag("#")));
+
+    recognize(pair(opener, take_till(|c| c == '\n')))(input)
+}
+
+// As far as the grammar is concerned comments are just more whitespace.
+pub(crate) fn blank(input: &str) -> nom::IResult<&str, ()> {
+    let (input, _) = many0(alt((multispace1, block_comment, line_comment)))(input)?;
 
     Ok((input, ()))
 }
 
-fn any_statement(input: &str) -> nom::IResult<&str, Stmt> {
-    // XXX Yeah, you can't put a comment anywhere. I am willing to live with that for the time
-    // being
-    let (input, _) = opt(comment)(input)?;
-    let (input, _) = multispace0(input)?;
+pub(crate) fn blank1(input: &str) -> nom::IResult<&str, ()> {
+    let (input, _) = many1(alt((multispace1, block_comment, line_comment)))(input)?;
+
+    Ok((input, ()))
+}
 
-    let (input, stmt) = statement(input)?;
+fn any_statement(input: &str) -> nom::IResult<&str, Spanned<Stmt>> {
+    let (rest, stmt) = statement(input)?;
+    let stmt = Spanned::new(stmt, Span::between(input, rest));
 
-    let (input, _) = opt(comment)(input)?;
-    let (input, _) = multispace0(input)?;
+    let (rest, _) = blank(rest)?;
 
-    Ok((input, stmt))
+    Ok((rest, stmt))
 }
 
-fn parse_entry(input: &str) -> nom::IResult<&str, Vec<Stmt>> {
-    many0(any_statement)(input)
+fn parse_entry(input: &str) -> nom::IResult<&str, Vec<Spanned<Stmt>>> {
+    preceded(blank, many0(any_statement))(input)
+}
+
+// Point at the first token we could not make sense of.
+fn syntax_error(unparsed: &str, rest: &str) -> WmachErr {
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let span = Span::between(rest, &rest[end..]);
+
+    WmachErr::SyntaxError {
+        text: rest[..end].to_string(),
+        snippet: Snippet::new(unparsed, span).expect("rest should be the end of unparsed"),
+    }
 }
 
 impl Program {
-    fn parse_statements(unparsed: &str) -> Result<Vec<Stmt>, WmachErr> {
-        let (rest, statements) = parse_entry(unparsed).map_err(|e| WmachErr::GeneralError {
-            message: format!("Nom Error: {}", e),
+    pub fn parse_statements(unparsed: &str) -> Result<Vec<Spanned<Stmt>>, WmachErr> {
+        let (rest, statements) = parse_entry(unparsed).map_err(|e| match e {
+            nom::Err::Error((rest, _)) | nom::Err::Failure((rest, _)) => syntax_error(unparsed, rest),
+            nom::Err::Incomplete(_) => WmachErr::GeneralError {
+                message: format!("Nom Error: {}", e),
+            },
         })?;
 
-        let rest = String::from_utf8(rest.as_bytes().to_vec()).expect("Invalid UTF8");
         if rest.len() > 0 {
-            Err(WmachErr::GeneralError {
-                message: format!("Left over data: {}", rest),
-            })?;
+            Err(syntax_error(unparsed, rest))?;
         }
 
         Ok(statements)
     }
 
     pub fn from_file(filename: &Path) -> Result<Program, WmachErr> {
-        let mut unparsed_file = String::new();
-        File::open(filename)?.read_to_string(&mut unparsed_file)?;
-
-        Program::from_str(&unparsed_file)
+        Ok(Ast::from_file(filename)?.resolve_labels()?.expand_seeks())
     }
 }
 
@@ -398,11 +892,11 @@ mod constraint_tests {
             _ => panic!("parsed stmt incorrect: {:?}", jmp),
         };
 
-        match branch_a {
+        match branch_a.node {
             Target::Name(name) => assert_eq!(name, true_branch),
             _ => panic!("parsed frist branch incorrectly: {:?}", branch_a),
         };
-        match branch_b {
+        match branch_b.node {
             Target::NextAddress => assert!(true),
             _ => panic!("parsed second branch incorrectly: {:?}", branch_b),
         };
@@ -425,11 +919,11 @@ mod constraint_tests {
             _ => panic!("parsed stmt incorrect: {:?}", jmp),
         };
 
-        match branch_a {
+        match branch_a.node {
             Target::Name(name) => assert_eq!(name, true_branch),
             _ => panic!("parsed frist branch incorrectly: {:?}", branch_a),
         };
-        match branch_b {
+        match branch_b.node {
             Target::Name(name) => assert_eq!(name, false_branch),
             _ => panic!("parsed second branch incorrectly: {:?}", branch_b),
         };
@@ -480,7 +974,7 @@ mod constraint_tests {
     #[test]
     fn parse_seek_left() {
         let program = format!("<");
-        let result = seek_left_op(&program);
+        let result = seek_op(&program);
 
         let (_, stmt) = match result {
             Ok(whatever) => whatever,
@@ -488,7 +982,7 @@ mod constraint_tests {
         };
 
         let op = match stmt {
-            Stmt::Seek(op) => op,
+            Stmt::Seek(op, 1) => op,
             _ => panic!("parsed stmt incorect: {:?}", stmt),
         };
 
@@ -501,7 +995,7 @@ mod constraint_tests {
     #[test]
     fn parse_seek_right() {
         let program = format!(">");
-        let result = seek_right_op(&program);
+        let result = seek_op(&program);
 
         let (_, stmt) = match result {
             Ok(whatever) => whatever,
@@ -509,7 +1003,7 @@ mod constraint_tests {
         };
 
         let op = match stmt {
-            Stmt::Seek(op) => op,
+            Stmt::Seek(op, 1) => op,
             _ => panic!("parsed stmt incorect: {:?}", stmt),
         };
 
@@ -577,6 +1071,383 @@ mod constraint_tests {
         };
     }
 
+    #[test]
+    fn parse_halt() {
+        let statements = Program::parse_statements("halt: + halt").expect("should parse fine");
+
+        match (&statements[0].node, &statements[2].node) {
+            (Stmt::Label(label), Stmt::Halt) => assert_eq!(label, "halt"),
+            x => panic!("parsed stmts incorrect: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn parse_seek_counts() {
+        let statements = Program::parse_statements(">8 <*16 > >3 <0x10").expect("should parse fine");
+        let seeks: Vec<String> = statements.iter().map(|stmt| format!("{:?}", stmt.node)).collect();
+
+        assert_eq!(seeks, vec!["Seek(Right, 8)", "Seek(Left, 16)", "Seek(Right, 4)", "Seek(Left, 16)"]);
+
+        for bad in [">0", "<*", ">*0"] {
+            match Program::parse_statements(bad) {
+                Err(WmachErr::SyntaxError { .. }) => (),
+                x => panic!("Failed to reject {}: {:?}", bad, x),
+            };
+        }
+    }
+
+    #[test]
+    fn parse_repeat() {
+        let statements = Program::parse_statements("repeat 3 { + >2 } -").expect("should parse fine");
+
+        match &statements[0].node {
+            Stmt::Repeat(3, body) => assert_eq!(body.len(), 2),
+            x => panic!("parsed repeat incorrectly: {:?}", x),
+        };
+        assert_eq!(statements.len(), 2);
+
+        // The count stays put until something wants the repeat gone.
+        let ast = Ast::from_str("repeat 2 { >5 }").expect("should parse fine");
+        assert!(matches!(ast.statements[0].node, Stmt::Repeat(2, _)));
+        assert_eq!(
+            format!("{:?}", ast.resolve_labels().expect("should resolve fine").instructions),
+            "[Seek(Right, 5), Seek(Right, 5)]"
+        );
+    }
+
+    #[test]
+    fn parse_data() {
+        let data = |src: &str| Program::from_str(src).expect("should parse fine").data;
+
+        let bits = |digits: &str| -> Vec<bool> { digits.chars().map(|c| c == '1').collect() };
+        assert_eq!(data("+ data bits 0110 >"), Data::new(bits("0110"), 0));
+        assert_eq!(data("data bits 01 at 4"), Data::new(bits("01000"), 4));
+        assert_eq!(data("data bytes 5, 0x80 at 2"), Data::new(bits("1010000000000001"), 2));
+        assert_eq!(data("data \"A\""), Data::new(bits("10000010"), 0));
+        assert_eq!(data("+"), Data::default());
+
+        // at is only part of the directive when a number follows.
+        let program = Program::from_str("data bits 1 at: jmp at").expect("should parse fine");
+        assert_eq!(program.data, Data::new(vec![true], 0));
+        assert_eq!(program.labels.get("at"), Some(&0));
+
+        match Program::from_str("data bytes 1, 256") {
+            Err(WmachErr::SyntaxError { .. }) => (),
+            x => panic!("Failed to catch the oversized byte: {:?}", x),
+        };
+
+        match Program::from_str("data bits 1\n+\ndata bits 0") {
+            Err(WmachErr::DuplicateData { snippet }) => assert_eq!(snippet.location().line, 3),
+            x => panic!("Failed to catch the second data directive: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn print_data() {
+        let program = Program::from_str("> data \"a\" at 9 +").expect("should parse fine");
+        let printed = program.to_string();
+
+        assert_eq!(printed, "data bits 1000011000 at 9\n    >\n    +\n");
+        assert_eq!(Program::from_str(&printed).expect("printed source should parse"), program);
+    }
+
+    #[test]
+    fn statement_spans() {
+        let program = "+ >\n  loop: jmp loop\n";
+        let statements = Program::parse_statements(program).expect("should parse fine");
+
+        let found: Vec<(&str, usize, usize)> = statements
+            .iter()
+            .map(|stmt| {
+                let location = stmt.span.locate(program).expect("span should be in the program");
+                (stmt.span.text(program).expect("span should be in the program"), location.line, location.column)
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![("+", 1, 1), (">", 1, 3), ("loop:", 2, 3), ("jmp loop", 2, 9)]
+        );
+
+        match &statements[3].node {
+            Stmt::Jmp(branch_t, _) => assert_eq!(branch_t.span.text(program), Some("loop")),
+            stmt => panic!("parsed stmt incorrect: {:?}", stmt),
+        };
+    }
+
+    #[test]
+    fn duplicate_label_snippet() {
+        let program = "top: +\n> top: -";
+        match Program::from_str(program) {
+            Err(WmachErr::DuplicateLabel { label, snippet }) => {
+                assert_eq!(label, "top");
+                assert_eq!(snippet.to_string(), " --> 2:3\n  |\n2 | > top: -\n  |   ^^^^");
+            }
+            x => panic!("Failed to catch the duplicate label: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn unknown_target_snippet() {
+        let program = "top: + jmp top, dnoe";
+        match Program::from_str(program) {
+            Err(WmachErr::UnknownTarget { offset, snippet, .. }) => {
+                assert_eq!(offset, 1);
+                assert_eq!(snippet.to_string(), " --> 1:17\n  |\n1 | top: + jmp top, dnoe\n  |                 ^^^^");
+            }
+            x => panic!("Failed to catch the unknown target: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn syntax_error_snippet() {
+        let program = "+ >\n  + jmp\n";
+        match Program::from_str(program) {
+            Err(WmachErr::SyntaxError { text, snippet }) => {
+                assert_eq!(text, "jmp");
+                assert_eq!(snippet.location().line, 2);
+                assert_eq!(snippet.location().column, 5);
+            }
+            x => panic!("Failed to catch the bad jmp: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn comments_anywhere() {
+        let program = "
+            /* leading */ /* and another */
+            top /* before the colon */ : // trailing
+            + # set it
+            jmp /* between */ top /* around */ , /* the comma */ top
+            /* a comment with * and / and even /* inside */
+            #
+            //
+        ";
+        let result = Program::from_str(program).expect("should parse fine");
+
+        assert_eq!(result.instructions.len(), 2);
+        assert_eq!(result.labels.get("top"), Some(&0));
+        match result.instructions[1] {
+            Insn::Jmp(0, 0) => (),
+            ref insn => panic!("parsed insn incorrect: {:?}", insn),
+        };
+    }
+
+    #[test]
+    fn seek_runs() {
+        let program = "
+            top:
+            > /* a comment with * and / in it */ > # more
+            >
+            < <
+            jmp top
+        ";
+        let ast = Ast::from_str(program).expect("should parse fine");
+
+        let seeks: Vec<(SeekOp, usize)> = ast
+            .statements
+            .iter()
+            .filter_map(|stmt| match stmt.node {
+                Stmt::Seek(direction, count) => Some((direction, count)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(seeks, vec![(SeekOp::Right, 3), (SeekOp::Left, 2)]);
+        assert_eq!(ast.statements[1].span.locate(program).map(|location| location.line), Some(3));
+    }
+
+    #[test]
+    fn only_comments() {
+        let result = Program::from_str("// nothing to see here\n/* at all */").expect("should parse fine");
+
+        assert!(result.instructions.is_empty());
+    }
+
+    #[test]
+    fn comment_spans() {
+        let program = "+ /* set */ >";
+        let statements = Program::parse_statements(program).expect("should parse fine");
+
+        assert_eq!(statements[0].span.text(program), Some("+"));
+        assert_eq!(statements[1].span.text(program), Some(">"));
+    }
+
+    #[test]
+    fn unterminated_comment() {
+        match Program::from_str("+ /* set >") {
+            Err(WmachErr::SyntaxError { text, .. }) => assert_eq!(text, "/*"),
+            x => panic!("Failed to reject the unterminated comment: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn namespaced_labels() {
+        let (rest, target) = target("lib.loop, done").expect("should parse fine");
+        assert_eq!(rest, ", done");
+        match target.node {
+            Target::Name(label) => assert_eq!(label, "lib.loop"),
+            _ => panic!("parsed target incorrect: {:?}", target),
+        };
+
+        // A trailing dot is still an output.
+        let (rest, _) = jmp_op("jmp loop.").expect("should parse fine");
+        assert_eq!(rest, ".");
+    }
+
+    #[test]
+    fn parse_directives() {
+        match include_op("include \"lib/bits.wm\"") {
+            Ok(("", Stmt::Include(path))) => assert_eq!(path.node, "lib/bits.wm"),
+            x => panic!("parsed include incorrect: {:?}", x),
+        };
+
+        match import_op("import bits as b") {
+            Ok(("", Stmt::Import(path, namespace))) => {
+                assert_eq!(path.node, "bits.wm");
+                assert_eq!(namespace.node, "b");
+            }
+            x => panic!("parsed import incorrect: {:?}", x),
+        };
+
+        match import_op("import \"../std/bits.wm\" as std.bits") {
+            Ok(("", Stmt::Import(path, namespace))) => {
+                assert_eq!(path.node, "../std/bits.wm");
+                assert_eq!(namespace.node, "std.bits");
+            }
+            x => panic!("parsed import incorrect: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn parse_macro() {
+        let program = "macro twice(body, out) { body() body() jmp out }";
+        let (rest, stmt) = macro_op(program).expect("should parse fine");
+        assert!(rest.is_empty());
+
+        let definition = match stmt {
+            Stmt::Macro(definition) => definition,
+            _ => panic!("parsed stmt incorrect: {:?}", stmt),
+        };
+        assert_eq!(definition.name.node, "twice");
+        let params: Vec<&str> = definition.params.iter().map(|p| p.node.as_str()).collect();
+        assert_eq!(params, vec!["body", "out"]);
+        assert_eq!(definition.body.len(), 3);
+    }
+
+    #[test]
+    fn parse_invoke() {
+        let program = "twice( { + > } , done )";
+        let (rest, stmt) = invoke_op(program).expect("should parse fine");
+        assert!(rest.is_empty());
+
+        let (name, args) = match stmt {
+            Stmt::Invoke(name, args) => (name, args),
+            _ => panic!("parsed stmt incorrect: {:?}", stmt),
+        };
+        assert_eq!(name.node, "twice");
+        match &args[0].node {
+            Argument::Block(body) => assert_eq!(body.len(), 2),
+            arg => panic!("parsed argument incorrect: {:?}", arg),
+        };
+        match &args[1].node {
+            Argument::Label(label) => assert_eq!(label, "done"),
+            arg => panic!("parsed argument incorrect: {:?}", arg),
+        };
+        assert_eq!(args[1].span.text(program), Some("done"));
+    }
+
+    #[test]
+    fn bad_macro_body() {
+        let program = "macro broken() {\n  + ?\n}";
+        match Program::from_str(program) {
+            Err(WmachErr::SyntaxError { text, snippet }) => {
+                assert_eq!(text, "?");
+                assert_eq!(snippet.location().line, 2);
+            }
+            x => panic!("Failed to reject the bad macro: {:?}", x),
+        };
+    }
+
+    #[test]
+    fn print_program() {
+        let program = Program::from_str("top: + > > , end: jmp top, end").expect("should parse fine");
+
+        assert_eq!(program.to_string(), "top:\n    +\n    >2\n    ,\nend:\n    jmp top, end\n");
+    }
+
+    // Straight out of the parser every run of seeks is as long as it gets, so these come back
+    // exactly. See print_merges_seek_runs for the ones that don't.
+    #[test]
+    fn print_round_trip() {
+        let sources = [
+            "",
+            "+ - > < , . ! halt",
+            "top: >>> a: b: <<- jmp top jmp a, b end:",
+            "+ >100000 - <*65537 > +",
+            "macro twice(body) { body() body() } loop: twice({ > jmp loop, out }) out: halt",
+        ];
+
+        for source in sources.iter() {
+            let ast = Ast::from_str(source).expect("should parse fine");
+
+            let program = ast.resolve_labels().expect("should resolve fine");
+            let printed = program.to_string();
+            let again = Ast::from_str(&printed).and_then(|ast| ast.resolve_labels());
+            assert_eq!(again.expect("printed source should parse"), program, "{}", printed);
+
+            let program = program.expand_seeks();
+            assert_eq!(program.to_string(), printed);
+            assert_eq!(Program::from_str(&printed).expect("printed source should parse"), program);
+        }
+    }
+
+    #[test]
+    fn print_merges_seek_runs() {
+        let program = Program {
+            instructions: vec![
+                Insn::Seek(SeekOp::Right, 2),
+                Insn::Seek(SeekOp::Right, 3),
+                Insn::Seek(SeekOp::Left, 1),
+                Insn::Seek(SeekOp::Left, 1),
+                Insn::Seek(SeekOp::Left, 4),
+                Insn::Seek(SeekOp::Left, 1),
+            ],
+            labels: vec![("split".to_string(), 4)].into_iter().collect(),
+            data: Data::default(),
+        };
+        let printed = program.to_string();
+        assert_eq!(printed, "    >5\n    <2\nsplit:\n    <5\n");
+
+        // Not the same instructions, but the same program.
+        let again = Ast::from_str(&printed).and_then(|ast| ast.resolve_labels()).expect("printed source should parse");
+        assert_eq!(
+            again.instructions,
+            vec![Insn::Seek(SeekOp::Right, 5), Insn::Seek(SeekOp::Left, 2), Insn::Seek(SeekOp::Left, 5)]
+        );
+        assert_eq!(again.expand_seeks(), program.expand_seeks());
+        assert_eq!(again.to_string(), printed);
+    }
+
+    #[test]
+    fn print_makes_up_labels() {
+        let program = Program {
+            instructions: vec![
+                Insn::Write(WriteOp::Set),
+                Insn::Jmp(3, 0),
+                Insn::Seek(SeekOp::Right, 1),
+                Insn::Jmp(2, 2),
+            ],
+            labels: vec![("_3".to_string(), 0)].into_iter().collect(),
+            data: Data::default(),
+        };
+        let printed = program.to_string();
+
+        assert_eq!(printed, "_3:\n    +\n    jmp _3', _3\n_2:\n    >\n_3':\n    jmp _2, _2\n");
+
+        let again = Program::from_str(&printed).expect("printed source should parse");
+        assert_eq!(again.instructions, program.instructions);
+        assert_eq!(again.to_string(), printed);
+    }
+
     /*
     #[test]
     fn parse_statement() {
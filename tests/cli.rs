@@ -0,0 +1,184 @@
+// Runs the bones binary the way somebody would from a shell.
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+fn bones(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_bones"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("bones should start");
+    child.stdin.take().expect("stdin is piped").write_all(input).expect("write stdin");
+
+    child.wait_with_output().expect("bones should finish")
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout.clone()).expect("stdout should be text")
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8(output.stderr.clone()).expect("stderr should be text")
+}
+
+// A file in a scratch directory, which goes away when the test is done.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("bones-cli-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).expect("mkdir");
+        Scratch(dir)
+    }
+
+    fn file(&self, name: &str, contents: &[u8]) -> String {
+        let path = self.0.join(name);
+        std::fs::write(&path, contents).expect("write");
+        path.to_string_lossy().to_string()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Echoes a byte, least significant bit first like the tiles do IO.
+const ECHO: &str = ", . , . , . , . , . , . , . , .";
+
+#[test]
+fn runs_on_the_tiles() {
+    let output = bones(&["-s", ECHO], b"A");
+
+    assert_eq!(stdout(&output), "A");
+    assert!(stderr(&output).contains("halted at step"), "{}", stderr(&output));
+}
+
+#[test]
+fn runs_files() {
+    let scratch = Scratch::new("file");
+    let lib = scratch.file("echo.wm", ECHO.as_bytes());
+    // Included files only go in once.
+    let main = scratch.file("main.wm", b"include \"echo.wm\"\ninclude \"echo.wm\"");
+
+    assert_eq!(stdout(&bones(&["-f", &main], b"Hi")), "H");
+    assert_eq!(stdout(&bones(&["-f", &lib], b"Hi")), "H");
+}
+
+#[test]
+fn emit() {
+    let output = bones(&["-s", "top: + > jmp top, end\nend:", "--emit", "wmach"], b"");
+    assert_eq!(stdout(&output), "top:\n    +\n    >\n    jmp top, end\nend:\n");
+
+    let output = bones(&["-s", "+ > halt", "--emit", "wat"], b"");
+    assert!(stdout(&output).starts_with("(module"));
+
+    let output = bones(&["-s", "a: jmp a, b b:", "--dot"], b"");
+    assert!(stdout(&output).starts_with("digraph"));
+
+    let scratch = Scratch::new("emit");
+    let c = scratch.0.join("Program.h");
+    let output = bones(&["-s", "+ > halt", "--emit", "c", "-o", &c.to_string_lossy()], b"");
+    assert_eq!(stdout(&output), "");
+    assert!(std::fs::read_to_string(&c).expect("-o should write the file").contains("SET();"));
+}
+
+#[test]
+fn emit_errors() {
+    let output = bones(&["-s", "+", "--emit", "cobol"], b"");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("expected one of: wmach"), "{}", stderr(&output));
+
+    // Backend errors point at the source.
+    let output = bones(&["-s", "+\n>3000000000", "--emit", "wasm"], b"");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("2 | >3000000000"), "{}", stderr(&output));
+}
+
+#[test]
+fn lint() {
+    let output = bones(&["-s", "+ halt\nunused: >", "--lint"], b"");
+    assert!(output.status.success());
+    assert!(stderr(&output).starts_with("warning: "), "{}", stderr(&output));
+    assert!(stderr(&output).contains("2 | unused: >"), "{}", stderr(&output));
+
+    let output = bones(&["-s", "+ halt\nunused: >"], b"");
+    assert!(!stderr(&output).contains("warning"), "{}", stderr(&output));
+}
+
+// Nobody is at a terminal here, so breakpoints say where they are and carry on.
+#[test]
+fn breakpoints() {
+    let output = bones(&["-s", "+ > ! - < !"], b"");
+
+    assert!(output.status.success());
+    assert_eq!(
+        stderr(&output),
+        "breakpoint at step 3\ntape: 01[0]\nbreakpoint at step 6\ntape: 0[1]0\nhalted at step 6\n"
+    );
+}
+
+#[test]
+fn fmt() {
+    let output = bones(&["-s", "loop: +>>  jmp loop,out // done\nout:", "--fmt"], b"");
+    let formatted = stdout(&output);
+    assert_eq!(formatted, "loop:\n    +\n    >2\n    jmp loop, out\nout:\n");
+
+    // Formatting is idempotent.
+    assert_eq!(stdout(&bones(&["-s", &formatted, "--fmt"], b"")), formatted);
+}
+
+#[test]
+fn brainfuck() {
+    // Adds one to every byte, until reading past the end of the input stops it.
+    let output = bones(&["-b", "-s", ",[+.,]"], b"HAL");
+    assert!(stderr(&output).starts_with("Error: IO"), "{}", stderr(&output));
+    assert_eq!(output.stdout, b"IBM");
+
+    let scratch = Scratch::new("brainfuck");
+    let echo = scratch.file("echo.bf", b"read a byte , and write it back .");
+    assert_eq!(stdout(&bones(&["-b", "-f", &echo], b"bf")), "b");
+}
+
+#[test]
+fn structured() {
+    let echo = "proc byte { , . , . , . , . , . , . , . , . } byte()";
+    assert_eq!(stdout(&bones(&["-w", "-s", echo], b"w")), "w");
+
+    // Skips the ones and stops on the zero after them, which lowers to a halt like any other.
+    let scratch = Scratch::new("structured");
+    let skip = scratch.file("skip.w", b"+ > + < while bit { > } if not bit { halt } else { - }");
+    let output = bones(&["-w", "-f", &skip, "--emit", "json"], b"");
+    assert!(stdout(&output).contains("\"insn\": \"halt\""), "{}", stdout(&output));
+}
+
+#[test]
+fn binaries() {
+    let scratch = Scratch::new("binary");
+    let source = scratch.file("echo.wm", ECHO.as_bytes());
+    let compiled = scratch.0.join("echo.wmc").to_string_lossy().to_string();
+
+    // -o on its own writes a binary, which -f runs without going near the source.
+    assert_eq!(stdout(&bones(&["-f", &source, "-o", &compiled], b"")), "");
+    let bytes = std::fs::read(&compiled).expect("-o should write the file");
+    assert!(bytes.starts_with(b"WMCH"));
+    std::fs::remove_file(&source).expect("rm");
+    assert_eq!(stdout(&bones(&["-f", &compiled], b"!")), "!");
+
+    // It still knows where everything came from.
+    let output = bones(&["-f", &compiled, "--emit", "json"], b"");
+    assert!(stdout(&output).contains("\"origin\": \""), "{}", stdout(&output));
+
+    let base64 = stdout(&bones(&["-f", &compiled, "--base64"], b""));
+    assert!(base64.starts_with("V01DS"), "{}", base64);
+
+    // Anything else is taken to be source.
+    let garbage = scratch.file("garbage.wmc", b"WMC");
+    let output = bones(&["-f", &garbage], b"");
+    assert!(stderr(&output).contains("Unable to parse ``WMC''"), "{}", stderr(&output));
+}